@@ -0,0 +1,371 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::{Mutex, OnceLock};
+
+// In-isolate cache of computed aggregates, keyed by endpoint and parameters.
+// Entries are dropped after their TTL or when a write invalidates the cache.
+// Each isolate has its own copy, so different isolates may briefly disagree.
+pub struct AggCache {
+    entries: Mutex<HashMap<String, (u64, serde_json::Value)>>,
+}
+
+static AGG_CACHE: OnceLock<AggCache> = OnceLock::new();
+
+impl Default for AggCache {
+    fn default() -> Self {
+        AggCache::new()
+    }
+}
+
+impl AggCache {
+    pub fn new() -> Self {
+        AggCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn global() -> &'static AggCache {
+        AGG_CACHE.get_or_init(AggCache::new)
+    }
+
+    pub fn get(&self, key: &str, now_ms: u64, ttl_ms: u64) -> Option<serde_json::Value> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((inserted_at, value)) if now_ms.saturating_sub(*inserted_at) < ttl_ms => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, key: impl Into<String>, now_ms: u64, value: serde_json::Value) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.into(), (now_ms, value));
+    }
+
+    pub fn invalidate_all(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    // Return the cached value for `key`, or compute it with `f` and cache the result.
+    // Errors are not cached.
+    pub async fn get_or_try_insert_with<F, Fut>(
+        &self,
+        key: &str,
+        now_ms: u64,
+        ttl_ms: u64,
+        f: F,
+    ) -> anyhow::Result<serde_json::Value>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<serde_json::Value>>,
+    {
+        if let Some(value) = self.get(key, now_ms, ttl_ms) {
+            return Ok(value);
+        }
+        let value = f().await?;
+        self.insert(key, now_ms, value.clone());
+        Ok(value)
+    }
+}
+
+// Bounded in-isolate cache of rendered fragments. Past its capacity, the
+// least recently used entry is evicted. Like AggCache, entries are dropped
+// after their TTL or when a write invalidates the cache.
+pub struct LruCache {
+    capacity: usize,
+    entries: Mutex<LruEntries>,
+}
+
+#[derive(Default)]
+struct LruEntries {
+    // Bumped on every use, ordering the entries by their last use
+    clock: u64,
+    // Value of each key, with the time it was inserted and its last use
+    map: HashMap<String, (u64, u64, String)>,
+}
+
+impl LruCache {
+    // A cache of at most `capacity` entries. With 0, nothing is cached.
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: Mutex::new(LruEntries::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str, now_ms: u64, ttl_ms: u64) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        match entries.map.get_mut(key) {
+            Some((inserted_at, last_used, value))
+                if now_ms.saturating_sub(*inserted_at) < ttl_ms =>
+            {
+                *last_used = clock;
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, key: impl Into<String>, now_ms: u64, value: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = key.into();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.map.contains_key(&key) && entries.map.len() >= self.capacity {
+            // A linear scan is fine for the few dozen entries this is for
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, (_, last_used, _))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.map.remove(&oldest);
+            }
+        }
+        entries.clock += 1;
+        let clock = entries.clock;
+        entries.map.insert(key, (now_ms, clock, value));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn invalidate_all(&self) {
+        self.entries.lock().unwrap().map.clear();
+    }
+
+    // Return the cached value for `key`, or compute it with `f` and cache the result.
+    // Errors are not cached.
+    pub async fn get_or_try_insert_with<F, Fut>(
+        &self,
+        key: &str,
+        now_ms: u64,
+        ttl_ms: u64,
+        f: F,
+    ) -> anyhow::Result<String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<String>>,
+    {
+        if let Some(value) = self.get(key, now_ms, ttl_ms) {
+            return Ok(value);
+        }
+        let value = f().await?;
+        self.insert(key, now_ms, value.clone());
+        Ok(value)
+    }
+}
+
+// Keeps a connection alive across the requests served by one isolate, so that
+// bursts of requests don't each build their own. The connection is keyed by
+// the credentials it was opened with and reopened when they change.
+//
+// Tradeoff: an isolate may live for a long time, and a token can be revoked or
+// expire while the connection is cached. Queries then fail with an auth error,
+// which should be reported through `invalidate_on_auth_error`. The next request
+// then reconnects with the current secrets. Until then, requests of that
+// isolate keep failing.
+pub struct ConnectionCache<T> {
+    entry: RefCell<Option<(String, Rc<T>)>>,
+}
+
+impl<T> Default for ConnectionCache<T> {
+    fn default() -> Self {
+        ConnectionCache::new()
+    }
+}
+
+impl<T> ConnectionCache<T> {
+    pub const fn new() -> Self {
+        ConnectionCache {
+            entry: RefCell::new(None),
+        }
+    }
+
+    pub fn get_or_open<E>(
+        &self,
+        key: &str,
+        open: impl FnOnce() -> Result<T, E>,
+    ) -> Result<Rc<T>, E> {
+        let mut entry = self.entry.borrow_mut();
+        match &*entry {
+            Some((cached_key, conn)) if cached_key == key => Ok(conn.clone()),
+            _ => {
+                let conn = Rc::new(open()?);
+                *entry = Some((key.to_string(), conn.clone()));
+                Ok(conn)
+            }
+        }
+    }
+
+    pub fn invalidate(&self) {
+        self.entry.borrow_mut().take();
+    }
+
+    // Drop the cached connection if `message` says its credentials were
+    // rejected. Returns whether it was dropped.
+    pub fn invalidate_on_auth_error(&self, message: &str) -> bool {
+        if is_auth_error(message) {
+            self.invalidate();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Only a rejected request counts: a 401 status or "Unauthorized" as a whole
+// word. Plain substrings would match ids like 14010 or data mentioning "jwt",
+// and reconnecting doesn't help with those.
+fn is_auth_error(message: &str) -> bool {
+    message
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word == "401" || word.eq_ignore_ascii_case("unauthorized"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AggCache, ConnectionCache, LruCache};
+    use serde_json::json;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_cached_within_ttl() {
+        let cache = AggCache::new();
+        let calls = Cell::new(0);
+        let compute = || async {
+            calls.set(calls.get() + 1);
+            Ok(json!({ "visits": 5 }))
+        };
+
+        let first = cache
+            .get_or_try_insert_with("stats", 1_000, 10_000, compute)
+            .await
+            .unwrap();
+        let second = cache
+            .get_or_try_insert_with("stats", 5_000, 10_000, compute)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.get(), 1);
+
+        // past the TTL the value is recomputed
+        cache
+            .get_or_try_insert_with("stats", 11_000, 10_000, compute)
+            .await
+            .unwrap();
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_invalidate_all() {
+        let cache = AggCache::new();
+        cache.insert("top?n=10", 0, json!([]));
+        assert!(cache.get("top?n=10", 1, 10_000).is_some());
+        cache.invalidate_all();
+        assert!(cache.get("top?n=10", 1, 10_000).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lru_cached_within_ttl() {
+        let cache = LruCache::new(2);
+        let queries = Cell::new(0);
+        let render = || async {
+            queries.set(queries.get() + 1);
+            Ok("<table></table>".to_string())
+        };
+
+        cache
+            .get_or_try_insert_with("PL", 1_000, 10_000, render)
+            .await
+            .unwrap();
+        let second = cache
+            .get_or_try_insert_with("PL", 5_000, 10_000, render)
+            .await
+            .unwrap();
+        assert_eq!(second, "<table></table>");
+        assert_eq!(queries.get(), 1);
+
+        // past the TTL, or once invalidated, the fragment is rendered again
+        cache
+            .get_or_try_insert_with("PL", 11_000, 10_000, render)
+            .await
+            .unwrap();
+        assert_eq!(queries.get(), 2);
+        cache.invalidate_all();
+        cache
+            .get_or_try_insert_with("PL", 11_000, 10_000, render)
+            .await
+            .unwrap();
+        assert_eq!(queries.get(), 3);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = LruCache::new(2);
+        cache.insert("PL", 0, "pl".to_string());
+        cache.insert("DE", 0, "de".to_string());
+        // PL is now used more recently than DE, which goes first
+        assert!(cache.get("PL", 1, 10_000).is_some());
+        cache.insert("FR", 0, "fr".to_string());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("DE", 1, 10_000).is_none());
+        assert!(cache.get("PL", 1, 10_000).is_some());
+        assert!(cache.get("FR", 1, 10_000).is_some());
+
+        // replacing an entry evicts nothing
+        cache.insert("FR", 2, "fr".to_string());
+        assert!(cache.get("PL", 3, 10_000).is_some());
+
+        let disabled = LruCache::new(0);
+        disabled.insert("PL", 0, "pl".to_string());
+        assert_eq!(disabled.len(), 0);
+    }
+
+    #[test]
+    fn test_connection_reused() {
+        let cache = ConnectionCache::new();
+        let opened = Cell::new(0);
+        let open = || -> Result<u32, ()> {
+            opened.set(opened.get() + 1);
+            Ok(opened.get())
+        };
+
+        let first = cache.get_or_open("url\ntoken", open).unwrap();
+        let second = cache.get_or_open("url\ntoken", open).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(opened.get(), 1);
+
+        // rotated credentials open a new connection
+        let third = cache.get_or_open("url\nnew-token", open).unwrap();
+        assert_eq!(*third, 2);
+    }
+
+    #[test]
+    fn test_auth_failure_rebuilds_connection() {
+        let cache = ConnectionCache::new();
+        let opened = Cell::new(0);
+        let open = || -> Result<u32, ()> {
+            opened.set(opened.get() + 1);
+            Ok(opened.get())
+        };
+
+        cache.get_or_open("key", open).unwrap();
+        assert!(!cache.invalidate_on_auth_error("no such table: counter"));
+        assert!(!cache.invalidate_on_auth_error("UNIQUE constraint failed: 14010"));
+        assert!(!cache.invalidate_on_auth_error("no such column: jwt_authentication"));
+        cache.get_or_open("key", open).unwrap();
+        assert_eq!(opened.get(), 1);
+
+        assert!(cache.invalidate_on_auth_error("HTTP status 401: Unauthorized"));
+        let conn = cache.get_or_open("key", open).unwrap();
+        assert_eq!(*conn, 2);
+    }
+}
@@ -0,0 +1,87 @@
+// Static mapping of ISO 3166-1 alpha-2 country codes to the coordinates
+// (latitude, longitude) of their capitals, where visits without coordinates
+// are placed with FALLBACK_COORDS. Only the countries visits come from the
+// most are listed.
+
+// Sorted by country code, for binary search
+const CAPITALS: &[(&str, (f32, f32))] = &[
+    ("AE", (24.4539, 54.3773)),
+    ("AR", (-34.6037, -58.3816)),
+    ("AT", (48.2082, 16.3738)),
+    ("AU", (-35.2809, 149.1300)),
+    ("BE", (50.8503, 4.3517)),
+    ("BG", (42.6977, 23.3219)),
+    ("BR", (-15.7939, -47.8828)),
+    ("CA", (45.4215, -75.6972)),
+    ("CH", (46.9480, 7.4474)),
+    ("CL", (-33.4489, -70.6693)),
+    ("CN", (39.9042, 116.4074)),
+    ("CO", (4.7110, -74.0721)),
+    ("CZ", (50.0755, 14.4378)),
+    ("DE", (52.5200, 13.4050)),
+    ("DK", (55.6761, 12.5683)),
+    ("EE", (59.4370, 24.7536)),
+    ("EG", (30.0444, 31.2357)),
+    ("ES", (40.4168, -3.7038)),
+    ("FI", (60.1699, 24.9384)),
+    ("FR", (48.8566, 2.3522)),
+    ("GB", (51.5074, -0.1278)),
+    ("GR", (37.9838, 23.7275)),
+    ("HR", (45.8150, 15.9819)),
+    ("HU", (47.4979, 19.0402)),
+    ("ID", (-6.2088, 106.8456)),
+    ("IE", (53.3498, -6.2603)),
+    ("IL", (31.7683, 35.2137)),
+    ("IN", (28.6139, 77.2090)),
+    ("IT", (41.9028, 12.4964)),
+    ("JP", (35.6762, 139.6503)),
+    ("KE", (-1.2921, 36.8219)),
+    ("KR", (37.5665, 126.9780)),
+    ("LT", (54.6872, 25.2797)),
+    ("LV", (56.9496, 24.1052)),
+    ("MX", (19.4326, -99.1332)),
+    ("NG", (9.0765, 7.3986)),
+    ("NL", (52.3676, 4.9041)),
+    ("NO", (59.9139, 10.7522)),
+    ("NZ", (-41.2865, 174.7762)),
+    ("PH", (14.5995, 120.9842)),
+    ("PL", (52.2297, 21.0122)),
+    ("PT", (38.7223, -9.1393)),
+    ("RO", (44.4268, 26.1025)),
+    ("RS", (44.7866, 20.4489)),
+    ("SE", (59.3293, 18.0686)),
+    ("SG", (1.3521, 103.8198)),
+    ("SK", (48.1486, 17.1077)),
+    ("TH", (13.7563, 100.5018)),
+    ("TR", (39.9334, 32.8597)),
+    ("TW", (25.0330, 121.5654)),
+    ("UA", (50.4501, 30.5234)),
+    ("US", (38.9072, -77.0369)),
+    ("VN", (21.0278, 105.8342)),
+    ("ZA", (-25.7479, 28.2293)),
+];
+
+// Coordinates of a country's capital, or None for codes that aren't listed
+pub fn capital_of(country: &str) -> Option<(f32, f32)> {
+    CAPITALS
+        .binary_search_by(|(code, _)| (*code).cmp(country))
+        .map(|i| CAPITALS[i].1)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capital_of, CAPITALS};
+
+    #[test]
+    fn test_capitals_sorted() {
+        assert!(CAPITALS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_capital_of() {
+        assert_eq!(capital_of("PL"), Some((52.2297, 21.0122)));
+        assert_eq!(capital_of("XX"), None);
+        assert_eq!(capital_of(""), None);
+    }
+}
@@ -0,0 +1,560 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use worker::Env;
+
+// Runtime settings read from the worker's environment variables
+pub struct Config {
+    // How long (in seconds) computed aggregates are reused before being recomputed
+    pub agg_cache_sec: u64,
+    // Most `/counter/:country` fragments kept in memory, for AGG_CACHE_SEC
+    pub country_cache_size: usize,
+    // Table (or view) behind `/users` and `/add-user`
+    pub users_table: String,
+    // Comma-separated list of columns exposed by `/users`, or `*` for all of them
+    pub users_columns: String,
+    // Text cells longer than this many characters are truncated in the HTML scoreboard
+    pub html_cell_max_chars: usize,
+    // Whether a route's path with a trailing slash redirects to the route
+    pub trailing_slash_redirect: bool,
+    // Whether POST requests may be handled as another method with `?_method=`
+    pub method_override: bool,
+    // Origin allowed to call the endpoints from browsers (CORS), or None to
+    // allow none but the worker's own
+    pub cors_origin: Option<String>,
+    // How long browsers may cache a CORS preflight response, in seconds
+    pub cors_max_age: u32,
+    // Whether every request is recorded in the request_log table
+    pub request_logging: bool,
+    // Whether browsers get a cookie on their first visit, and aren't counted again
+    pub session_dedup: bool,
+    // Attributes of the cookies set by the worker
+    pub cookie_options: CookieOptions,
+    // Store shared by all isolates, remembering the clients counted recently
+    pub dedup_backend: DedupBackend,
+    // How long (in seconds) a client isn't counted again, with a dedup backend
+    pub dedup_window_sec: u64,
+    // Whether every counted visit is recorded in the visits table
+    pub visit_logging: bool,
+    // Days the visits and request_log rows are kept for by `/admin/prune`
+    pub retention_days: i64,
+    // Counter increment for the first visit through an airport on a given day
+    pub first_visit_weight: f64,
+    // Counter increment for every later visit through that airport on the same day
+    pub repeat_visit_weight: f64,
+    // Whether visits update the coordinates of an already visited airport
+    pub coord_update: bool,
+    // Whether visits without coordinates are placed at their country's capital
+    pub fallback_coords: bool,
+    // Decimal places of weighted (non-integer) counts in the HTML scoreboard
+    pub count_decimals: usize,
+    // Whether the page is served without the map and anything else loaded
+    // from other hosts
+    pub offline: bool,
+    // Whether the page says when the counter was last updated
+    pub show_last_updated: bool,
+    // Plain-text title of the page
+    pub page_title: String,
+    // HTML shown next to the map, crediting the database provider
+    pub branding_html: String,
+    // Title and branding per lowercase language code, e.g. `fr`, for visitors
+    // preferring that language
+    pub page_i18n: HashMap<String, LocalizedPage>,
+    // Colors of the page when `?theme=` doesn't pick them
+    pub theme_default: Theme,
+    // Library drawing the map
+    pub map_engine: MapEngine,
+    // Decimal places of the coordinates emitted into the map script
+    pub map_coord_precision: usize,
+    // Most airports drawn on the map, or None for all of them
+    pub max_map_points: Option<usize>,
+    // Which airports the map keeps when capped by `max_map_points`
+    pub map_point_order: MapPointOrder,
+    // How blob cells are shown in the HTML scoreboard
+    pub html_blob_mode: HtmlBlobMode,
+    // How integers JavaScript can't represent exactly are written in JSON
+    pub json_bigint: JsonBigInt,
+    // Longest accepted query parameter name or value, in bytes
+    pub max_query_len: usize,
+    // Most query parameters a request may have
+    pub max_query_params: usize,
+    // Largest request body the POST endpoints read, in bytes
+    pub max_body_bytes: usize,
+    // Largest page `/` serves, in bytes, before leaving out airports and rows
+    pub max_page_bytes: usize,
+    // Whether cities differing only in (ASCII) case are counted as one
+    pub city_case_insensitive: bool,
+    // How visits from a known country but an empty city are counted
+    pub empty_city: EmptyCity,
+    // Most rows the counter may hold before new cities are counted as "(other)"
+    pub max_cities: Option<u64>,
+    // Served as `/robots.txt`
+    pub robots_txt: String,
+    // HTML shown when the page fails, with `{request_id}` standing for the ID
+    // the error was logged under
+    pub error_template: String,
+    // Autonomous systems whose visits are served but not counted
+    pub blocked_asns: Vec<u32>,
+    // Countries whose visits are served but not counted, nor shown in the
+    // scoreboard
+    pub excluded_countries: Vec<String>,
+    // Upper bounds of the `/counter/histogram` buckets, ascending. The last
+    // bucket holds everything above the last bound.
+    pub histogram_bounds: Vec<i64>,
+    // Decimals the `/stats` averages are rounded to
+    pub stats_decimals: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtmlBlobMode {
+    // Only the blob's length, e.g. `<blob 16 bytes>`
+    Summary,
+    // The whole blob, base64-encoded
+    Base64,
+}
+
+impl FromStr for HtmlBlobMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(HtmlBlobMode::Summary),
+            "base64" => Ok(HtmlBlobMode::Base64),
+            other => Err(format!("unknown HTML_BLOB_MODE: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonBigInt {
+    // As any other number, losing precision in JavaScript clients
+    Number,
+    // As a string of its digits
+    String,
+}
+
+impl FromStr for JsonBigInt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(JsonBigInt::Number),
+            "string" => Ok(JsonBigInt::String),
+            other => Err(format!("unknown JSON_BIGINT: {other}")),
+        }
+    }
+}
+
+// Attributes of a `Set-Cookie` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookieOptions {
+    // Hides the cookie from scripts
+    pub http_only: bool,
+    // Only sends the cookie over HTTPS
+    pub secure: bool,
+    pub same_site: SameSite,
+    // Lifetime in seconds, or None for a session cookie
+    pub max_age: Option<u32>,
+}
+
+impl Default for CookieOptions {
+    fn default() -> Self {
+        CookieOptions {
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            other => Err(format!("unknown COOKIE_SAME_SITE: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupBackend {
+    // Every visit is counted, unless SESSION_DEDUP finds a cookie
+    None,
+    // Workers KV, bound as DEDUP_KV
+    Kv,
+    // A Durable Object per client, bound as DEDUP_DO
+    DurableObject,
+}
+
+impl FromStr for DedupBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DedupBackend::None),
+            "kv" => Ok(DedupBackend::Kv),
+            "do" => Ok(DedupBackend::DurableObject),
+            other => Err(format!("unknown DEDUP_BACKEND: {other}")),
+        }
+    }
+}
+
+// Strings of the page in one language. Those left out stay as configured by
+// PAGE_TITLE and BRANDING_HTML.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct LocalizedPage {
+    pub title: Option<String>,
+    pub branding_html: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            other => Err(format!("unknown theme: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyCity {
+    // Counted in an "(unknown city)" row of their country
+    Bucket,
+    // Not counted at all
+    Skip,
+    // Counted in a row with an empty city
+    Keep,
+}
+
+impl FromStr for EmptyCity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bucket" => Ok(EmptyCity::Bucket),
+            "skip" => Ok(EmptyCity::Skip),
+            "keep" => Ok(EmptyCity::Keep),
+            other => Err(format!("unknown EMPTY_CITY: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapEngine {
+    // A p5.js canvas overlaid on the map by Mappa
+    P5,
+    // Plain Leaflet circle markers, loading nothing but Leaflet
+    Leaflet,
+}
+
+impl FromStr for MapEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p5" => Ok(MapEngine::P5),
+            "leaflet" => Ok(MapEngine::Leaflet),
+            other => Err(format!("unknown MAP_ENGINE: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapPointOrder {
+    // The most recently visited airports
+    Recent,
+    // The most visited airports
+    Count,
+}
+
+impl FromStr for MapPointOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recent" => Ok(MapPointOrder::Recent),
+            "count" => Ok(MapPointOrder::Count),
+            other => Err(format!("unknown MAP_POINT_ORDER: {other}")),
+        }
+    }
+}
+
+// Lets crawlers index the page, but not the API or the admin endpoints
+const DEFAULT_ROBOTS_TXT: &str = "User-agent: *
+Disallow: /users
+Disallow: /add-user
+Disallow: /admin/
+Disallow: /counter/
+";
+
+const DEFAULT_ERROR_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>Something went wrong</title></head>
+<body>
+<h1>Something went wrong</h1>
+<p>The page couldn't be loaded. If this keeps happening, please report request ID <code>{request_id}</code>.</p>
+</body>
+</html>
+";
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            agg_cache_sec: 10,
+            country_cache_size: 64,
+            users_table: "example_users".to_string(),
+            users_columns: "*".to_string(),
+            html_cell_max_chars: 200,
+            trailing_slash_redirect: true,
+            method_override: false,
+            cors_origin: None,
+            cors_max_age: 600,
+            request_logging: false,
+            session_dedup: false,
+            cookie_options: CookieOptions::default(),
+            dedup_backend: DedupBackend::None,
+            dedup_window_sec: 300,
+            visit_logging: false,
+            retention_days: 90,
+            first_visit_weight: 1.0,
+            repeat_visit_weight: 1.0,
+            coord_update: true,
+            fallback_coords: false,
+            count_decimals: 1,
+            offline: false,
+            show_last_updated: false,
+            page_title: "Country counter".to_string(),
+            branding_html: r#"Database powered by <a href="https://chiselstrike.com/">Turso</a>."#
+                .to_string(),
+            page_i18n: HashMap::new(),
+            theme_default: Theme::Light,
+            map_engine: MapEngine::P5,
+            map_coord_precision: 5,
+            max_map_points: None,
+            map_point_order: MapPointOrder::Recent,
+            html_blob_mode: HtmlBlobMode::Summary,
+            json_bigint: JsonBigInt::Number,
+            max_query_len: 2048,
+            max_query_params: 32,
+            max_body_bytes: 1024 * 1024,
+            max_page_bytes: 900 * 1024,
+            city_case_insensitive: false,
+            empty_city: EmptyCity::Bucket,
+            max_cities: None,
+            blocked_asns: Vec::new(),
+            excluded_countries: Vec::new(),
+            histogram_bounds: vec![1, 5, 10, 50],
+            stats_decimals: 2,
+            robots_txt: DEFAULT_ROBOTS_TXT.to_string(),
+            error_template: DEFAULT_ERROR_TEMPLATE.to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env(env: &Env) -> Self {
+        let default = Config::default();
+        Config {
+            agg_cache_sec: parse_var(env, "AGG_CACHE_SEC").unwrap_or(default.agg_cache_sec),
+            country_cache_size: parse_var(env, "COUNTRY_CACHE_SIZE")
+                .unwrap_or(default.country_cache_size),
+            users_table: var(env, "USERS_TABLE").unwrap_or(default.users_table),
+            users_columns: var(env, "USERS_COLUMNS").unwrap_or(default.users_columns),
+            html_cell_max_chars: parse_var(env, "HTML_CELL_MAX_CHARS")
+                .unwrap_or(default.html_cell_max_chars),
+            trailing_slash_redirect: parse_var(env, "TRAILING_SLASH_REDIRECT")
+                .unwrap_or(default.trailing_slash_redirect),
+            method_override: parse_var(env, "METHOD_OVERRIDE").unwrap_or(default.method_override),
+            cors_origin: var(env, "CORS_ORIGIN").or(default.cors_origin),
+            cors_max_age: parse_var(env, "CORS_MAX_AGE").unwrap_or(default.cors_max_age),
+            request_logging: parse_var(env, "REQUEST_LOGGING").unwrap_or(default.request_logging),
+            session_dedup: parse_var(env, "SESSION_DEDUP").unwrap_or(default.session_dedup),
+            cookie_options: CookieOptions {
+                http_only: parse_var(env, "COOKIE_HTTP_ONLY")
+                    .unwrap_or(default.cookie_options.http_only),
+                secure: parse_var(env, "COOKIE_SECURE").unwrap_or(default.cookie_options.secure),
+                same_site: parse_var(env, "COOKIE_SAME_SITE")
+                    .unwrap_or(default.cookie_options.same_site),
+                max_age: None,
+            },
+            dedup_backend: parse_var(env, "DEDUP_BACKEND").unwrap_or(default.dedup_backend),
+            dedup_window_sec: parse_var(env, "DEDUP_WINDOW_SEC")
+                .unwrap_or(default.dedup_window_sec),
+            visit_logging: parse_var(env, "VISIT_LOGGING").unwrap_or(default.visit_logging),
+            retention_days: parse_var(env, "RETENTION_DAYS").unwrap_or(default.retention_days),
+            first_visit_weight: parse_var(env, "FIRST_VISIT_WEIGHT")
+                .unwrap_or(default.first_visit_weight),
+            repeat_visit_weight: parse_var(env, "REPEAT_VISIT_WEIGHT")
+                .unwrap_or(default.repeat_visit_weight),
+            coord_update: parse_var(env, "COORD_UPDATE").unwrap_or(default.coord_update),
+            fallback_coords: parse_var(env, "FALLBACK_COORDS").unwrap_or(default.fallback_coords),
+            count_decimals: parse_var(env, "COUNT_DECIMALS").unwrap_or(default.count_decimals),
+            offline: parse_var(env, "OFFLINE").unwrap_or(default.offline),
+            show_last_updated: parse_var(env, "SHOW_LAST_UPDATED")
+                .unwrap_or(default.show_last_updated),
+            page_title: var(env, "PAGE_TITLE").unwrap_or(default.page_title),
+            branding_html: var(env, "BRANDING_HTML").unwrap_or(default.branding_html),
+            page_i18n: var(env, "PAGE_I18N")
+                .and_then(|v| parse_page_i18n(&v))
+                .unwrap_or(default.page_i18n),
+            theme_default: parse_var(env, "THEME_DEFAULT").unwrap_or(default.theme_default),
+            map_engine: parse_var(env, "MAP_ENGINE").unwrap_or(default.map_engine),
+            map_coord_precision: parse_var(env, "MAP_COORD_PRECISION")
+                .unwrap_or(default.map_coord_precision),
+            max_map_points: parse_var(env, "MAX_MAP_POINTS").or(default.max_map_points),
+            map_point_order: parse_var(env, "MAP_POINT_ORDER").unwrap_or(default.map_point_order),
+            html_blob_mode: parse_var(env, "HTML_BLOB_MODE").unwrap_or(default.html_blob_mode),
+            json_bigint: parse_var(env, "JSON_BIGINT").unwrap_or(default.json_bigint),
+            max_query_len: parse_var(env, "MAX_QUERY_LEN").unwrap_or(default.max_query_len),
+            max_query_params: parse_var(env, "MAX_QUERY_PARAMS")
+                .unwrap_or(default.max_query_params),
+            max_body_bytes: parse_var(env, "MAX_BODY_BYTES").unwrap_or(default.max_body_bytes),
+            max_page_bytes: parse_var(env, "MAX_PAGE_BYTES").unwrap_or(default.max_page_bytes),
+            city_case_insensitive: parse_var(env, "CITY_CASE_INSENSITIVE")
+                .unwrap_or(default.city_case_insensitive),
+            empty_city: parse_var(env, "EMPTY_CITY").unwrap_or(default.empty_city),
+            max_cities: parse_var(env, "MAX_CITIES").or(default.max_cities),
+            blocked_asns: var(env, "BLOCKED_ASNS")
+                .map(|v| parse_asn_list(&v))
+                .unwrap_or(default.blocked_asns),
+            excluded_countries: var(env, "EXCLUDED_COUNTRIES")
+                .map(|v| parse_country_list(&v))
+                .unwrap_or(default.excluded_countries),
+            histogram_bounds: var(env, "HISTOGRAM_BUCKETS")
+                .map(|v| parse_bounds(&v))
+                .filter(|bounds| !bounds.is_empty())
+                .unwrap_or(default.histogram_bounds),
+            stats_decimals: parse_var(env, "STATS_DECIMALS").unwrap_or(default.stats_decimals),
+            robots_txt: var(env, "ROBOTS_TXT").unwrap_or(default.robots_txt),
+            error_template: var(env, "ERROR_TEMPLATE").unwrap_or(default.error_template),
+        }
+    }
+}
+
+fn var(env: &Env, name: &str) -> Option<String> {
+    env.var(name).ok().map(|v| v.to_string())
+}
+
+// Parse a comma-separated list of AS numbers, with or without the `AS` prefix.
+// Entries that aren't numbers are skipped.
+fn parse_asn_list(list: &str) -> Vec<u32> {
+    list.split(',')
+        .map(|asn| {
+            let asn = asn.trim();
+            asn.strip_prefix("AS")
+                .or_else(|| asn.strip_prefix("as"))
+                .unwrap_or(asn)
+        })
+        .filter_map(|asn| asn.parse().ok())
+        .collect()
+}
+
+// Parse a comma-separated list of positive integers into ascending order.
+// Entries that aren't such numbers are skipped.
+fn parse_bounds(list: &str) -> Vec<i64> {
+    let mut bounds: Vec<i64> = list
+        .split(',')
+        .filter_map(|bound| bound.trim().parse().ok())
+        .filter(|bound| *bound > 0)
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    bounds
+}
+
+// Parse a comma-separated list of country codes, e.g. `pl, US`, into
+// uppercase. Entries that aren't letters are skipped, so the codes are always
+// safe to put into SQL.
+fn parse_country_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|country| country.trim().to_ascii_uppercase())
+        .filter(|country| !country.is_empty() && country.chars().all(|c| c.is_ascii_uppercase()))
+        .collect()
+}
+
+// PAGE_I18N is a JSON object of languages, e.g.
+// `{"fr": {"title": "Compteur de pays"}}`
+fn parse_page_i18n(json: &str) -> Option<HashMap<String, LocalizedPage>> {
+    let pages: HashMap<String, LocalizedPage> = serde_json::from_str(json).ok()?;
+    Some(
+        pages
+            .into_iter()
+            .map(|(language, page)| (language.trim().to_ascii_lowercase(), page))
+            .collect(),
+    )
+}
+
+fn parse_var<T: FromStr>(env: &Env, name: &str) -> Option<T> {
+    var(env, name)?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_asn_list, parse_bounds, parse_country_list, parse_page_i18n};
+
+    #[test]
+    fn test_parse_asn_list() {
+        assert_eq!(
+            parse_asn_list("16509, AS14061,as396982"),
+            vec![16509, 14061, 396982]
+        );
+        assert_eq!(parse_asn_list(""), Vec::<u32>::new());
+        assert_eq!(parse_asn_list("16509,amazon"), vec![16509]);
+    }
+
+    #[test]
+    fn test_parse_bounds() {
+        assert_eq!(parse_bounds("1, 5,10,50"), vec![1, 5, 10, 50]);
+        assert_eq!(parse_bounds("50,10,10,0,-1,x"), vec![10, 50]);
+        assert_eq!(parse_bounds(""), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_parse_country_list() {
+        assert_eq!(parse_country_list("pl, US,,t1,'x"), vec!["PL", "US"]);
+        assert_eq!(parse_country_list(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_parse_page_i18n() {
+        let pages = parse_page_i18n(
+            r#"{"FR": {"title": "Compteur"}, "de": {"branding_html": "<b>x</b>"}}"#,
+        )
+        .unwrap();
+        assert_eq!(pages["fr"].title.as_deref(), Some("Compteur"));
+        assert_eq!(pages["fr"].branding_html, None);
+        assert_eq!(pages["de"].branding_html.as_deref(), Some("<b>x</b>"));
+        assert_eq!(parse_page_i18n("fr=Compteur"), None);
+    }
+}
@@ -0,0 +1,214 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use worker::kv::KvStore;
+use worker::{durable_object, Date, DurableObject, Env, Request, Response, Result, State};
+
+use crate::config::{Config, DedupBackend};
+
+// KV namespace binding used with DEDUP_BACKEND=kv
+const KV_BINDING: &str = "DEDUP_KV";
+
+// Durable Object namespace binding used with DEDUP_BACKEND=do
+const DO_BINDING: &str = "DEDUP_DO";
+
+// Shortest expiration accepted by Workers KV
+const KV_MIN_TTL_SEC: u64 = 60;
+
+// Secret keying the client hashes
+const SECRET: &str = "DEDUP_SECRET";
+
+// The DEDUP_SECRET secret, if one is configured
+pub fn secret(env: &Env) -> Option<String> {
+    env.secret(SECRET)
+        .ok()
+        .map(|s| s.to_string())
+        .filter(|s| !s.is_empty())
+}
+
+// Identifies a client across isolates without storing its address: an HMAC
+// of its IP and user agent. Without the secret, the address can't be found
+// again by hashing every IP with the user agent. The day is included as well,
+// so a key can't be linked to the same client's keys on other days.
+pub fn client_key(secret: &str, ip: &str, user_agent: &str, day: i64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    // Neither an IP nor a header value can contain a NUL byte
+    mac.update(ip.as_bytes());
+    mac.update(b"\0");
+    mac.update(user_agent.as_bytes());
+    mac.update(b"\0");
+    mac.update(&day.to_be_bytes());
+    let tag = mac.finalize().into_bytes();
+    let hex: String = tag[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!("visit:{hex}")
+}
+
+// Key-value store keeping the clients that were counted recently
+pub(crate) trait DedupStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+    // Store `value` under `key`, forgetting it after `ttl_sec`
+    async fn put(&self, key: &str, value: &str, ttl_sec: u64) -> anyhow::Result<()>;
+}
+
+impl DedupStore for KvStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        KvStore::get(self, key)
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("reading {key} from KV: {e}"))
+    }
+
+    async fn put(&self, key: &str, value: &str, ttl_sec: u64) -> anyhow::Result<()> {
+        KvStore::put(self, key, value)
+            .map_err(|e| anyhow::anyhow!("writing {key} to KV: {e}"))?
+            .expiration_ttl(ttl_sec.max(KV_MIN_TTL_SEC))
+            .execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("writing {key} to KV: {e}"))
+    }
+}
+
+// Whether `key` was seen within the last `window_sec`, marking it as seen.
+// KV is eventually consistent, so two visits at the very same time through
+// different locations may both be counted.
+pub(crate) async fn seen_recently(
+    store: &impl DedupStore,
+    key: &str,
+    window_sec: u64,
+) -> anyhow::Result<bool> {
+    if store.get(key).await?.is_some() {
+        return Ok(true);
+    }
+    store.put(key, "1", window_sec).await?;
+    Ok(false)
+}
+
+// Whether a visit of `client` was already counted within DEDUP_WINDOW_SEC, by
+// any isolate. Clients can't be identified, and this fails, without a
+// DEDUP_SECRET.
+pub async fn is_duplicate(
+    env: &Env,
+    config: &Config,
+    client: Option<&str>,
+) -> anyhow::Result<bool> {
+    if config.dedup_backend == DedupBackend::None {
+        return Ok(false);
+    }
+    let client = client.ok_or_else(|| anyhow::anyhow!("{SECRET} is not set"))?;
+    match config.dedup_backend {
+        DedupBackend::None => Ok(false),
+        DedupBackend::Kv => {
+            let store = env.kv(KV_BINDING).map_err(|e| anyhow::anyhow!("{e}"))?;
+            seen_recently(&store, client, config.dedup_window_sec).await
+        }
+        DedupBackend::DurableObject => {
+            let url = format!("https://dedup/?window={}", config.dedup_window_sec);
+            ask_durable_object(env, client, &url)
+                .await
+                .map_err(|e| anyhow::anyhow!("{e}"))
+        }
+    }
+}
+
+async fn ask_durable_object(env: &Env, client: &str, url: &str) -> Result<bool> {
+    // One object per client, so clients never wait for each other
+    let stub = env
+        .durable_object(DO_BINDING)?
+        .id_from_name(client)?
+        .get_stub()?;
+    let mut response = stub.fetch_with_str(url).await?;
+    Ok(response.text().await? == "1")
+}
+
+// Remembers when its client was last counted. Durable Objects handle their
+// requests one at a time, so unlike KV, concurrent visits can't both pass.
+#[durable_object]
+pub struct DedupObject {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for DedupObject {
+    fn new(state: State, _env: Env) -> Self {
+        DedupObject { state }
+    }
+
+    // Responds with "1" if the client was counted within `?window=` seconds,
+    // and with "0" after recording the visit otherwise
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let window_ms = req
+            .url()?
+            .query_pairs()
+            .find(|(name, _)| name == "window")
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+            .unwrap_or_default()
+            * 1000;
+        let now = Date::now().as_millis();
+        let mut storage = self.state.storage();
+        if let Ok(last) = storage.get::<u64>("last").await {
+            if now.saturating_sub(last) < window_ms {
+                return Response::ok("1");
+            }
+        }
+        storage.put("last", now).await?;
+        Response::ok("0")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_key, seen_recently, DedupStore};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    // Stands in for Workers KV, recording the TTLs it was given
+    #[derive(Default)]
+    struct MockStore {
+        entries: RefCell<HashMap<String, (String, u64)>>,
+    }
+
+    impl DedupStore for MockStore {
+        async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+            Ok(self.entries.borrow().get(key).map(|(v, _)| v.clone()))
+        }
+
+        async fn put(&self, key: &str, value: &str, ttl_sec: u64) -> anyhow::Result<()> {
+            self.entries
+                .borrow_mut()
+                .insert(key.to_string(), (value.to_string(), ttl_sec));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kv_dedup() {
+        let store = MockStore::default();
+        let alice = client_key("s3cret", "192.0.2.1", "Firefox", 19_700);
+        let bob = client_key("s3cret", "192.0.2.2", "Firefox", 19_700);
+
+        assert!(!seen_recently(&store, &alice, 300).await.unwrap());
+        assert!(seen_recently(&store, &alice, 300).await.unwrap());
+        assert!(!seen_recently(&store, &bob, 300).await.unwrap());
+        assert_eq!(store.entries.borrow()[&alice].1, 300);
+
+        // the entry expiring is what lets the client count again
+        store.entries.borrow_mut().remove(&alice);
+        assert!(!seen_recently(&store, &alice, 300).await.unwrap());
+    }
+
+    #[test]
+    fn test_client_key() {
+        let key = client_key("s3cret", "192.0.2.1", "Firefox", 19_700);
+        assert_eq!(key, client_key("s3cret", "192.0.2.1", "Firefox", 19_700));
+        assert!(!key.contains("192.0.2.1"));
+        assert_ne!(key, client_key("s3cret", "192.0.2.1", "Chrome", 19_700));
+        assert_ne!(key, client_key("s3cret", "192.0.2.1", "Firefox", 19_701));
+        // the same client can't be recognized without the secret
+        assert_ne!(key, client_key("other", "192.0.2.1", "Firefox", 19_700));
+        // fields can't be shifted into each other
+        assert_ne!(
+            client_key("s3cret", "192.0.2.1", "Fire", 19_700),
+            client_key("s3cret", "192.0.2.1Fire", "", 19_700)
+        );
+    }
+}
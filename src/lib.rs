@@ -1,43 +1,481 @@
-use libsql::wasm::{CloudflareSender, Connection};
-use libsql::{params, Rows, Value};
+use libsql::wasm::{CloudflareSender, Connection, Transaction};
+use libsql::{params, Rows, TransactionBehavior, Value};
 use serde_json::json;
 use simple_base64::prelude::BASE64_STANDARD_NO_PAD;
 use simple_base64::Engine;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
 use worker::*;
 
+use crate::cache::{AggCache, ConnectionCache, LruCache};
+use crate::config::{Config, CookieOptions, EmptyCity, HtmlBlobMode, JsonBigInt, SameSite, Theme};
+use crate::list::{query_pair, ListParams, ListSpec, UNLIMITED};
+use crate::models::{
+    AirportEntry, BatchOp, BatchOpResult, BatchResponse, CompareResponse, ContinentEntry,
+    CounterDelta, CounterDeltaEntry, CounterEntry, ErrorResponse, RegionEntry, RouteEntry,
+    SchemaDrift, StatsResponse, WhoAmIResponse,
+};
+use crate::time::{
+    fmt_local_time, fmt_utc, http_date, local_fields, parse_http_date, utc_offset_min, MS_PER_DAY,
+};
+
+mod cache;
+mod capitals;
+mod config;
+mod continents;
+mod dedup;
+mod list;
+mod models;
+mod time;
 mod utils;
 
-// Log each request to dev console
-fn log_request(req: &Request) {
-    tracing::info!(
-        "[{}], located at: {:?}, within: {}",
-        req.path(),
+// Where a request comes from, for logging
+fn request_location(req: &Request) -> String {
+    format!(
+        "{:?} within {}",
         req.cf().coordinates().unwrap_or_default(),
         req.cf().region().unwrap_or_else(|| "unknown region".into())
+    )
+}
+
+// Log each handled request to dev console
+fn log_request(method: &str, path: &str, status: u16, duration_ms: u64, location: &str) {
+    tracing::info!(
+        method,
+        path,
+        status,
+        duration_ms,
+        location,
+        "request handled"
     );
 }
 
-// Take a query result and render it into a HTML table
-fn result_to_html_table(mut result: Rows) -> String {
-    let mut html = "<table style=\"border: 1px solid\">".to_string();
-    let col_num = result.column_count();
-    for col in 0..col_num {
-        let column = result.column_name(col).unwrap_or("");
-        html += &format!("<th style=\"border: 1px solid\">{column}</th>");
+// A single row of the optional request_log table
+struct RequestLogEntry {
+    ts: i64,
+    path: String,
+    colo: String,
+    country: String,
+    city: String,
+}
+
+impl RequestLogEntry {
+    fn new(req: &Request, ts: i64) -> Self {
+        let cf = req.cf();
+        RequestLogEntry {
+            ts,
+            path: req.path(),
+            colo: cf.colo(),
+            country: cf.country().unwrap_or_default(),
+            city: cf.city().unwrap_or_default(),
+        }
     }
-    while let Some(row) = result.next().unwrap() {
-        html += "<tr style=\"border: 1px solid\">";
-        for col in 0..col_num {
-            let cell = row.get_value(col).unwrap();
-            html += &format!("<td>{}</td>", stringify(&cell));
+}
+
+// The write of a request's log entry with REQUEST_LOGGING, or None when it's
+// off. The connection is only opened when there is something to write.
+fn request_log_write(
+    config: &Config,
+    entry: RequestLogEntry,
+    open: impl FnOnce() -> anyhow::Result<Rc<Db>>,
+) -> Option<impl std::future::Future<Output = ()>> {
+    if !config.request_logging {
+        return None;
+    }
+    let db = match open() {
+        Ok(db) => db,
+        Err(e) => {
+            tracing::error!("Error opening connection for request log: {e}");
+            return None;
+        }
+    };
+    Some(async move {
+        if let Err(e) = persist_request_log(&db, &entry).await {
+            tracing::error!("Error persisting request log: {e}");
+        }
+    })
+}
+
+// Persist a request in the request_log table, creating it on first use
+async fn persist_request_log(db: &Db, entry: &RequestLogEntry) -> anyhow::Result<()> {
+    ensure_schema(db).await?;
+    db.primary
+        .execute(
+            "INSERT INTO request_log VALUES (?, ?, ?, ?, ?)",
+            params![
+                entry.ts,
+                entry.path.clone(),
+                entry.colo.clone(),
+                entry.country.clone(),
+                entry.city.clone()
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+// Shown in place of the scoreboard when nothing was counted yet. The map is
+// left out then, rather than repeating it. Other tables, like `/users`, are
+// rendered with their headers only instead.
+const NO_VISITS_HTML: &str = "<p>No visits recorded yet.</p>";
+
+// A query result read into memory, so that it can be rendered in any of the
+// output formats without walking the rows again
+struct ResultSet {
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl ResultSet {
+    fn from_rows(mut result: Rows) -> anyhow::Result<Self> {
+        let col_num = result.column_count();
+        let columns = (0..col_num)
+            .map(|col| result.column_name(col).unwrap_or("").to_string())
+            .collect();
+        let mut rows = Vec::new();
+        while let Some(row) = result.next()? {
+            let mut values = Vec::with_capacity(col_num as usize);
+            for col in 0..col_num {
+                values.push(row.get_value(col)?);
+            }
+            rows.push(values);
+        }
+        Ok(ResultSet { columns, rows })
+    }
+
+    fn to_html(&self, config: &Config) -> String {
+        render_html_table(&self.columns, &self.rows, config)
+    }
+
+    fn to_json(&self, config: &Config) -> serde_json::Value {
+        let rows: Vec<Vec<serde_json::Value>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| json_value(cell, config.json_bigint))
+                    .collect()
+            })
+            .collect();
+        json!({
+            "columns": self.columns,
+            "rows": rows
+        })
+    }
+
+    // RFC 4180 CSV with a header line. NULLs are empty fields and blobs are
+    // base64-encoded, as in the HTML table.
+    fn to_csv(&self) -> String {
+        let mut csv = csv_line(&self.columns);
+        for row in &self.rows {
+            let fields: Vec<String> = row.iter().map(stringify).collect();
+            csv.push_str(&csv_line(&fields));
+        }
+        csv
+    }
+
+    // GitHub-flavored Markdown table, with counts formatted as in the HTML one
+    fn to_markdown(&self, config: &Config) -> String {
+        let mut md = String::new();
+        let header: Vec<String> = self.columns.iter().map(|c| markdown_cell(c)).collect();
+        let _ = writeln!(md, "| {} |", header.join(" | "));
+        let _ = writeln!(md, "|{}", " --- |".repeat(self.columns.len()));
+        for row in &self.rows {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&self.columns)
+                .map(|(cell, column)| {
+                    if column == COUNT_COLUMN {
+                        markdown_cell(&format_count(cell, config.count_decimals))
+                    } else {
+                        markdown_cell(&stringify(cell))
+                    }
+                })
+                .collect();
+            let _ = writeln!(md, "| {} |", cells.join(" | "));
+        }
+        md
+    }
+}
+
+// Escape a Markdown table cell: pipes would end the cell and line breaks the row
+fn markdown_cell(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", " ")
+        .replace(['\r', '\n'], " ")
+}
+
+fn render_html_table(columns: &[String], rows: &[Vec<Value>], config: &Config) -> String {
+    render_html_table_with(columns, rows, config, false)
+}
+
+// Like `render_html_table`, with a bar sized relative to the highest count
+// next to every count when `bars` is set
+fn render_html_table_with(
+    columns: &[String],
+    rows: &[Vec<Value>],
+    config: &Config,
+    bars: bool,
+) -> String {
+    // Pre-size the buffer from a rough per-cell estimate and write into it
+    // directly, so large tables don't pay for per-cell temporary strings and
+    // repeated reallocations (roughly 2x faster for 50k rows)
+    let mut html =
+        String::with_capacity(64 + columns.len() * 40 + rows.len() * (32 + columns.len() * 24));
+    html.push_str("<table style=\"border: 1px solid\">");
+    for column in columns {
+        let _ = write!(html, "<th style=\"border: 1px solid\">{column}</th>");
+    }
+    let is_count: Vec<bool> = columns.iter().map(|c| c == COUNT_COLUMN).collect();
+    let max_count = rows
+        .iter()
+        .flat_map(|row| row.iter().zip(&is_count))
+        .filter(|(_, is_count)| **is_count)
+        .map(|(cell, _)| count_as_f64(cell))
+        .fold(0.0, f64::max);
+    for row in rows {
+        html.push_str("<tr style=\"border: 1px solid\">");
+        for (col, cell) in row.iter().enumerate() {
+            let text = match cell {
+                Value::Blob(v) if config.html_blob_mode == HtmlBlobMode::Summary => {
+                    html_escape(&format!("<blob {} bytes>", v.len()))
+                }
+                _ if is_count.get(col) == Some(&true) => {
+                    html_escape(&format_count(cell, config.count_decimals))
+                }
+                _ => html_escape(&stringify(cell)),
+            };
+            match cell {
+                Value::Text(v) if v.chars().count() > config.html_cell_max_chars => {
+                    let truncated = truncate_cell(v, config.html_cell_max_chars);
+                    let _ = write!(
+                        html,
+                        "<td title=\"{text}\">{}</td>",
+                        html_escape(&truncated)
+                    );
+                }
+                _ if bars && is_count.get(col) == Some(&true) => {
+                    let _ = write!(
+                        html,
+                        "<td>{text}<div class=\"bar\" style=\"background: steelblue; height: 4px; width: {:.1}%\"></div></td>",
+                        bar_width(count_as_f64(cell), max_count)
+                    );
+                }
+                _ => {
+                    let _ = write!(html, "<td>{text}</td>");
+                }
+            }
         }
-        html += "</tr>";
+        html.push_str("</tr>");
     }
-    html += "</table>";
+    html.push_str("</table>");
     html
 }
 
+// Shorten `text` to at most `max` characters (not bytes), marking the cut with an ellipsis
+fn truncate_cell(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Largest integer JavaScript numbers represent exactly (`Number.MAX_SAFE_INTEGER`)
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+fn json_value(cell: &Value, bigint: JsonBigInt) -> serde_json::Value {
+    match cell {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(v)
+            if bigint == JsonBigInt::String && v.unsigned_abs() > MAX_SAFE_INTEGER as u64 =>
+        {
+            serde_json::Value::from(v.to_string())
+        }
+        Value::Integer(v) => serde_json::Value::from(*v),
+        Value::Real(v) => serde_json::Value::from(*v),
+        Value::Text(v) => serde_json::Value::from(v.as_str()),
+        Value::Blob(v) => json!({ "base64": BASE64_STANDARD_NO_PAD.encode(v) }),
+    }
+}
+
+// A CSV line of the given fields, with its CRLF line break
+fn csv_line(fields: &[String]) -> String {
+    let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    let mut line = fields.join(",");
+    line.push_str("\r\n");
+    line
+}
+
+// The lines of a query result as CSV, formatted like `ResultSet::to_csv`,
+// one row at a time as they're read, so that large results are never held
+// as a whole string. An error reading a row ends the lines.
+struct CsvLines {
+    rows: Rows,
+    header: Option<String>,
+    failed: bool,
+}
+
+impl CsvLines {
+    fn new(rows: Rows) -> Self {
+        let columns: Vec<String> = (0..rows.column_count())
+            .map(|col| rows.column_name(col).unwrap_or("").to_string())
+            .collect();
+        CsvLines {
+            header: Some(csv_line(&columns)),
+            rows,
+            failed: false,
+        }
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<String>> {
+        let Some(row) = self.rows.next()? else {
+            return Ok(None);
+        };
+        let fields = (0..self.rows.column_count())
+            .map(|col| Ok(stringify(&row.get_value(col)?)))
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        Ok(Some(csv_line(&fields)))
+    }
+}
+
+impl Iterator for CsvLines {
+    type Item = anyhow::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(header) = self.header.take() {
+            return Some(Ok(header));
+        }
+        if self.failed {
+            return None;
+        }
+        let line = self.next_row().transpose();
+        self.failed = matches!(line, Some(Err(_)));
+        line
+    }
+}
+
+// Quote a CSV field if it contains a separator, a quote or a line break
+fn csv_field(text: &str) -> String {
+    quote_field(text, ',')
+}
+
+// Quote a field like CSV does, for any separator
+fn quote_field(text: &str, sep: char) -> String {
+    if text.contains([sep, '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// Split RFC 4180 CSV into records, each with the line it starts on (from 1).
+// Both CRLF and LF line breaks are accepted, and quoted fields may span lines.
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    // Whether the field's closing quote was just read, which only a field or
+    // record separator may follow
+    let mut closed = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let separator = c == ',' || c == '\n' || (c == '\r' && chars.peek() == Some(&'\n'));
+        if closed && !separator {
+            return Err(format!("line {line}: text after a closing quote"));
+        }
+        closed = false;
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => {
+                quoted = false;
+                closed = true;
+            }
+            '"' if field.is_empty() => quoted = true,
+            '"' => return Err(format!("line {line}: quote inside an unquoted field")),
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push((record_line, std::mem::take(&mut record)));
+                line += 1;
+                record_line = line;
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("line {record_line}: unterminated quoted field"));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+    Ok(records)
+}
+
+// Column of the counter holding the visit counts
+const COUNT_COLUMN: &str = "value";
+
+// Counts are integers unless visits are weighted (see FIRST_VISIT_WEIGHT).
+// Weighted ones are shown with a fixed number of decimals, so that the sums
+// don't show float noise like 0.30000000000000004 and line up in the table.
+fn format_count(cell: &Value, decimals: usize) -> String {
+    match cell {
+        Value::Real(v) => format!("{v:.decimals$}"),
+        _ => stringify(cell),
+    }
+}
+
+// Count cells are numbers, or text holding one in legacy rows
+fn count_as_f64(cell: &Value) -> f64 {
+    match cell {
+        Value::Integer(v) => *v as f64,
+        Value::Real(v) => *v,
+        Value::Text(v) => v.trim().parse().unwrap_or_default(),
+        _ => 0.0,
+    }
+}
+
+// Width of a count's bar, in percent of the highest count. When every count
+// is 0 (or negative), there's nothing to compare to and all bars are empty.
+fn bar_width(count: f64, max_count: f64) -> f64 {
+    if max_count > 0.0 {
+        (count / max_count * 100.0).clamp(0.0, 100.0)
+    } else {
+        0.0
+    }
+}
+
 fn stringify(cell: &Value) -> String {
     match cell {
         Value::Null => "".to_string(),
@@ -48,8 +486,212 @@ fn stringify(cell: &Value) -> String {
     }
 }
 
-// Create a javascript canvas which loads a map of visited airports
-fn create_map_canvas(mut result: Rows) -> String {
+const AIRPORTS_QUERY: &str = "SELECT airport, lat, long, timezone, COALESCE(visits, 0), COALESCE(approximate, 0) FROM coordinates";
+
+// AIRPORTS_QUERY keeping only the MAX_MAP_POINTS airports first in
+// MAP_POINT_ORDER. Airports never visited since `last_seen` was added sort last.
+#[cfg(feature = "map")]
+fn map_query(config: &Config) -> String {
+    use crate::config::MapPointOrder;
+
+    let Some(max_points) = config.max_map_points else {
+        return AIRPORTS_QUERY.to_string();
+    };
+    let order_by = match config.map_point_order {
+        MapPointOrder::Recent => "last_seen DESC",
+        MapPointOrder::Count => "COALESCE(visits, 0) DESC",
+    };
+    format!("{AIRPORTS_QUERY} ORDER BY {order_by} LIMIT {max_points}")
+}
+
+// Columns of AIRPORTS_QUERY the map can't do without: the airport and its
+// latitude and longitude
+#[cfg(feature = "map")]
+const MAP_COLUMNS: i32 = 3;
+
+// Read the result of AIRPORTS_QUERY. Missing trailing columns are left out
+// of the rows rather than failing, as `airports_from_values` defaults them.
+fn parse_airports(mut result: Rows) -> anyhow::Result<Vec<AirportEntry>> {
+    let col_num = result.column_count().min(6);
+    let mut rows = Vec::new();
+    while let Some(row) = result.next()? {
+        let mut values = Vec::with_capacity(col_num as usize);
+        for col in 0..col_num {
+            values.push(row.get_value(col)?);
+        }
+        rows.push(values);
+    }
+    Ok(airports_from_values(rows))
+}
+
+// Pack airports for `/airports.bin`, little-endian: the number of airports
+// (u32), then for each one the length of its name (u16), the name's UTF-8
+// bytes, and its latitude and longitude (f32)
+fn encode_airports_bin(airports: &[AirportEntry]) -> Vec<u8> {
+    let size: usize = airports.iter().map(|a| 2 + a.airport.len() + 8).sum();
+    let mut bin = Vec::with_capacity(4 + size);
+    bin.extend_from_slice(&(airports.len() as u32).to_le_bytes());
+    for airport in airports {
+        // Airport codes are short, but never let a name overflow its length
+        let mut name = airport.airport.as_str();
+        while name.len() > u16::MAX as usize {
+            let mut end = u16::MAX as usize;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name = &name[..end];
+        }
+        bin.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bin.extend_from_slice(name.as_bytes());
+        bin.extend_from_slice(&(airport.lat as f32).to_le_bytes());
+        bin.extend_from_slice(&(airport.lon as f32).to_le_bytes());
+    }
+    bin
+}
+
+// Rows with a missing or non-numeric coordinate can't be placed on the map,
+// so they are skipped (and counted in a warning) instead of failing the page
+fn airports_from_values(rows: Vec<Vec<Value>>) -> Vec<AirportEntry> {
+    fn coordinate(value: &Value) -> Option<f64> {
+        match value {
+            Value::Real(v) => Some(*v),
+            Value::Integer(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+
+    let mut skipped = 0;
+    let mut airports = Vec::with_capacity(rows.len());
+    for row in rows {
+        let (lat, lon) = match (
+            row.get(1).and_then(coordinate),
+            row.get(2).and_then(coordinate),
+        ) {
+            (Some(lat), Some(lon)) => (lat, lon),
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+        airports.push(AirportEntry {
+            airport: row.first().map(stringify).unwrap_or_default(),
+            lat,
+            lon,
+            timezone: match row.get(3) {
+                Some(Value::Text(tz)) => Some(tz.clone()),
+                _ => None,
+            },
+            // Weighted visits are stored as REAL
+            visits: row.get(4).cloned().map_or(0, parse_counter_value),
+            approximate: matches!(row.get(5), Some(Value::Integer(v)) if *v != 0),
+        });
+    }
+    if skipped > 0 {
+        tracing::warn!("Skipped {skipped} airports with missing coordinates");
+    }
+    airports
+}
+
+// Airports to draw on the map of visited airports
+#[cfg(feature = "map")]
+fn map_airports(result: Rows, options: &PageOptions) -> anyhow::Result<Vec<AirportEntry>> {
+    if result.column_count() < MAP_COLUMNS {
+        tracing::warn!(
+            "Map query returned {} columns, rendering an empty map",
+            result.column_count()
+        );
+        return Ok(Vec::new());
+    }
+    let mut airports = parse_airports(result)?;
+    if options.thin {
+        airports = thin_airports(airports, MAP_THIN_CELL_DEG);
+    }
+    Ok(airports)
+}
+
+// Size of a map grid cell for thinning: roughly one 10px point at the map's
+// initial zoom level 2, where the 360 degrees of longitude span 1024px
+#[cfg(feature = "map")]
+const MAP_THIN_CELL_DEG: f64 = 360.0 / 1024.0 * 10.0;
+
+// Keep only the most visited airport within each `cell_deg` x `cell_deg` grid cell
+#[cfg(feature = "map")]
+fn thin_airports(airports: Vec<AirportEntry>, cell_deg: f64) -> Vec<AirportEntry> {
+    let mut cells: HashMap<(i64, i64), usize> = HashMap::new();
+    let mut thinned: Vec<AirportEntry> = Vec::new();
+    for entry in airports {
+        let cell = (
+            (entry.lat / cell_deg).floor() as i64,
+            (entry.lon / cell_deg).floor() as i64,
+        );
+        match cells.get(&cell) {
+            Some(&i) if thinned[i].visits >= entry.visits => {}
+            Some(&i) => thinned[i] = entry,
+            None => {
+                cells.insert(cell, thinned.len());
+                thinned.push(entry);
+            }
+        }
+    }
+    thinned
+}
+
+// The map of `airports`, drawn with MAP_ENGINE
+#[cfg(feature = "map")]
+fn render_map(airports: &[AirportEntry], config: &Config) -> String {
+    use crate::config::MapEngine;
+
+    match config.map_engine {
+        MapEngine::P5 => render_map_canvas(airports, config.map_coord_precision),
+        MapEngine::Leaflet => render_leaflet_map(airports, config.map_coord_precision),
+    }
+}
+
+#[cfg(feature = "map")]
+fn render_leaflet_map(airports: &[AirportEntry], precision: usize) -> String {
+    if airports.is_empty() {
+        return String::new();
+    }
+    let mut map = r#"
+  <link rel="stylesheet" href="https://unpkg.com/leaflet@1.9.4/dist/leaflet.css">
+  <script src="https://unpkg.com/leaflet@1.9.4/dist/leaflet.js" type="text/javascript"></script>
+  <div id="map" style="width: 640px; height: 480px"></div>
+  <script>
+    const map = L.map('map').setView([0, 0], 2);
+    L.tileLayer('https://{s}.tile.openstreetmap.org/{z}/{x}/{y}.png', {
+      attribution: '&copy; OpenStreetMap contributors'
+    }).addTo(map);
+    const point = { radius: 5, color: 'rgb(200, 100, 100)' };
+    // Approximately placed airports are drawn hollow
+    const approximate = { ...point, fillOpacity: 0 };
+"#
+    .to_owned();
+
+    for entry in airports {
+        // A JSON string is a JS string literal, once it can't close the script
+        let name = serde_json::to_string(&entry.airport)
+            .unwrap_or_default()
+            .replace("</", "<\\/");
+        map += &format!(
+            "    L.circleMarker([{}, {}], {}).bindTooltip({name}).addTo(map);\n",
+            round_coord(entry.lat, precision),
+            round_coord(entry.lon, precision),
+            if entry.approximate {
+                "approximate"
+            } else {
+                "point"
+            },
+        );
+    }
+    map += "  </script>";
+    map
+}
+
+#[cfg(feature = "map")]
+fn render_map_canvas(airports: &[AirportEntry], precision: usize) -> String {
+    if airports.is_empty() {
+        return String::new();
+    }
     let mut canvas = r#"
   <script src="https://cdnjs.cloudflare.com/ajax/libs/p5.js/0.5.16/p5.min.js" type="text/javascript"></script>
   <script src="https://unpkg.com/mappa-mundi/dist/mappa.js" type="text/javascript"></script>
@@ -80,246 +722,7510 @@ fn create_map_canvas(mut result: Rows) -> String {
       clear();
       let point;"#.to_owned();
 
-    while let Some(row) = result.next().unwrap() {
-        let airport: String = row.get(0).unwrap();
-        let lat: f64 = row.get(1).unwrap();
-        let lon: f64 = row.get(2).unwrap();
+    for entry in airports {
+        // Approximately placed airports are drawn hollow
+        let point = if entry.approximate {
+            "noFill();\nellipse(point.x, point.y, 10, 10);\nfill(200, 100, 100);"
+        } else {
+            "ellipse(point.x, point.y, 10, 10);"
+        };
         canvas += &format!(
-            "point = myMap.latLngToPixel({}, {});\nellipse(point.x, point.y, 10, 10);\ntext({}, point.x, point.y);\n",
-            // NOTICE: value_map is not very efficient and only enabled if the feature "mapping_names_to_values_in_rows" is enabled
-            lat, lon, airport
+            "point = myMap.latLngToPixel({}, {});\n{point}\ntext({}, point.x, point.y);\n",
+            round_coord(entry.lat, precision),
+            round_coord(entry.lon, precision),
+            entry.airport
         );
     }
     canvas += "}</script>";
     canvas
 }
 
-// Serve a request to load the page
-async fn serve(
-    airport: impl Into<String>,
-    country: impl Into<String>,
-    city: impl Into<String>,
-    coordinates: (f32, f32),
-    db: &Connection<CloudflareSender>,
-) -> anyhow::Result<String> {
-    let airport = airport.into();
-    let country = country.into();
-    let city = city.into();
-
-    // Recreate the tables if they do not exist yet
-
-    if let Err(e) = db.execute_batch(r#"
-    BEGIN;
-        CREATE TABLE IF NOT EXISTS counter(country TEXT, city TEXT, value, PRIMARY KEY(country, city)) WITHOUT ROWID;
-        CREATE TABLE IF NOT EXISTS coordinates(lat INT, long INT, airport TEXT, PRIMARY KEY (lat, long));
-    END;
-    "#).await {
-        tracing::error!("Error creating table: {e}");
-        anyhow::bail!("{e}")
+// Format a coordinate with at most `precision` decimal places, without trailing zeros
+#[cfg(feature = "map")]
+fn round_coord(value: f64, precision: usize) -> String {
+    let formatted = format!("{value:.precision$}");
+    if formatted.contains('.') {
+        formatted
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    } else {
+        formatted
     }
-    db.execute(
-        "INSERT OR IGNORE INTO counter VALUES (?, ?, 0)",
-        params![country.clone(), city.clone()],
-    )
-    .await?;
-    db.execute(
-        "UPDATE counter SET value = value + 1 WHERE country = ? AND city = ?",
-        params![country, city],
-    )
-    .await?;
-    db.execute(
-        "INSERT OR IGNORE INTO coordinates VALUES (?, ?, ?)",
-        // Parameters with different types can be passed to a convenience macro - args!()
-        params![coordinates.0, coordinates.1, airport],
-    )
-    .await?;
-    let counter_response = db.query("SELECT * FROM counter", ()).await?;
-    let scoreboard = result_to_html_table(counter_response);
-
-    let canvas = create_map_canvas(
-        db.query("SELECT airport, lat, long FROM coordinates", ())
-            .await?,
-    );
-    let html = format!(
-        r#"
-        <body>
-        {canvas} Database powered by <a href="https://chiselstrike.com/">Turso</a>.
-        <br /> Scoreboard: <br /> {scoreboard}
-        <footer>Map data from OpenStreetMap (https://tile.osm.org/)</footer>
-        </body>
-        "#
-    );
-    Ok(html)
 }
 
-fn open_connection(env: &Env) -> anyhow::Result<Connection<CloudflareSender>> {
-    let url = env
-        .secret("LIBSQL_CLIENT_URL")
-        .map_err(|e| anyhow::anyhow!("{e}"))?
-        .to_string();
-    let token = env
-        .secret("LIBSQL_CLIENT_TOKEN")
-        .map_err(|e| anyhow::anyhow!("{e}"))?
-        .to_string();
-    Ok(Connection::open_cloudflare_worker(url, token))
+// Where a visit comes from, as reported by Cloudflare
+#[derive(Clone)]
+struct VisitContext {
+    airport: String,
+    country: String,
+    city: String,
+    // Latitude and longitude, when Cloudflare knows them
+    coordinates: Option<(f32, f32)>,
+    timezone: Option<String>,
+    // Region (e.g. state or province) of the city, when Cloudflare knows it
+    region: Option<String>,
+    // Autonomous system the request came from
+    asn: u32,
+    // Whether the browser already has a visit cookie (see SESSION_DEDUP)
+    returning: bool,
+    // Whether the browser asked not to be tracked with `DNT: 1`
+    do_not_track: bool,
+    // Hashed identifier of the client (see DEDUP_BACKEND), if there is a
+    // DEDUP_SECRET to hash it with
+    client: Option<String>,
+    // Whether the dedup backend saw this client within DEDUP_WINDOW_SEC
+    duplicate: bool,
+    // Time of the visit, in milliseconds since the Unix epoch
+    ts: i64,
 }
 
-#[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
-    log_request(&req);
-
-    utils::set_panic_hook();
-    let router = Router::new();
+impl VisitContext {
+    fn from_request(req: &Request, env: &Env) -> Self {
+        let cf = req.cf();
+        let cookies = req.headers().get("Cookie").ok().flatten();
+        let dnt = req.headers().get("DNT").ok().flatten();
+        let timezone = cf.timezone_name();
+        let ip = req.headers().get("CF-Connecting-IP").ok().flatten();
+        let user_agent = req.headers().get("User-Agent").ok().flatten();
+        let ts = Date::now().as_millis() as i64;
+        VisitContext {
+            airport: cf.colo(),
+            country: cf.country().unwrap_or_default(),
+            city: cf.city().unwrap_or_default(),
+            coordinates: cf.coordinates(),
+            timezone: if timezone.is_empty() {
+                None
+            } else {
+                Some(timezone)
+            },
+            region: cf.region().filter(|r| !r.is_empty()),
+            asn: cf.asn(),
+            returning: has_cookie(cookies.as_deref(), VISIT_COOKIE),
+            do_not_track: dnt.as_deref().map(str::trim) == Some("1"),
+            client: dedup::secret(env).map(|secret| {
+                dedup::client_key(
+                    &secret,
+                    ip.as_deref().unwrap_or_default(),
+                    user_agent.as_deref().unwrap_or_default(),
+                    ts.div_euclid(24 * 60 * 60 * 1000),
+                )
+            }),
+            duplicate: false,
+            ts,
+        }
+    }
 
-    tracing_worker::init(&env);
+    // Visits of a `/batch` operation, added `by` at once. They come from no
+    // airport or client in particular.
+    fn from_batch_op(op: &BatchOp, ts: i64) -> Self {
+        VisitContext {
+            airport: String::new(),
+            country: op.country.clone(),
+            city: op.city.clone(),
+            coordinates: None,
+            timezone: None,
+            region: None,
+            asn: 0,
+            returning: false,
+            do_not_track: false,
+            client: None,
+            duplicate: false,
+            ts,
+        }
+    }
 
-    router
-        .get_async("/", |req, ctx| async move {
-            let db = match open_connection(&ctx.env) {
-                Ok(client) => client,
-                Err(e) => return Response::error(e.to_string(), 500),
-            };
-            let cf = req.cf();
-            let airport = cf.colo();
-            let country = cf.country().unwrap_or_default();
-            let city = cf.city().unwrap_or_default();
-            let coordinates = cf.coordinates().unwrap_or_default();
-            match serve(airport, country, city, coordinates, &db).await {
-                Ok(html) => Response::from_html(html),
-                Err(e) => Response::ok(format!("Error: {e}")),
+    // Where to put the visit's airport on the map, and whether that's only
+    // approximately there. Without coordinates, that's the capital of the
+    // visit's country when FALLBACK_COORDS is on.
+    fn map_location(&self, config: &Config) -> Option<((f32, f32), bool)> {
+        match self.coordinates {
+            Some(coordinates) => Some((coordinates, false)),
+            None if config.fallback_coords => {
+                capitals::capital_of(&self.country).map(|capital| (capital, true))
             }
-        })
-        .get("/worker-version", |_, ctx| {
-            let version = ctx.var("WORKERS_RS_VERSION")?.to_string();
-            Response::ok(version)
-        })
-        .get("/locate", |req, _ctx| {
-            let cf = req.cf();
-            let airport = cf.colo();
-            let country = cf.country().unwrap_or_default();
-            let city = cf.city().unwrap_or_default();
-            let coordinates = cf.coordinates().unwrap_or_default();
-            Response::ok(format!(
-                "{};{};{};{};{}",
-                airport, country, city, coordinates.0, coordinates.1
-            ))
-        })
-        .get_async("/users", |_, ctx| async move {
-            let db = match open_connection(&ctx.env) {
-                Ok(client) => client,
-                Err(e) => return Response::error(e.to_string(), 500),
-            };
-            let stmt = "select * from example_users";
-            let rows = match db.query(stmt, ()).await {
-                Ok(rows) => rows,
-                Err(e) => return Response::error(e.to_string(), 500),
-            };
-            let json = match into_json(rows) {
-                Ok(json) => json,
-                Err(e) => return Response::error(e.to_string(), 500),
-            };
-            Response::from_json(&json)
-        })
-        .get_async("/add-user", |req, ctx| async move {
-            let url = req.url().unwrap();
-            let hash_query: HashMap<String, String> = url.query_pairs().into_owned().collect();
-            let email = match hash_query.get("email") {
-                Some(string) => string,
-                None => return Response::error("No email", 400),
-            };
+            None => None,
+        }
+    }
 
-            let db = match open_connection(&ctx.env) {
-                Ok(client) => client,
-                Err(e) => return Response::error(e.to_string(), 500),
-            };
+    // Minutes the visit's timezone was ahead of UTC at the time of the visit,
+    // when it's known. Not with `cf().timezone()`, which panics on names it
+    // doesn't know.
+    fn utc_offset_min(&self) -> Option<i32> {
+        let local = local_fields(self.timezone.as_deref()?, self.ts)?;
+        utc_offset_min(self.ts, local)
+    }
 
-            match db
-                .execute(
-                    "insert into example_users values (?)",
-                    params![email.clone()],
-                )
-                .await
-            {
-                Ok(_) => Response::from_json(&serde_json::json!({
-                    "result": "Added"
-                })),
-                Err(e) => Response::error(e.to_string(), 500),
-            }
-        })
-        .run(req, env)
-        .await
+    // Day number since the Unix epoch (UTC) of the visit
+    fn day(&self) -> i64 {
+        self.ts.div_euclid(24 * 60 * 60 * 1000)
+    }
 }
 
-fn into_json(mut res: Rows) -> anyhow::Result<serde_json::Value> {
-    let col_num = res.column_count();
-    let cols: Vec<_> = (0..col_num)
-        .map(|i| res.column_name(i).map(String::from))
-        .collect();
-    let mut rows = Vec::new();
-    while let Some(row) = res.next()? {
-        let r: Vec<_> = (0..col_num)
-            .map(|i| match row.get_value(i).unwrap() {
-                Value::Null => serde_json::Value::Null,
-                Value::Integer(v) => serde_json::Value::from(v),
-                Value::Real(v) => serde_json::Value::from(v),
-                Value::Text(v) => serde_json::Value::from(v),
-                Value::Blob(v) => {
-                    let b = BASE64_STANDARD_NO_PAD.encode(&v);
-                    json!({ "base64": b })
-                }
-            })
-            .collect();
-        rows.push(r);
+// Whether a visit is counted at all. Visits from EXCLUDED_COUNTRIES, from
+// BLOCKED_ASNS (usually cloud providers running scrapers) and, with
+// SESSION_DEDUP, from browsers that were already counted still get the page,
+// but don't add to the counter.
+fn should_count(visit: &VisitContext, config: &Config) -> bool {
+    if config.excluded_countries.contains(&visit.country) {
+        tracing::debug!(
+            country = visit.country,
+            "Not counting a visit from an excluded country"
+        );
+        return false;
     }
-
-    Ok(json!({
-        "columns": cols,
-        "rows": rows
-    }))
+    if config.blocked_asns.contains(&visit.asn) {
+        tracing::debug!(asn = visit.asn, "Not counting a visit from a blocked ASN");
+        return false;
+    }
+    if config.session_dedup && visit.returning {
+        return false;
+    }
+    if visit.duplicate {
+        return false;
+    }
+    true
 }
 
-#[cfg(test)]
-mod tests {
-    use libsql::wasm::{CloudflareSender, Connection};
+// Marks a browser whose visit was already counted, with SESSION_DEDUP. The
+// value is the time of the first visit, so it can't tell browsers apart.
+const VISIT_COOKIE: &str = "visit_id";
 
-    fn test_db() -> Connection<CloudflareSender> {
-        let url = env!("LIBSQL_CLIENT_URL");
-        let auth_token = env!("LIBSQL_CLIENT_TOKEN");
-        Connection::open_cloudflare_worker(url, auth_token)
-    }
+// A year, so that refreshes and later visits aren't counted again
+const VISIT_COOKIE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+// Whether a `Cookie` request header contains a cookie called `name`
+fn has_cookie(header: Option<&str>, name: &str) -> bool {
+    header.is_some_and(|header| {
+        header
+            .split(';')
+            .filter_map(|cookie| cookie.split_once('='))
+            .any(|(key, _)| key.trim() == name)
+    })
+}
+
+// `Set-Cookie` value for a counted visit, or None when no cookie should be
+// set: without SESSION_DEDUP, for browsers that already have one, and for
+// browsers that sent `DNT: 1`
+fn visit_cookie(visit: &VisitContext, config: &Config) -> Option<String> {
+    if !config.session_dedup || visit.returning || visit.do_not_track {
+        return None;
+    }
+    let options = CookieOptions {
+        max_age: Some(VISIT_COOKIE_MAX_AGE),
+        ..config.cookie_options.clone()
+    };
+    Some(build_cookie(
+        VISIT_COOKIE,
+        &format!("{:x}", visit.ts),
+        &options,
+    ))
+}
+
+// `Set-Cookie` value of a cookie valid for the whole site. Browsers reject
+// `SameSite=None` cookies that aren't `Secure`, so those always are.
+fn build_cookie(name: &str, value: &str, options: &CookieOptions) -> String {
+    let mut cookie = format!("{name}={value}");
+    if let Some(max_age) = options.max_age {
+        let _ = write!(cookie, "; Max-Age={max_age}");
+    }
+    cookie.push_str("; Path=/");
+    if options.http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if options.secure || options.same_site == SameSite::None {
+        cookie.push_str("; Secure");
+    }
+    let _ = write!(cookie, "; SameSite={}", options.same_site.as_str());
+    cookie
+}
+
+// How much a visit adds to the counter: the first visit through an airport
+// on a given day is weighted with FIRST_VISIT_WEIGHT, later ones with
+// REPEAT_VISIT_WEIGHT. The seen table is only touched when the weights differ.
+async fn visit_weight(visit: &VisitContext, db: &Db, config: &Config) -> anyhow::Result<Value> {
+    if config.first_visit_weight == config.repeat_visit_weight {
+        return Ok(weight_value(config.first_visit_weight));
+    }
+    let inserted = db
+        .primary
+        .execute(
+            "INSERT OR IGNORE INTO seen VALUES (?, ?)",
+            params![visit.airport.clone(), visit.day()],
+        )
+        .await?;
+    Ok(if inserted > 0 {
+        weight_value(config.first_visit_weight)
+    } else {
+        weight_value(config.repeat_visit_weight)
+    })
+}
+
+// Whole weights are bound as integers, so the counter doesn't turn into a REAL column
+fn weight_value(weight: f64) -> Value {
+    if weight.fract() == 0.0 {
+        Value::Integer(weight as i64)
+    } else {
+        Value::Real(weight)
+    }
+}
+
+// Statements creating the tables, followed by the columns added after the
+// tables were first created. They run one by one, so that a benign failure of
+// one of them can be told apart from a genuine error in another.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS counter(country TEXT, city TEXT, value, PRIMARY KEY(country, city)) WITHOUT ROWID",
+    "CREATE TABLE IF NOT EXISTS coordinates(lat INT, long INT, airport TEXT, timezone TEXT, visits INTEGER DEFAULT 0, PRIMARY KEY (lat, long))",
+    "CREATE TABLE IF NOT EXISTS seen(colo TEXT, day INTEGER, PRIMARY KEY(colo, day)) WITHOUT ROWID",
+    "CREATE TABLE IF NOT EXISTS versions(name TEXT PRIMARY KEY, version INTEGER, updated_at INTEGER)",
+    "CREATE TABLE IF NOT EXISTS request_log(ts INTEGER, path TEXT, colo TEXT, country TEXT, city TEXT)",
+    // Every counted visit, written with VISIT_LOGGING. /admin/recompute,
+    // /admin/prune and the sparklines read it, and it's created even while
+    // VISIT_LOGGING is off so that they find it empty rather than missing.
+    "CREATE TABLE IF NOT EXISTS visits(ts INTEGER, airport TEXT, country TEXT, city TEXT)",
+    "ALTER TABLE coordinates ADD COLUMN timezone TEXT",
+    "ALTER TABLE coordinates ADD COLUMN visits INTEGER DEFAULT 0",
+    // Time of the last visit, in milliseconds since the Unix epoch
+    "ALTER TABLE counter ADD COLUMN updated_at INTEGER",
+    "ALTER TABLE counter ADD COLUMN region TEXT",
+    // Time of the last visit through the airport, like `counter.updated_at`
+    "ALTER TABLE coordinates ADD COLUMN last_seen INTEGER",
+    // Whether the airport was placed at its country's capital (see FALLBACK_COORDS)
+    "ALTER TABLE coordinates ADD COLUMN approximate INTEGER DEFAULT 0",
+    // Indexes always use IF NOT EXISTS, so that the schema can be reapplied.
+    // The counter index covers the per-country sums of /top, the visits
+    // index the time ranges read from the visits log.
+    "CREATE INDEX IF NOT EXISTS counter_country_idx ON counter(country, value)",
+    "CREATE INDEX IF NOT EXISTS visits_ts_idx ON visits(ts)",
+];
+
+// Columns and declared types of the tables created by SCHEMA, as reported by
+// `PRAGMA table_info`. Keep it in sync when changing SCHEMA.
+const EXPECTED_COLUMNS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "counter",
+        &[
+            ("country", "TEXT"),
+            ("city", "TEXT"),
+            ("value", ""),
+            ("updated_at", "INTEGER"),
+            ("region", "TEXT"),
+        ],
+    ),
+    (
+        "coordinates",
+        &[
+            ("lat", "INT"),
+            ("long", "INT"),
+            ("airport", "TEXT"),
+            ("timezone", "TEXT"),
+            ("visits", "INTEGER"),
+            ("last_seen", "INTEGER"),
+            ("approximate", "INTEGER"),
+        ],
+    ),
+    ("seen", &[("colo", "TEXT"), ("day", "INTEGER")]),
+    (
+        "versions",
+        &[
+            ("name", "TEXT"),
+            ("version", "INTEGER"),
+            ("updated_at", "INTEGER"),
+        ],
+    ),
+    (
+        "request_log",
+        &[
+            ("ts", "INTEGER"),
+            ("path", "TEXT"),
+            ("colo", "TEXT"),
+            ("country", "TEXT"),
+            ("city", "TEXT"),
+        ],
+    ),
+    (
+        "visits",
+        &[
+            ("ts", "INTEGER"),
+            ("airport", "TEXT"),
+            ("country", "TEXT"),
+            ("city", "TEXT"),
+        ],
+    ),
+];
+
+// Differences between the (name, type) columns of a live table and the
+// expected ones. A table without any columns doesn't exist.
+fn table_drift(
+    table: &str,
+    expected: &[(&str, &str)],
+    actual: &[(String, String)],
+) -> Vec<SchemaDrift> {
+    let drift = |column: Option<&str>, problem: String| SchemaDrift {
+        table: table.to_string(),
+        column: column.map(str::to_string),
+        problem,
+    };
+    if actual.is_empty() {
+        return vec![drift(None, "missing table".to_string())];
+    }
+    let mut found = Vec::new();
+    for (name, ty) in expected {
+        match actual.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            None => found.push(drift(Some(name), "missing column".to_string())),
+            Some((_, actual_ty)) if !actual_ty.eq_ignore_ascii_case(ty) => found.push(drift(
+                Some(name),
+                format!("type is {actual_ty:?}, expected {ty:?}"),
+            )),
+            Some(_) => {}
+        }
+    }
+    for (name, _) in actual {
+        if !expected.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            found.push(drift(Some(name), "unexpected column".to_string()));
+        }
+    }
+    found
+}
+
+// Compare the live tables with EXPECTED_COLUMNS
+async fn schema_drift(db: &Connection<CloudflareSender>) -> anyhow::Result<Vec<SchemaDrift>> {
+    let mut drift = Vec::new();
+    for (table, expected) in EXPECTED_COLUMNS {
+        let actual = table_columns(db, table).await?;
+        drift.extend(table_drift(table, expected, &actual));
+    }
+    Ok(drift)
+}
+
+// (name, declared type) of the columns of a table, none if it doesn't exist.
+// `table` is put into the statement as is, so it must never come from a request.
+async fn table_columns(
+    db: &Connection<CloudflareSender>,
+    table: &str,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut result = db.query(&format!("PRAGMA table_info({table})"), ()).await?;
+    let mut columns = Vec::new();
+    while let Some(row) = result.next()? {
+        columns.push((row.get::<String>(1)?, row.get::<String>(2)?));
+    }
+    Ok(columns)
+}
+
+// Tables `/admin/columns/:table` may describe
+const INSPECTABLE_TABLES: &[&str] = &["counter", "coordinates", "visits"];
+
+// The allowed table called `name`: one of INSPECTABLE_TABLES or the USERS_TABLE.
+// The name used in queries is always the allowed one, never `name` itself.
+fn inspectable_table<'a>(name: &str, config: &'a Config) -> Option<&'a str> {
+    if let Some(table) = INSPECTABLE_TABLES.iter().find(|t| **t == name) {
+        return Some(table);
+    }
+    users_table(&config.users_table)
+        .ok()
+        .filter(|table| *table == name)
+}
+
+// Set once the schema was applied by this isolate
+static SCHEMA_READY: AtomicBool = AtomicBool::new(false);
+
+// Create or migrate the tables, once per isolate
+async fn ensure_schema(db: &Db) -> anyhow::Result<()> {
+    if SCHEMA_READY.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    apply_schema(&db.primary, SCHEMA).await?;
+    SCHEMA_READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+async fn apply_schema(
+    db: &Connection<CloudflareSender>,
+    statements: &[&str],
+) -> anyhow::Result<()> {
+    for statement in statements {
+        if let Err(e) = db.execute(statement, ()).await {
+            let message = e.to_string();
+            if is_benign_schema_error(&message) {
+                tracing::debug!("Schema already up to date: {message}");
+                continue;
+            }
+            tracing::error!("Error creating schema: {message}");
+            anyhow::bail!("{message}")
+        }
+    }
+    Ok(())
+}
+
+// Errors meaning that the table or column being created is already there
+fn is_benign_schema_error(message: &str) -> bool {
+    message.contains("already exists") || message.contains("duplicate column name")
+}
+
+// Presentation options of the page, selected with query parameters
+#[derive(Default)]
+struct PageOptions {
+    // `?thin=true`: show at most one airport per map grid cell
+    thin: bool,
+    // `?bars=true`: show a bar next to every count of the scoreboard
+    bars: bool,
+    // `?theme=light|dark`: colors of the page, THEME_DEFAULT when not given
+    // (or not a theme)
+    theme: Option<Theme>,
+    // Language of the title and branding, from `Accept-Language`
+    language: Option<String>,
+}
+
+impl PageOptions {
+    fn from_query(query: &HashMap<String, String>) -> Self {
+        PageOptions {
+            thin: query.get("thin").is_some_and(|v| v == "true"),
+            bars: query.get("bars").is_some_and(|v| v == "true"),
+            theme: query.get("theme").and_then(|v| v.parse().ok()),
+            language: None,
+        }
+    }
+}
+
+// Serve a request to load the page
+async fn serve(
+    visit: &VisitContext,
+    db: &Db,
+    config: &Config,
+    options: &PageOptions,
+) -> anyhow::Result<String> {
+    ensure_schema(db).await?;
+    if should_count(visit, config) {
+        if let Some(visit) = normalize_key(visit, config) {
+            count_visit(&visit, db, config).await?;
+        }
+    }
+
+    let counter_response = db.read().query(&scoreboard_query(config), ()).await?;
+    let scoreboard = ResultSet::from_rows(counter_response)?;
+    let last_updated = if config.show_last_updated {
+        // The time of this request when no visit recorded when it was counted
+        Some(last_updated(db, config).await?.unwrap_or(visit.ts))
+    } else {
+        None
+    };
+
+    // The map loads its scripts and tiles from other hosts
+    #[cfg(feature = "map")]
+    let airports = if config.offline {
+        None
+    } else {
+        Some(map_airports(
+            db.read().query(&map_query(config), ()).await?,
+            options,
+        )?)
+    };
+    #[cfg(not(feature = "map"))]
+    let airports = None;
+
+    Ok(render_page_within(
+        config,
+        &scoreboard,
+        airports,
+        last_updated,
+        options,
+    ))
+}
+
+// Time of the latest counted visit, in milliseconds since the Unix epoch, of
+// the rows on the scoreboard. `updated_at` isn't indexed, so this reads the
+// whole counter.
+async fn last_updated(db: &Db, config: &Config) -> anyhow::Result<Option<i64>> {
+    let mut result = db
+        .read()
+        .query(
+            &format!(
+                "SELECT MAX(updated_at) FROM counter{}",
+                excluded_countries_filter(config)
+            ),
+            (),
+        )
+        .await?;
+    Ok(match result.next()? {
+        Some(row) => match row.get_value(0)? {
+            Value::Integer(ts) => Some(ts),
+            _ => None,
+        },
+        None => None,
+    })
+}
+
+// Rows of the scoreboard, without the EXCLUDED_COUNTRIES
+fn scoreboard_query(config: &Config) -> String {
+    format!(
+        "SELECT country, city, value FROM counter{}",
+        excluded_countries_filter(config)
+    )
+}
+
+// WHERE clause leaving the EXCLUDED_COUNTRIES out of the counter, if there are
+// any. Their codes are letters only, so they can be inlined.
+fn excluded_countries_filter(config: &Config) -> String {
+    if config.excluded_countries.is_empty() {
+        return String::new();
+    }
+    let excluded: Vec<String> = config
+        .excluded_countries
+        .iter()
+        .map(|country| format!("'{country}'"))
+        .collect();
+    format!(" WHERE country NOT IN ({})", excluded.join(", "))
+}
+
+// Appended to the scoreboard when parts of the page were left out
+const TRUNCATED_HTML: &str =
+    "<p>Some airports or scoreboard rows are not shown, to keep the page small.</p>";
+
+// Render the page within MAX_PAGE_BYTES. Map points go first, then the
+// scoreboard rows, the least visited ones first, halving the number kept
+// until the page fits. The most visited row is always kept, as an empty
+// scoreboard would read as no visits at all. A page too big even then is
+// served as is.
+fn render_page_within(
+    config: &Config,
+    scoreboard: &ResultSet,
+    airports: Option<Vec<AirportEntry>>,
+    last_updated: Option<i64>,
+    options: &PageOptions,
+) -> String {
+    let render = |rows: &[Vec<Value>], points: Option<&[AirportEntry]>, truncated: bool| {
+        let mut table = if rows.is_empty() {
+            NO_VISITS_HTML.to_string()
+        } else {
+            render_html_table_with(&scoreboard.columns, rows, config, options.bars)
+        };
+        if truncated {
+            table.push_str(TRUNCATED_HTML);
+        }
+        #[cfg(feature = "map")]
+        let canvas = points.map(|points| render_map(points, config));
+        #[cfg(not(feature = "map"))]
+        let canvas = points.map(|_| String::new());
+        render_page(&PageContext {
+            theme: options.theme.unwrap_or(config.theme_default),
+            last_updated: last_updated.map(fmt_utc),
+            ..PageContext::new(config, table, canvas).localize(config, options.language.as_deref())
+        })
+    };
+
+    let page = render(&scoreboard.rows, airports.as_deref(), false);
+    if page.len() <= config.max_page_bytes {
+        return page;
+    }
+
+    let mut airports = airports;
+    if let Some(airports) = &mut airports {
+        airports.sort_by(|a, b| b.visits.cmp(&a.visits));
+    }
+    let mut rows = scoreboard.rows.clone();
+    if let Some(count) = scoreboard.columns.iter().position(|c| c == COUNT_COLUMN) {
+        rows.sort_by(|a, b| count_as_f64(&b[count]).total_cmp(&count_as_f64(&a[count])));
+    }
+    let mut points_kept = airports.as_ref().map_or(0, Vec::len);
+    let mut rows_kept = rows.len();
+    loop {
+        if points_kept > 0 {
+            points_kept /= 2;
+        } else if rows_kept > 1 {
+            rows_kept /= 2;
+        } else {
+            tracing::warn!("Page exceeds MAX_PAGE_BYTES even without airports and with one row");
+        }
+        let points = airports.as_deref().map(|a| &a[..points_kept]);
+        let page = render(&rows[..rows_kept], points, true);
+        if page.len() <= config.max_page_bytes || (points_kept == 0 && rows_kept <= 1) {
+            return page;
+        }
+    }
+}
+
+// Counter rows visits are folded into once MAX_CITIES is reached, one per country
+const OTHER_CITY: &str = "(other)";
+
+// City of the row visits from an empty city are counted in, with EMPTY_CITY=bucket
+const UNKNOWN_CITY: &str = "(unknown city)";
+
+// The visit with the city it's counted under, or None when it isn't counted
+// because its city is empty (see EMPTY_CITY)
+fn normalize_key<'a>(visit: &'a VisitContext, config: &Config) -> Option<Cow<'a, VisitContext>> {
+    if !visit.city.trim().is_empty() {
+        return Some(Cow::Borrowed(visit));
+    }
+    match config.empty_city {
+        EmptyCity::Bucket => Some(Cow::Owned(VisitContext {
+            city: UNKNOWN_CITY.to_string(),
+            ..visit.clone()
+        })),
+        EmptyCity::Skip => None,
+        EmptyCity::Keep => Some(Cow::Borrowed(visit)),
+    }
+}
+
+// City a visit is counted under. With CITY_CASE_INSENSITIVE, a city that
+// differs from an existing row only in case is counted under that row's
+// spelling. Once the counter holds MAX_CITIES rows, cities without a row yet
+// are counted as OTHER_CITY of their country, so that odd city names can't
+// grow the table without bounds.
+//
+// Case is matched here rather than with a NOCASE collation of the primary
+// key, which existing tables can't change without being rebuilt.
+async fn counted_city(visit: &VisitContext, db: &Db, config: &Config) -> anyhow::Result<String> {
+    if config.max_cities.is_none() && !config.city_case_insensitive {
+        return Ok(visit.city.clone());
+    }
+    let collation = if config.city_case_insensitive {
+        "NOCASE"
+    } else {
+        "BINARY"
+    };
+    let count = if config.max_cities.is_some() {
+        "(SELECT COUNT(*) FROM counter)"
+    } else {
+        "0"
+    };
+    let mut result = db
+        .primary
+        .query(
+            &format!(
+                "SELECT (SELECT city FROM counter WHERE country = ?1 AND city = ?2 COLLATE {collation} ORDER BY city = ?2 DESC LIMIT 1), {count}"
+            ),
+            params![visit.country.clone(), visit.city.clone()],
+        )
+        .await?;
+    let (existing, rows) = match result.next()? {
+        Some(row) => match row.get_value(0)? {
+            Value::Text(city) => (Some(city), row.get::<i64>(1)?),
+            _ => (None, row.get::<i64>(1)?),
+        },
+        None => (None, 0),
+    };
+    match (existing, config.max_cities) {
+        (Some(city), _) => Ok(city),
+        (None, Some(max_cities)) if rows as u64 >= max_cities => Ok(OTHER_CITY.to_string()),
+        (None, _) => Ok(visit.city.clone()),
+    }
+}
+
+const LOCATE_SEPARATOR: char = ';';
+
+// Separator of the `/locate` fields from `?sep=`. It has to be a single
+// character that can't be mistaken for a part of a field.
+fn locate_separator(sep: Option<&str>) -> Result<char, String> {
+    let Some(sep) = sep else {
+        return Ok(LOCATE_SEPARATOR);
+    };
+    let mut chars = sep.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !c.is_alphanumeric() && !c.is_whitespace() && c != '"' && c != '.' => {
+            Ok(c)
+        }
+        _ => Err(
+            "sep must be a single character other than a letter, digit, whitespace, '\"' or '.'"
+                .to_string(),
+        ),
+    }
+}
+
+// The `/locate` fields joined by `sep`. Fields containing it are quoted like
+// in CSV, so that e.g. city names with the separator can still be split.
+fn locate_line(fields: &[String], sep: char) -> String {
+    let fields: Vec<String> = fields.iter().map(|f| quote_field(f, sep)).collect();
+    fields.join(&sep.to_string())
+}
+
+// Add the visit's airport to the visited ones. With COORD_UPDATE, an airport
+// already there gets the visit's coordinates, so that wrong ones heal.
+// Approximate ones never replace the coordinates of an airport, nor add a
+// second row for an airport that has some already.
+async fn record_coordinates(visit: &VisitContext, db: &Db, config: &Config) -> anyhow::Result<()> {
+    let Some((coordinates, approximate)) = visit.map_location(config) else {
+        return Ok(());
+    };
+    let timezone = visit
+        .timezone
+        .clone()
+        .map(Value::Text)
+        .unwrap_or(Value::Null);
+    if approximate {
+        // The table is keyed by (lat, long), not by airport, so an airport seen
+        // before would otherwise get another row at the capital. Airports
+        // placed at the same capital share its point, under the first one.
+        db.primary
+            .execute(
+                "INSERT OR IGNORE INTO coordinates(lat, long, airport, timezone, approximate) SELECT ?1, ?2, ?3, ?4, 1 WHERE NOT EXISTS (SELECT 1 FROM coordinates WHERE airport = ?3)",
+                params![coordinates.0, coordinates.1, visit.airport.clone(), timezone],
+            )
+            .await?;
+        return Ok(());
+    }
+    if config.coord_update {
+        // Only repaired tables have a unique index on airport to upsert on (see
+        // `/admin/repair-coordinates`), so the row is updated first, and only
+        // inserted when there wasn't any
+        let updated = db
+            .primary
+            .execute(
+                "UPDATE OR IGNORE coordinates SET lat = ?, long = ?, timezone = COALESCE(?, timezone), approximate = 0 WHERE airport = ?",
+                params![
+                    coordinates.0,
+                    coordinates.1,
+                    timezone.clone(),
+                    visit.airport.clone()
+                ],
+            )
+            .await?;
+        if updated > 0 {
+            return Ok(());
+        }
+    }
+    db.primary
+        .execute(
+            "INSERT OR IGNORE INTO coordinates(lat, long, airport, timezone) VALUES (?, ?, ?, ?)",
+            // Parameters with different types can be passed to a convenience macro - args!()
+            params![
+                coordinates.0,
+                coordinates.1,
+                visit.airport.clone(),
+                timezone
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+// How a visit would be recorded by "/", without counting it
+async fn whoami(visit: &VisitContext, db: &Db, config: &Config) -> anyhow::Result<WhoAmIResponse> {
+    ensure_schema(db).await?;
+    let key = normalize_key(visit, config);
+    Ok(WhoAmIResponse {
+        airport: visit.airport.clone(),
+        country: visit.country.clone(),
+        city: counted_city(key.as_deref().unwrap_or(visit), db, config).await?,
+        region: visit.region.clone(),
+        lat: visit.coordinates.unwrap_or_default().0,
+        lon: visit.coordinates.unwrap_or_default().1,
+        timezone: visit.timezone.clone(),
+        local_time: fmt_local_time(visit.ts, visit.utc_offset_min()),
+        counted: should_count(visit, config) && key.is_some(),
+    })
+}
+
+// Statement adding visits to the counter, with a row of (country, city, value,
+// updated_at, region) for each of `cities`. A single upsert, so that
+// concurrent visits of the same city can't lose an increment between creating
+// the row and updating it.
+fn count_upsert(cities: usize) -> String {
+    let rows = vec!["(?, ?, ?, ?, ?)"; cities].join(", ");
+    format!(
+        "INSERT INTO counter(country, city, value, updated_at, region) VALUES {rows}
+        ON CONFLICT(country, city) DO UPDATE SET
+            value = value + excluded.value,
+            updated_at = excluded.updated_at,
+            region = COALESCE(excluded.region, region)"
+    )
+}
+
+// Add a visit to the counter and to the visited airports
+async fn count_visit(visit: &VisitContext, db: &Db, config: &Config) -> anyhow::Result<()> {
+    let city = counted_city(visit, db, config).await?;
+    let weight = visit_weight(visit, db, config).await?;
+    db.primary
+        .execute(
+            &count_upsert(1),
+            params![
+                visit.country.clone(),
+                city.clone(),
+                weight.clone(),
+                visit.ts,
+                visit.region.clone().map(Value::Text).unwrap_or(Value::Null)
+            ],
+        )
+        .await?;
+    record_coordinates(visit, db, config).await?;
+    db.primary
+        .execute(
+            "UPDATE coordinates SET visits = COALESCE(visits, 0) + ?, last_seen = ? WHERE airport = ?",
+            params![weight, visit.ts, visit.airport.clone()],
+        )
+        .await?;
+    if config.visit_logging {
+        db.primary
+            .execute(
+                "INSERT INTO visits VALUES (?, ?, ?, ?)",
+                params![
+                    visit.ts,
+                    visit.airport.clone(),
+                    visit.country.clone(),
+                    city.clone()
+                ],
+            )
+            .await?;
+    }
+    invalidate_caches();
+    Ok(())
+}
+
+// Dynamic pieces of the page. Every field holds ready-to-insert HTML: text
+// is escaped when the context is built, so the template never has to.
+struct PageContext {
+    title: String,
+    scoreboard_html: String,
+    map_html: Option<String>,
+    branding_html: String,
+    theme: Theme,
+    // When the counter was last updated, in UTC, if the page says it
+    last_updated: Option<String>,
+}
+
+impl PageContext {
+    // The title is operator-provided text and is escaped, while the branding
+    // is trusted HTML and taken as is
+    fn new(config: &Config, scoreboard_html: String, map_html: Option<String>) -> Self {
+        PageContext {
+            title: html_escape(&config.page_title),
+            scoreboard_html,
+            map_html,
+            branding_html: config.branding_html.clone(),
+            theme: config.theme_default,
+            last_updated: None,
+        }
+    }
+
+    // Title and branding in `language`, where PAGE_I18N has them. The
+    // scoreboard's data, such as city names, is never translated.
+    fn localize(mut self, config: &Config, language: Option<&str>) -> Self {
+        if let Some(page) = language.and_then(|l| config.page_i18n.get(l)) {
+            if let Some(title) = &page.title {
+                self.title = html_escape(title);
+            }
+            if let Some(branding_html) = &page.branding_html {
+                self.branding_html = branding_html.clone();
+            }
+        }
+        self
+    }
+}
+
+// Language of the page: the most preferred one of the `Accept-Language`
+// header that PAGE_I18N has strings for, by its primary subtag (`fr-CH` is
+// `fr`). None means the configured PAGE_TITLE and BRANDING_HTML.
+fn page_language(accept_language: Option<&str>, config: &Config) -> Option<String> {
+    let mut languages: Vec<(String, f32)> = accept_language?
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            let primary = tag.split('-').next()?.to_ascii_lowercase();
+            (quality > 0.0 && !primary.is_empty()).then_some((primary, quality))
+        })
+        .collect();
+    // A stable sort keeps the header's order among equal preferences
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages
+        .into_iter()
+        .map(|(language, _)| language)
+        .find(|language| config.page_i18n.contains_key(language))
+}
+
+// Colors of the page and the scoreboard, as CSS custom properties. The table
+// borders take the text color, so they follow the theme as well.
+fn theme_css(theme: Theme) -> &'static str {
+    match theme {
+        Theme::Light => {
+            "<style>:root { --page-bg: #ffffff; --page-fg: #1a1a1a; --table-bg: #ffffff; }\
+            body { background: var(--page-bg); color: var(--page-fg); }\
+            table { background: var(--table-bg); }</style>"
+        }
+        Theme::Dark => {
+            "<style>:root { --page-bg: #121212; --page-fg: #e0e0e0; --table-bg: #1e1e1e; }\
+            body { background: var(--page-bg); color: var(--page-fg); }\
+            table { background: var(--table-bg); }</style>"
+        }
+    }
+}
+
+// Assemble the page, with the map section only when a map was rendered
+fn render_page(ctx: &PageContext) -> String {
+    let title = &ctx.title;
+    let scoreboard = &ctx.scoreboard_html;
+    let branding = &ctx.branding_html;
+    let theme = theme_css(ctx.theme);
+    let last_updated = match &ctx.last_updated {
+        Some(time) => format!("<p>Last updated: {time}</p>"),
+        None => String::new(),
+    };
+    let (canvas, footer) = match &ctx.map_html {
+        Some(canvas) if !canvas.is_empty() => (
+            canvas.as_str(),
+            "<footer>Map data from OpenStreetMap (https://tile.osm.org/)</footer>",
+        ),
+        _ => ("", ""),
+    };
+    format!(
+        r#"<!DOCTYPE html>
+        <html>
+        <head><meta charset="utf-8"><title>{title}</title>{theme}</head>
+        <body>
+        {canvas} {branding}
+        <br /> Scoreboard: <br /> {scoreboard}
+        {last_updated}
+        {footer}
+        </body>
+        </html>
+        "#
+    )
+}
+
+// A globe, served for /favicon.ico so that browsers stop getting 404s.
+// Browsers accept an SVG body there regardless of the extension.
+const FAVICON_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16"><circle cx="8" cy="8" r="7" fill="#4a90d9"/><path d="M1 8h14M8 1c-3 4-3 10 0 14M8 1c3 4 3 10 0 14" stroke="#fff" fill="none"/></svg>"##;
+
+// The icon never changes, so browsers may keep it for a week
+const FAVICON_CACHE_CONTROL: &str = "public, max-age=604800, immutable";
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+// Status, headers and body of a response that doesn't depend on the request
+// or the database. Unlike `Response`, whose headers only exist in Workers, it
+// can be built and checked in tests.
+#[derive(Debug, PartialEq)]
+struct PlainResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl PlainResponse {
+    fn into_response(self) -> Result<Response> {
+        let mut response = Response::ok(self.body)?.with_status(self.status);
+        for (name, value) in &self.headers {
+            response.headers_mut().set(name, value)?;
+        }
+        Ok(response)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// ROBOTS_TXT, for /robots.txt
+fn robots_txt(config: &Config) -> PlainResponse {
+    PlainResponse {
+        status: 200,
+        headers: vec![("Content-Type", TEXT_CONTENT_TYPE.to_string())],
+        body: config.robots_txt.clone(),
+    }
+}
+
+// The icon, for /favicon.ico
+fn favicon() -> PlainResponse {
+    PlainResponse {
+        status: 200,
+        headers: vec![
+            ("Content-Type", "image/svg+xml".to_string()),
+            ("Cache-Control", FAVICON_CACHE_CONTROL.to_string()),
+        ],
+        body: FAVICON_SVG.to_string(),
+    }
+}
+
+// Liveness probe: status and body of the response to `GET /ping`, or None for
+// any other request. It depends on nothing, not even the configuration, so it
+// only fails when the worker itself is down.
+fn ping(method: &str, path: &str) -> Option<(u16, &'static str)> {
+    (method == "GET" && path == "/ping").then_some((200, "pong"))
+}
+
+const MARKDOWN_CONTENT_TYPE: &str = "text/markdown; charset=utf-8";
+
+// Lists the page as the only URL worth indexing. `origin` is the scheme and
+// host the worker was reached at, e.g. `https://example.com`.
+fn sitemap_xml(origin: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9"><url><loc>{}/</loc></url></urlset>
+"#,
+        html_escape(origin)
+    )
+}
+
+// City names are often non-ASCII, so the charset is always stated explicitly
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+fn json_error(message: impl Into<String>, status: u16) -> Result<Response> {
+    let body = ErrorResponse {
+        error: message.into(),
+    };
+    Ok(Response::from_json(&body)?.with_status(status))
+}
+
+// With OFFLINE, the page is only the scoreboard, so the browser can be told
+// not to load anything but the icon. Inline styles are needed by the table.
+const OFFLINE_CSP: &str =
+    "default-src 'none'; img-src 'self'; style-src 'unsafe-inline'; base-uri 'none'; form-action 'none'";
+
+// `Content-Security-Policy` of the page, if it has one
+fn page_csp(config: &Config) -> Option<&'static str> {
+    config.offline.then_some(OFFLINE_CSP)
+}
+
+// The page, with its CSP
+fn page(html: String, config: &Config) -> PlainResponse {
+    let mut headers = vec![
+        ("Content-Type", HTML_CONTENT_TYPE.to_string()),
+        // The title and branding depend on the visitor's language
+        ("Vary", "Accept-Language".to_string()),
+    ];
+    if let Some(csp) = page_csp(config) {
+        headers.push(("Content-Security-Policy", csp.to_string()));
+    }
+    PlainResponse {
+        status: 200,
+        headers,
+        body: html,
+    }
+}
+
+// Response to `HEAD /`: the headers of the page, without rendering it, and
+// so without counting the visit
+fn head_page(config: &Config) -> PlainResponse {
+    page(String::new(), config)
+}
+
+fn page_response(html: String, config: &Config) -> Result<Response> {
+    page(html, config).into_response()
+}
+
+fn html_response(html: String) -> Result<Response> {
+    let mut response = Response::from_html(html)?;
+    response
+        .headers_mut()
+        .set("Content-Type", HTML_CONTENT_TYPE)?;
+    Ok(response)
+}
+
+// The counter's `value` column has no declared type, so legacy rows may hold
+// the count as text. Anything that isn't a number counts as 0.
+fn parse_counter_value(value: Value) -> i64 {
+    match value {
+        Value::Integer(v) => v,
+        Value::Real(v) => v.round() as i64,
+        Value::Null => 0,
+        Value::Text(text) => {
+            let text = text.trim();
+            match text.parse::<i64>() {
+                Ok(v) => v,
+                Err(_) => match text.parse::<f64>() {
+                    Ok(v) if v.is_finite() => v.round() as i64,
+                    _ => {
+                        tracing::warn!("Counting non-numeric counter value {text:?} as 0");
+                        0
+                    }
+                },
+            }
+        }
+        Value::Blob(_) => {
+            tracing::warn!("Counting blob counter value as 0");
+            0
+        }
+    }
+}
+
+// Aggregates show countries and cities left NULL or empty by older versions
+// under this label, rather than as a nameless group
+const UNKNOWN_LABEL: &str = "(unknown)";
+
+// SQL expression of a counter column, with NULL and empty values replaced by
+// UNKNOWN_LABEL. `column` must be a trusted column name.
+fn known_or_unknown(column: &str) -> String {
+    format!("COALESCE(NULLIF({column}, ''), '{UNKNOWN_LABEL}')")
+}
+
+// Totals across the whole counter table
+async fn stats(
+    db: &Connection<CloudflareSender>,
+    decimals: u32,
+) -> anyhow::Result<serde_json::Value> {
+    let mut result = db
+        .query(
+            &format!(
+                "SELECT COUNT(DISTINCT {}), COUNT(*), COALESCE(SUM(value), 0) FROM counter",
+                known_or_unknown("country")
+            ),
+            (),
+        )
+        .await?;
+    let row = match result.next()? {
+        Some(row) => row,
+        None => anyhow::bail!("aggregate query returned no rows"),
+    };
+    let stats = stats_response(
+        row.get(0)?,
+        row.get(1)?,
+        parse_counter_value(row.get_value(2)?),
+        decimals,
+    );
+    Ok(serde_json::to_value(stats)?)
+}
+
+fn stats_response(countries: i64, cities: i64, visits: i64, decimals: u32) -> StatsResponse {
+    StatsResponse {
+        countries,
+        cities,
+        visits,
+        avg_visits_per_city: average(visits, cities, decimals),
+        avg_visits_per_country: average(visits, countries, decimals),
+    }
+}
+
+// `total / count` rounded to `decimals`, or 0 when there's nothing to divide by
+fn average(total: i64, count: i64, decimals: u32) -> f64 {
+    if count == 0 {
+        return 0.0;
+    }
+    // f64 holds no more than 15 significant decimals anyway
+    let scale = 10f64.powi(decimals.min(15) as i32);
+    (total as f64 / count as f64 * scale).round() / scale
+}
+
+const TOP_LIST: ListSpec = ListSpec {
+    default_limit: 10,
+    max_limit: 100,
+    orders: &[("value", "value DESC, country"), ("country", "country")],
+};
+
+// Countries with the highest number of visits, summed over all their cities
+async fn top_countries(
+    db: &Connection<CloudflareSender>,
+    list: &ListParams,
+) -> anyhow::Result<serde_json::Value> {
+    let order_by = list.order_by(&TOP_LIST).unwrap_or("value DESC");
+    let mut result = db
+        .query(
+            &format!(
+                "SELECT {} AS country, SUM(value) AS value FROM counter GROUP BY 1 ORDER BY {order_by} LIMIT ? OFFSET ?",
+                known_or_unknown("country")
+            ),
+            params![i64::from(list.limit), i64::from(list.offset)],
+        )
+        .await?;
+    let mut top = Vec::new();
+    while let Some(row) = result.next()? {
+        top.push(CounterEntry {
+            country: row.get(0)?,
+            city: None,
+            value: parse_counter_value(row.get_value(1)?),
+        });
+    }
+    Ok(serde_json::to_value(top)?)
+}
+
+const TOP_CITIES_LIST: ListSpec = ListSpec {
+    default_limit: 10,
+    max_limit: 100,
+    orders: &[("value", "value DESC, country, city")],
+};
+
+// Cities with the highest number of visits
+async fn top_cities(
+    db: &Connection<CloudflareSender>,
+    list: &ListParams,
+) -> anyhow::Result<Vec<CounterEntry>> {
+    let order_by = list
+        .order_by(&TOP_CITIES_LIST)
+        .unwrap_or("value DESC, country, city");
+    let mut result = db
+        .query(
+            &format!(
+                "SELECT {} AS country, {} AS city, value FROM counter ORDER BY {order_by} LIMIT ? OFFSET ?",
+                known_or_unknown("country"),
+                known_or_unknown("city")
+            ),
+            params![i64::from(list.limit), i64::from(list.offset)],
+        )
+        .await?;
+    let mut top = Vec::new();
+    while let Some(row) = result.next()? {
+        top.push(CounterEntry {
+            country: row.get(0)?,
+            city: Some(row.get(1)?),
+            value: parse_counter_value(row.get_value(2)?),
+        });
+    }
+    Ok(top)
+}
+
+// A server-sent event carrying `data`, a single line of JSON, which asks the
+// browser to reconnect after `retry_ms` once the stream ends. Workers can't
+// keep polling the database for an open stream, so `/counter/stream` sends a
+// single snapshot and EventSource's reconnection fetches the next one.
+fn sse_snapshot(data: &serde_json::Value, retry_ms: u64) -> String {
+    format!("data: {data}\nretry: {retry_ms}\n\n")
+}
+
+// `/top` and `/top-cities` accept `?n=` as an alias of `?limit=`, which it predates
+fn top_list_params(
+    url: &Url,
+    spec: &ListSpec,
+) -> std::result::Result<ListParams, Result<Response>> {
+    let mut list = ListParams::from_query(url, spec)?;
+    // As before `?limit=` existed, an `n` that isn't a number is ignored
+    if query_pair(url, "limit").is_none() {
+        if let Some(n) = query_pair(url, "n").and_then(|n| n.parse::<u32>().ok()) {
+            list.limit = n.clamp(1, spec.max_limit);
+        }
+    }
+    Ok(list)
+}
+
+// Visits per continent, busiest first
+async fn continent_totals(db: &Connection<CloudflareSender>) -> anyhow::Result<serde_json::Value> {
+    let mut result = db
+        .query(
+            &format!(
+                "SELECT {}, SUM(value) FROM counter GROUP BY 1",
+                known_or_unknown("country")
+            ),
+            (),
+        )
+        .await?;
+    let mut countries = Vec::new();
+    while let Some(row) = result.next()? {
+        countries.push((
+            row.get::<String>(0)?,
+            parse_counter_value(row.get_value(1)?),
+        ));
+    }
+    Ok(serde_json::to_value(sum_by_continent(countries))?)
+}
+
+// Number of cities per range of counts, e.g. `{"1": 10, "2-5": 4, "51+": 1}`
+async fn count_histogram(
+    db: &Connection<CloudflareSender>,
+    bounds: &[i64],
+) -> anyhow::Result<serde_json::Value> {
+    let mut result = db
+        .query("SELECT value, COUNT(*) FROM counter GROUP BY 1", ())
+        .await?;
+    let mut counts = Vec::new();
+    while let Some(row) = result.next()? {
+        counts.push((parse_counter_value(row.get_value(0)?), row.get::<i64>(1)?));
+    }
+    Ok(histogram(counts, bounds))
+}
+
+// Bucket the number of cities with each count by the upper `bounds` of the
+// buckets, ascending. Every bucket is present, even when empty, and cities
+// with no visits left aren't in any.
+fn histogram(counts: Vec<(i64, i64)>, bounds: &[i64]) -> serde_json::Value {
+    let mut buckets = vec![0; bounds.len() + 1];
+    for (value, cities) in counts {
+        if value < 1 {
+            continue;
+        }
+        let bucket = bounds.partition_point(|bound| *bound < value);
+        buckets[bucket] += cities;
+    }
+    let mut histogram = serde_json::Map::new();
+    let mut low = 1;
+    for (i, cities) in buckets.into_iter().enumerate() {
+        let label = match bounds.get(i) {
+            Some(&high) if high == low => low.to_string(),
+            Some(&high) => format!("{low}-{high}"),
+            None => format!("{low}+"),
+        };
+        histogram.insert(label, cities.into());
+        low = bounds.get(i).map_or(low, |high| high + 1);
+    }
+    histogram.into()
+}
+
+// Add up per-country totals by continent, ordered by the sums (descending)
+// and then by name
+fn sum_by_continent(countries: Vec<(String, i64)>) -> Vec<ContinentEntry> {
+    let mut totals: HashMap<&'static str, i64> = HashMap::new();
+    for (country, value) in countries {
+        *totals
+            .entry(continents::continent_of(&country))
+            .or_default() += value;
+    }
+    let mut entries: Vec<ContinentEntry> = totals
+        .into_iter()
+        .map(|(continent, value)| ContinentEntry {
+            continent: continent.to_string(),
+            value,
+        })
+        .collect();
+    entries.sort_by(|a, b| b.value.cmp(&a.value).then(a.continent.cmp(&b.continent)));
+    entries
+}
+
+// Fields of the rows of each `/data` section
+const COUNTER_FIELDS: &[&str] = &["country", "city", "value"];
+const AIRPORT_FIELDS: &[&str] = &["airport", "lat", "lon", "timezone", "visits", "approximate"];
+
+// Fields picked by a comma-separated `?columns=`-like parameter, all of them
+// when it's missing
+fn parse_fields(
+    name: &str,
+    param: Option<&str>,
+    known: &'static [&'static str],
+) -> std::result::Result<Vec<&'static str>, String> {
+    let Some(param) = param else {
+        return Ok(known.to_vec());
+    };
+    let mut fields = Vec::new();
+    for field in param.split(',').map(str::trim) {
+        match known.iter().find(|k| **k == field) {
+            Some(k) if !fields.contains(k) => fields.push(*k),
+            Some(_) => {}
+            None => return Err(format!("{name} must be a list of {}", known.join(", "))),
+        }
+    }
+    Ok(fields)
+}
+
+// Serialize `rows`, keeping only the given fields of each
+fn project<T: serde::Serialize>(
+    rows: &[T],
+    fields: &[&str],
+) -> serde_json::Result<Vec<serde_json::Value>> {
+    rows.iter()
+        .map(|row| {
+            let mut value = serde_json::to_value(row)?;
+            if let serde_json::Value::Object(map) = &mut value {
+                map.retain(|key, _| fields.contains(&key.as_str()));
+            }
+            Ok(value)
+        })
+        .collect()
+}
+
+// The whole counter and all visited airports, for clients drawing their own map
+async fn all_data(
+    db: &Connection<CloudflareSender>,
+    counter_fields: &[&str],
+    airport_fields: &[&str],
+) -> anyhow::Result<serde_json::Value> {
+    let mut result = db
+        .query("SELECT country, city, value FROM counter", ())
+        .await?;
+    let mut counter = Vec::new();
+    while let Some(row) = result.next()? {
+        counter.push(CounterEntry {
+            country: row.get(0)?,
+            city: Some(row.get(1)?),
+            value: parse_counter_value(row.get_value(2)?),
+        });
+    }
+    let airports = parse_airports(db.query(AIRPORTS_QUERY, ()).await?)?;
+    Ok(json!({
+        "counter": project(&counter, counter_fields)?,
+        "airports": project(&airports, airport_fields)?,
+    }))
+}
+
+// Visits per region of each country, busiest first. Visits from cities with
+// an unknown region are summed up under a null region.
+async fn region_totals(db: &Connection<CloudflareSender>) -> anyhow::Result<Vec<RegionEntry>> {
+    let mut result = db
+        .query(
+            &format!(
+                "SELECT {}, region, SUM(value) AS value FROM counter GROUP BY 1, 2 ORDER BY value DESC",
+                known_or_unknown("country")
+            ),
+            (),
+        )
+        .await?;
+    let mut regions = Vec::new();
+    while let Some(row) = result.next()? {
+        regions.push(RegionEntry {
+            country: row.get(0)?,
+            region: match row.get_value(1)? {
+                Value::Text(region) => Some(region),
+                _ => None,
+            },
+            value: parse_counter_value(row.get_value(2)?),
+        });
+    }
+    Ok(regions)
+}
+
+// Most operations a `/batch` request may have, keeping the statement applying
+// them well below SQLite's limit on parameters
+const MAX_BATCH_OPS: usize = 100;
+
+// Largest increment of a single `/batch` operation
+const MAX_BATCH_BY: i64 = 1_000_000;
+
+// Why a `/batch` operation can't be applied, if it can't
+fn batch_op_error(op: &BatchOp, visit: &VisitContext, config: &Config) -> Option<String> {
+    if let Some(error) = country_code_error(&op.country) {
+        return Some(error);
+    }
+    if op.city.trim().is_empty() {
+        return Some("city must not be empty".to_string());
+    }
+    if !(1..=MAX_BATCH_BY).contains(&op.by) {
+        return Some(format!("by must be between 1 and {MAX_BATCH_BY}"));
+    }
+    if !should_count(visit, config) {
+        return Some(format!("visits from {} aren't counted", op.country));
+    }
+    None
+}
+
+// Why a country can't be written to the counter, if it can't
+fn country_code_error(country: &str) -> Option<String> {
+    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+        return Some(format!(
+            "country must be a two-letter uppercase code, got {country:?}"
+        ));
+    }
+    None
+}
+
+// Write counts of cities with a single upsert, which SQLite applies entirely
+// or not at all. With `replace`, the counts replace those in the counter,
+// otherwise they're added to them. Rows of the same city are applied in
+// order, as each one conflicts with the previous.
+async fn upsert_counts(
+    db: &Db,
+    counts: &[(&str, &str, Value)],
+    replace: bool,
+    now_ms: i64,
+) -> anyhow::Result<()> {
+    if counts.is_empty() {
+        return Ok(());
+    }
+    ensure_schema(db).await?;
+    let rows = vec!["(?, ?, ?, ?)"; counts.len()].join(", ");
+    let mut values = Vec::with_capacity(counts.len() * 4);
+    for (country, city, count) in counts {
+        values.push(Value::Text(country.to_string()));
+        values.push(Value::Text(city.to_string()));
+        values.push(count.clone());
+        values.push(Value::Integer(now_ms));
+    }
+    let value = if replace {
+        "excluded.value"
+    } else {
+        "value + excluded.value"
+    };
+    db.primary
+        .execute(
+            &format!(
+                "INSERT INTO counter(country, city, value, updated_at) VALUES {rows}
+                ON CONFLICT(country, city) DO UPDATE SET
+                    value = {value},
+                    updated_at = excluded.updated_at"
+            ),
+            libsql::Params::Positional(values),
+        )
+        .await?;
+    invalidate_caches();
+    Ok(())
+}
+
+// Add the counts of `ops` to the counter, like as many visits of their cities:
+// those from EXCLUDED_COUNTRIES are refused, and cities are matched and capped
+// as by `counted_city`. With `atomic`, nothing is applied when any operation
+// is invalid, otherwise the valid ones are. Cities are looked up and counted
+// in a single transaction, so that other visits can't add rows in between.
+async fn apply_batch(
+    db: &Db,
+    config: &Config,
+    ops: &[BatchOp],
+    atomic: bool,
+    now_ms: i64,
+) -> anyhow::Result<BatchResponse> {
+    let visits: Vec<VisitContext> = ops
+        .iter()
+        .map(|op| VisitContext::from_batch_op(op, now_ms))
+        .collect();
+    let errors: Vec<Option<String>> = ops
+        .iter()
+        .zip(&visits)
+        .map(|(op, visit)| batch_op_error(op, visit, config))
+        .collect();
+    let invalid = errors.iter().any(Option::is_some);
+    if atomic && invalid {
+        let results =
+            errors
+                .into_iter()
+                .map(|error| BatchOpResult {
+                    ok: false,
+                    error: Some(error.unwrap_or_else(|| {
+                        "not applied, as other operations are invalid".to_string()
+                    })),
+                })
+                .collect();
+        return Ok(BatchResponse {
+            applied: 0,
+            results,
+        });
+    }
+
+    let valid: Vec<(&BatchOp, &VisitContext)> = ops
+        .iter()
+        .zip(&visits)
+        .zip(&errors)
+        .filter(|(_, error)| error.is_none())
+        .map(|(op, _)| op)
+        .collect();
+    if !valid.is_empty() {
+        ensure_schema(db).await?;
+        let tx = db
+            .primary
+            .transaction(TransactionBehavior::Immediate)
+            .await?;
+        let counted = count_batch_in(&tx, &valid, config).await;
+        match counted {
+            Ok(_) => tx.commit().await?,
+            Err(_) => tx.rollback().await?,
+        }
+        counted?;
+        invalidate_caches();
+        bump_table_version(&db.primary, UNLOGGED_COUNTS, now_ms).await?;
+    }
+    Ok(BatchResponse {
+        applied: valid.len(),
+        results: errors
+            .into_iter()
+            .map(|error| BatchOpResult {
+                ok: error.is_none(),
+                error,
+            })
+            .collect(),
+    })
+}
+
+async fn count_batch_in(
+    tx: &Transaction<CloudflareSender>,
+    ops: &[(&BatchOp, &VisitContext)],
+    config: &Config,
+) -> anyhow::Result<()> {
+    let cities = batch_cities(tx, ops, config).await?;
+    let mut values = Vec::with_capacity(ops.len() * 5);
+    for ((op, visit), city) in ops.iter().zip(cities) {
+        values.push(Value::Text(visit.country.clone()));
+        values.push(Value::Text(city));
+        values.push(Value::Integer(op.by));
+        values.push(Value::Integer(visit.ts));
+        values.push(Value::Null);
+    }
+    tx.execute(&count_upsert(ops.len()), libsql::Params::Positional(values))
+        .await?;
+    Ok(())
+}
+
+// Cities the visits of `ops` are counted under, as `counted_city` would count
+// them one after the other, but with a single query. Cities new to the
+// counter are matched and capped along with the rows added by earlier
+// operations of the batch.
+async fn batch_cities(
+    tx: &Transaction<CloudflareSender>,
+    ops: &[(&BatchOp, &VisitContext)],
+    config: &Config,
+) -> anyhow::Result<Vec<String>> {
+    if config.max_cities.is_none() && !config.city_case_insensitive {
+        return Ok(ops.iter().map(|(_, visit)| visit.city.clone()).collect());
+    }
+    let collation = if config.city_case_insensitive {
+        "NOCASE"
+    } else {
+        "BINARY"
+    };
+    let rows = vec!["(?, ?, ?)"; ops.len()].join(", ");
+    let mut values = Vec::with_capacity(ops.len() * 3);
+    for (i, (_, visit)) in ops.iter().enumerate() {
+        values.push(Value::Integer(i as i64));
+        values.push(Value::Text(visit.country.clone()));
+        values.push(Value::Text(visit.city.clone()));
+    }
+    let mut result = tx
+        .query(
+            &format!(
+                "WITH ops(i, country, city) AS (VALUES {rows})
+                SELECT (SELECT city FROM counter WHERE country = ops.country AND city = ops.city COLLATE {collation} ORDER BY city = ops.city DESC LIMIT 1),
+                    (SELECT COUNT(*) FROM counter)
+                FROM ops ORDER BY i"
+            ),
+            libsql::Params::Positional(values),
+        )
+        .await?;
+    let mut existing = Vec::with_capacity(ops.len());
+    let mut total = 0;
+    while let Some(row) = result.next()? {
+        existing.push(match row.get_value(0)? {
+            Value::Text(city) => Some(city),
+            _ => None,
+        });
+        total = row.get::<i64>(1)?;
+    }
+    anyhow::ensure!(
+        existing.len() == ops.len(),
+        "city lookup returned {} rows",
+        existing.len()
+    );
+
+    let same_city = |a: &str, b: &str| {
+        if config.city_case_insensitive {
+            a.eq_ignore_ascii_case(b)
+        } else {
+            a == b
+        }
+    };
+    let mut added: Vec<(&str, &str)> = Vec::new();
+    let mut cities = Vec::with_capacity(ops.len());
+    for ((_, visit), existing) in ops.iter().zip(existing) {
+        let earlier = added
+            .iter()
+            .find(|(country, city)| {
+                *country == visit.country && same_city(*city, visit.city.as_str())
+            })
+            .map(|(_, city)| city.to_string());
+        let city = match (existing, earlier, config.max_cities) {
+            (Some(city), _, _) | (None, Some(city), _) => city,
+            (None, None, Some(max_cities)) if (total as u64 + added.len() as u64) >= max_cities => {
+                OTHER_CITY.to_string()
+            }
+            (None, None, _) => {
+                added.push((&visit.country, &visit.city));
+                visit.city.clone()
+            }
+        };
+        cities.push(city);
+    }
+    Ok(cities)
+}
+
+// Most rows `/counter/import.csv` takes, so that they fit a single statement
+const MAX_IMPORT_ROWS: usize = 5000;
+
+// Rows of a counter CSV: `country,city,value` lines, with an optional header
+// line. Counts may be fractional, as weighted visits
+// are. The first invalid row fails the whole import, naming its line.
+fn parse_counter_csv(text: &str) -> Result<Vec<(String, String, Value)>, String> {
+    let mut records = parse_csv(text)?;
+    if records
+        .first()
+        .is_some_and(|(_, fields)| fields == &["country", "city", "value"])
+    {
+        records.remove(0);
+    }
+    if records.len() > MAX_IMPORT_ROWS {
+        return Err(format!(
+            "at most {MAX_IMPORT_ROWS} rows can be imported at once"
+        ));
+    }
+    records
+        .into_iter()
+        .map(|(line, fields)| {
+            let [country, city, value] = <[String; 3]>::try_from(fields).map_err(|fields| {
+                format!("line {line}: expected 3 fields, got {}", fields.len())
+            })?;
+            if let Some(error) = country_code_error(&country) {
+                return Err(format!("line {line}: {error}"));
+            }
+            if city.trim().is_empty() {
+                return Err(format!("line {line}: city must not be empty"));
+            }
+            let value = match (value.trim().parse::<i64>(), value.trim().parse::<f64>()) {
+                (Ok(count), _) if count >= 0 => Value::Integer(count),
+                (Err(_), Ok(count)) if count.is_finite() && count >= 0.0 => Value::Real(count),
+                _ => {
+                    return Err(format!(
+                        "line {line}: value must be a non-negative number, got {value:?}"
+                    ))
+                }
+            };
+            Ok((country, city, value))
+        })
+        .collect()
+}
+
+// Write the rows of a counter CSV to the counter, returning how many there were
+async fn import_counter_csv(
+    db: &Db,
+    csv: &str,
+    replace: bool,
+    now_ms: i64,
+) -> anyhow::Result<Result<usize, String>> {
+    let rows = match parse_counter_csv(csv) {
+        Ok(rows) => rows,
+        Err(error) => return Ok(Err(error)),
+    };
+    let counts: Vec<(&str, &str, Value)> = rows
+        .iter()
+        .map(|(country, city, value)| (country.as_str(), city.as_str(), value.clone()))
+        .collect();
+    upsert_counts(db, &counts, replace, now_ms).await?;
+    if !counts.is_empty() {
+        bump_table_version(&db.primary, UNLOGGED_COUNTS, now_ms).await?;
+    }
+    Ok(Ok(rows.len()))
+}
+
+// Rendered `/counter/:country` fragments of this isolate. The capacity is the
+// COUNTRY_CACHE_SIZE of the first request, as the cache outlives requests.
+static COUNTRY_PAGES: OnceLock<LruCache> = OnceLock::new();
+
+fn country_pages(config: &Config) -> &'static LruCache {
+    COUNTRY_PAGES.get_or_init(|| LruCache::new(config.country_cache_size))
+}
+
+// Drop the cached aggregates and fragments after a write to the counter, so
+// that this isolate never serves counts older than the write
+fn invalidate_caches() {
+    AggCache::global().invalidate_all();
+    if let Some(pages) = COUNTRY_PAGES.get() {
+        pages.invalidate_all();
+    }
+}
+
+// The cities of a country as an HTML table, busiest first
+async fn country_fragment(
+    db: &Connection<CloudflareSender>,
+    country: &str,
+    config: &Config,
+) -> anyhow::Result<String> {
+    let rows = db
+        .query(
+            "SELECT country, city, value FROM counter WHERE country = ? ORDER BY value DESC",
+            params![country],
+        )
+        .await?;
+    let result = ResultSet::from_rows(rows)?;
+    if result.rows.is_empty() {
+        return Ok(NO_VISITS_HTML.to_string());
+    }
+    Ok(result.to_html(config))
+}
+
+// Current count of a single city, 0 when it was never visited
+async fn city_count(
+    db: &Connection<CloudflareSender>,
+    country: &str,
+    city: &str,
+) -> anyhow::Result<i64> {
+    let mut result = db
+        .query(
+            "SELECT value FROM counter WHERE country = ? AND city = ?",
+            params![country, city],
+        )
+        .await?;
+    Ok(match result.next()? {
+        Some(row) => parse_counter_value(row.get_value(0)?),
+        None => 0,
+    })
+}
+
+// Visits of a country, summed over all its cities
+async fn country_total(
+    db: &Connection<CloudflareSender>,
+    country: &str,
+) -> anyhow::Result<CounterEntry> {
+    let mut result = db
+        .query(
+            "SELECT COALESCE(SUM(value), 0) FROM counter WHERE country = ?",
+            params![country],
+        )
+        .await?;
+    let value = match result.next()? {
+        Some(row) => parse_counter_value(row.get_value(0)?),
+        None => 0,
+    };
+    Ok(CounterEntry {
+        country: country.to_string(),
+        city: None,
+        value,
+    })
+}
+
+fn compare_countries(a: CounterEntry, b: CounterEntry) -> CompareResponse {
+    let leader = match a.value.cmp(&b.value) {
+        std::cmp::Ordering::Greater => Some(a.country.clone()),
+        std::cmp::Ordering::Less => Some(b.country.clone()),
+        std::cmp::Ordering::Equal => None,
+    };
+    CompareResponse { a, b, leader }
+}
+
+// Counter rows updated after `since` (exclusive), oldest change first. The
+// cursor is the latest `updated_at` returned, or `since` when nothing changed,
+// so that it can be passed back as the next `since`.
+async fn counter_delta(
+    db: &Connection<CloudflareSender>,
+    since: i64,
+) -> anyhow::Result<CounterDelta> {
+    let mut result = db
+        .query(
+            "SELECT country, city, value, updated_at FROM counter WHERE updated_at > ? ORDER BY updated_at",
+            params![since],
+        )
+        .await?;
+    let mut delta = CounterDelta {
+        rows: Vec::new(),
+        cursor: since,
+    };
+    while let Some(row) = result.next()? {
+        let entry = CounterDeltaEntry {
+            country: row.get(0)?,
+            city: row.get(1)?,
+            value: parse_counter_value(row.get_value(2)?),
+            updated_at: row.get(3)?,
+        };
+        delta.cursor = delta.cursor.max(entry.updated_at);
+        delta.rows.push(entry);
+    }
+    Ok(delta)
+}
+
+// Parse the required `?since=` parameter of `/counter/delta`
+fn parse_since(value: Option<&str>) -> std::result::Result<i64, &'static str> {
+    match value.map(str::parse::<i64>) {
+        None => Err("since is required"),
+        Some(Ok(since)) if since >= 0 => Ok(since),
+        Some(_) => Err("since must be a non-negative timestamp in milliseconds"),
+    }
+}
+
+// Days shown by the `/spark/:country.svg` sparklines, ending today
+const SPARK_DAYS: i64 = 30;
+
+// Size of a sparkline, in pixels
+const SPARK_WIDTH: f64 = 100.0;
+const SPARK_HEIGHT: f64 = 20.0;
+
+// Visits of `country` on each of the `days` days ending on `today` (in days
+// since the epoch, UTC), oldest first. Only visits recorded with
+// VISIT_LOGGING are known, so days before it was enabled show none.
+async fn daily_visits(
+    db: &Connection<CloudflareSender>,
+    country: &str,
+    today: i64,
+    days: i64,
+) -> anyhow::Result<Vec<i64>> {
+    let first_day = today - days + 1;
+    let mut rows = db
+        .query(
+            "SELECT ts / ?, COUNT(*) FROM visits WHERE country = ? AND ts >= ? GROUP BY 1",
+            params![MS_PER_DAY, country.to_string(), first_day * MS_PER_DAY],
+        )
+        .await?;
+    let mut buckets = vec![0; days as usize];
+    while let Some(row) = rows.next()? {
+        let day = row.get::<i64>(0)? - first_day;
+        if let Some(bucket) = usize::try_from(day).ok().and_then(|d| buckets.get_mut(d)) {
+            *bucket = row.get::<i64>(1)?;
+        }
+    }
+    Ok(buckets)
+}
+
+// An SVG line of the daily visits, scaled so that the busiest day reaches the
+// top. Without any visit, there's nothing to scale and it's a flat baseline.
+fn sparkline_svg(buckets: &[i64]) -> String {
+    let max = buckets.iter().copied().max().unwrap_or_default();
+    let line = if max <= 0 || buckets.len() < 2 {
+        format!(
+            r#"<line x1="0" y1="{SPARK_HEIGHT}" x2="{SPARK_WIDTH}" y2="{SPARK_HEIGHT}" stroke="lightgray" stroke-width="1"/>"#
+        )
+    } else {
+        let step = SPARK_WIDTH / (buckets.len() - 1) as f64;
+        let points: Vec<String> = buckets
+            .iter()
+            .enumerate()
+            .map(|(i, &visits)| {
+                let y = SPARK_HEIGHT - visits.max(0) as f64 / max as f64 * SPARK_HEIGHT;
+                format!("{:.1},{:.1}", i as f64 * step, y)
+            })
+            .collect();
+        format!(
+            r#"<polyline points="{}" fill="none" stroke="steelblue" stroke-width="1"/>"#,
+            points.join(" ")
+        )
+    };
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{SPARK_WIDTH}" height="{SPARK_HEIGHT}" viewBox="0 0 {SPARK_WIDTH} {SPARK_HEIGHT}">{line}</svg>"#
+    )
+}
+
+const SEARCH_LIST: ListSpec = ListSpec {
+    default_limit: 20,
+    max_limit: 100,
+    orders: &[
+        ("value", "value DESC"),
+        ("city", "city, country"),
+        ("country", "country, city"),
+    ],
+};
+
+// Cities or countries containing `query`, busiest first unless ordered otherwise
+async fn search_cities(
+    db: &Connection<CloudflareSender>,
+    query: &str,
+    list: &ListParams,
+) -> anyhow::Result<Vec<CounterEntry>> {
+    let pattern = like_pattern(query);
+    let order_by = list.order_by(&SEARCH_LIST).unwrap_or("value DESC");
+    let mut result = db
+        .query(
+            &format!(
+                r"SELECT country, city, value FROM counter
+                WHERE city LIKE ?1 ESCAPE '\' OR country LIKE ?1 ESCAPE '\'
+                ORDER BY {order_by} LIMIT ?2 OFFSET ?3"
+            ),
+            params![pattern, i64::from(list.limit), i64::from(list.offset)],
+        )
+        .await?;
+    let mut entries = Vec::new();
+    while let Some(row) = result.next()? {
+        entries.push(CounterEntry {
+            country: row.get(0)?,
+            city: Some(row.get(1)?),
+            value: parse_counter_value(row.get_value(2)?),
+        });
+    }
+    Ok(entries)
+}
+
+// A LIKE pattern matching `query` anywhere, with `%` and `_` in it matched literally
+fn like_pattern(query: &str) -> String {
+    let mut pattern = String::with_capacity(query.len() + 2);
+    pattern.push('%');
+    for c in query.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+// Query parameters of a request, keeping the first occurrence of each name.
+// Requests with a URL that can't be parsed or with more than MAX_QUERY_PARAMS
+// parameters are rejected with 400, and those with a parameter name or value
+// longer than MAX_QUERY_LEN bytes with 414, before a handler gets to act on
+// them. The error is the response to return as is.
+fn parse_query(
+    req: &Request,
+    config: &Config,
+) -> std::result::Result<HashMap<String, String>, Result<Response>> {
+    parse_query_once(req, config, &[])
+}
+
+// Like `parse_query`, but the `unique` parameters are rejected with 400 when
+// they are given more than once, rather than silently using the first one
+fn parse_query_once(
+    req: &Request,
+    config: &Config,
+    unique: &[&str],
+) -> std::result::Result<HashMap<String, String>, Result<Response>> {
+    let url = req.url().map_err(|e| {
+        tracing::warn!("Malformed request URL: {e}");
+        json_error("Malformed request URL", 400)
+    })?;
+    collect_query(&url, config.max_query_len, config.max_query_params, unique).map_err(
+        |e| match e {
+            QueryError::TooLong(name) => {
+                json_error(format!("Query parameter {name} is too long"), 414)
+            }
+            QueryError::TooMany(max) => {
+                json_error(format!("At most {max} query parameters are accepted"), 400)
+            }
+            QueryError::Repeated(name) => json_error(
+                format!("Query parameter {name} must be given only once"),
+                400,
+            ),
+        },
+    )
+}
+
+// Body of a request, rejected with 413 when it's larger than `max` bytes (see
+// MAX_BODY_BYTES). The error is the response to return as is.
+async fn read_body_limited(
+    req: &mut Request,
+    max: usize,
+) -> std::result::Result<Vec<u8>, Result<Response>> {
+    let content_length = req.headers().get("Content-Length").ok().flatten();
+    let body = match req.stream() {
+        Ok(stream) => collect_body(content_length.as_deref(), stream, max).await,
+        // Requests without a body have no stream to read
+        Err(_) => {
+            collect_body(
+                content_length.as_deref(),
+                futures_util::stream::empty(),
+                max,
+            )
+            .await
+        }
+    };
+    match body {
+        Ok(Some(body)) => Ok(body),
+        Ok(None) => Err(json_error(
+            format!("Request body is larger than {max} bytes"),
+            413,
+        )),
+        Err(e) => Err(Err(e)),
+    }
+}
+
+// The body read from `stream`, or None when it's larger than `max` bytes.
+// A larger Content-Length is refused without reading anything, and a body
+// longer than declared is refused as soon as it goes over `max`.
+async fn collect_body(
+    content_length: Option<&str>,
+    stream: impl futures_util::Stream<Item = Result<Vec<u8>>>,
+    max: usize,
+) -> Result<Option<Vec<u8>>> {
+    use futures_util::StreamExt;
+
+    let declared = content_length.and_then(|len| len.trim().parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max as u64) {
+        return Ok(None);
+    }
+    let mut stream = std::pin::pin!(stream);
+    let mut body = Vec::with_capacity(declared.unwrap_or(0) as usize);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(body))
+}
+
+// Why the query parameters of a request were rejected, with the parameter's name
+#[derive(Debug, PartialEq, Eq)]
+enum QueryError {
+    TooLong(String),
+    Repeated(String),
+    // More parameters than the given maximum
+    TooMany(usize),
+}
+
+// Query parameters of `url`, keeping the first occurrence of each name
+fn collect_query(
+    url: &Url,
+    max_len: usize,
+    max_params: usize,
+    unique: &[&str],
+) -> std::result::Result<HashMap<String, String>, QueryError> {
+    // Counted on the raw query, before any of them is decoded, so that a flood
+    // of parameters is turned away cheaply. Like `query_pairs`, empty ones
+    // between two `&` don't count.
+    let params = url.query().map_or(0, |query| {
+        query.split('&').filter(|param| !param.is_empty()).count()
+    });
+    if params > max_params {
+        return Err(QueryError::TooMany(max_params));
+    }
+    let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    if let Some((key, _)) = pairs
+        .iter()
+        .find(|(key, value)| key.len() > max_len || value.len() > max_len)
+    {
+        return Err(QueryError::TooLong(truncate_cell(key, 32)));
+    }
+    for name in unique {
+        if pairs.iter().filter(|(key, _)| key == name).count() > 1 {
+            return Err(QueryError::Repeated(name.to_string()));
+        }
+    }
+    let mut query = HashMap::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        query.entry(key).or_insert(value);
+    }
+    Ok(query)
+}
+
+const COORDINATES_LIST: ListSpec = ListSpec {
+    default_limit: UNLIMITED,
+    max_limit: UNLIMITED,
+    orders: &[
+        ("airport", "airport"),
+        ("visits", "COALESCE(visits, 0) DESC, airport"),
+    ],
+};
+
+// The users table may have any columns, so it's only paged in table order
+const USERS_LIST: ListSpec = ListSpec {
+    default_limit: UNLIMITED,
+    max_limit: UNLIMITED,
+    orders: &[],
+};
+
+// Database connections: writes always go to the primary, while reads go to
+// a replica when one is configured and fall back to the primary otherwise
+struct Db {
+    primary: Connection<CloudflareSender>,
+    replica: Option<Connection<CloudflareSender>>,
+}
+
+impl Db {
+    fn new(primary: Connection<CloudflareSender>) -> Self {
+        Db {
+            primary,
+            replica: None,
+        }
+    }
+
+    fn with_replica(mut self, replica: Connection<CloudflareSender>) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    fn read(&self) -> &Connection<CloudflareSender> {
+        self.replica.as_ref().unwrap_or(&self.primary)
+    }
+}
+
+// Admin endpoints require an `Authorization: Bearer <ADMIN_TOKEN>` header.
+// They are disabled entirely when no ADMIN_TOKEN secret is configured.
+fn is_admin(req: &Request, env: &Env) -> bool {
+    let authorization = req.headers().get("Authorization").ok().flatten();
+    let admin_token = env.secret("ADMIN_TOKEN").ok().map(|t| t.to_string());
+    check_admin_token(authorization.as_deref(), admin_token.as_deref())
+}
+
+fn check_admin_token(authorization: Option<&str>, admin_token: Option<&str>) -> bool {
+    match (authorization, admin_token) {
+        (Some(authorization), Some(token)) if !token.is_empty() => {
+            match authorization.strip_prefix("Bearer ") {
+                Some(given) => constant_time_eq(given.trim().as_bytes(), token.as_bytes()),
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+// Compare secrets without short-circuiting on the first mismatching byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Decode a percent-encoded path segment, e.g. `S%C3%A3o%20Paulo` into `São Paulo`
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Statements of `/admin/repair-coordinates`, run on the coordinates `{table}`.
+// It used to be keyed by (lat, long) and declared with INT columns, so
+// airports could have several rows (one per rounded position). Each airport
+// keeps its most precise row, coordinates stored as text are turned back into
+// numbers, and a unique index keeps airports from getting another row. All of
+// them can be rerun.
+const REPAIR_COORDINATES: &[&str] = &[
+    "DELETE FROM {table} WHERE rowid NOT IN (
+        SELECT rowid FROM (
+            SELECT rowid, ROW_NUMBER() OVER (
+                PARTITION BY airport
+                ORDER BY typeof(lat) = 'real' OR typeof(long) = 'real' DESC,
+                    COALESCE(visits, 0) DESC,
+                    rowid
+            ) AS n FROM {table}
+        ) WHERE n = 1
+    )",
+    "UPDATE OR IGNORE {table} SET lat = CAST(lat AS REAL) WHERE typeof(lat) = 'text'",
+    "UPDATE OR IGNORE {table} SET long = CAST(long AS REAL) WHERE typeof(long) = 'text'",
+    "CREATE UNIQUE INDEX IF NOT EXISTS {table}_airport_idx ON {table}(airport)",
+];
+
+// Deduplicate the coordinates table, returning its row count before and after
+async fn repair_coordinates(db: &Db) -> anyhow::Result<(i64, i64)> {
+    ensure_schema(db).await?;
+    let (before, after) = repair_coordinates_in(&db.primary, "coordinates").await?;
+    tracing::info!("Repaired coordinates: {before} rows before, {after} after");
+    Ok((before, after))
+}
+
+async fn repair_coordinates_in(
+    db: &Connection<CloudflareSender>,
+    table: &str,
+) -> anyhow::Result<(i64, i64)> {
+    let before = row_count(db, table).await?;
+    for statement in REPAIR_COORDINATES {
+        db.execute(&statement.replace("{table}", table), ()).await?;
+    }
+    let after = row_count(db, table).await?;
+    Ok((before, after))
+}
+
+async fn row_count(db: &Connection<CloudflareSender>, table: &str) -> anyhow::Result<i64> {
+    let mut result = db
+        .query(&format!("SELECT COUNT(*) FROM {table}"), ())
+        .await?;
+    match result.next()? {
+        Some(row) => Ok(row.get::<i64>(0)?),
+        None => Ok(0),
+    }
+}
+
+// Statements of `/admin/recompute`, with the country to limit them to, or
+// NULL for all of them. Every logged city gets its number of visits, then
+// the cities without any logged visit are removed.
+const RECOMPUTE_COUNTER: &[&str] = &[
+    "INSERT INTO counter(country, city, value, updated_at)
+        SELECT country, city, COUNT(*), MAX(ts) FROM visits
+        WHERE ?1 IS NULL OR country = ?1
+        GROUP BY country, city
+    ON CONFLICT(country, city) DO UPDATE SET
+        value = excluded.value,
+        updated_at = excluded.updated_at",
+    "DELETE FROM counter
+    WHERE (?1 IS NULL OR country = ?1) AND NOT EXISTS (
+        SELECT 1 FROM visits WHERE visits.country = counter.country AND visits.city = counter.city
+    )",
+];
+
+// Rebuild the counter (or a single country of it) from the visits table in a
+// single transaction, returning its row count before and after
+async fn recompute_counter(db: &Db, country: Option<&str>) -> anyhow::Result<(i64, i64)> {
+    ensure_schema(db).await?;
+    let country = country.map_or(Value::Null, |c| Value::Text(c.to_string()));
+    let tx = db
+        .primary
+        .transaction(TransactionBehavior::Immediate)
+        .await?;
+    let counts = recompute_in(&tx, &country).await;
+    match counts {
+        Ok(_) => tx.commit().await?,
+        Err(_) => tx.rollback().await?,
+    }
+    let (before, after) = counts?;
+    invalidate_caches();
+    tracing::info!("Recomputed counter: {before} rows before, {after} after");
+    Ok((before, after))
+}
+
+async fn recompute_in(
+    tx: &Transaction<CloudflareSender>,
+    country: &Value,
+) -> anyhow::Result<(i64, i64)> {
+    let before = counter_rows(tx, country).await?;
+    for statement in RECOMPUTE_COUNTER {
+        tx.execute(statement, params![country.clone()]).await?;
+    }
+    let after = counter_rows(tx, country).await?;
+    Ok((before, after))
+}
+
+async fn counter_rows(tx: &Transaction<CloudflareSender>, country: &Value) -> anyhow::Result<i64> {
+    let mut result = tx
+        .query(
+            "SELECT COUNT(*) FROM counter WHERE ?1 IS NULL OR country = ?1",
+            params![country.clone()],
+        )
+        .await?;
+    match result.next()? {
+        Some(row) => Ok(row.get(0)?),
+        None => anyhow::bail!("count query returned no rows"),
+    }
+}
+
+// Tables `/admin/prune` deletes old rows from, by their `ts` column
+const PRUNED_TABLES: &[&str] = &["visits", "request_log"];
+
+// Rows deleted by a single statement of `/admin/prune`, so that none of them
+// holds the database for long
+const PRUNE_BATCH: i64 = 1000;
+
+// Most statements a single `/admin/prune` request runs. Each one is a
+// subrequest, and Workers cap their number per request.
+const PRUNE_MAX_STATEMENTS: usize = 25;
+
+// `versions` row bumped whenever logged visits are pruned. From then on the
+// visits no longer account for every count, so the counter can't be
+// recomputed from them.
+const PRUNED_VISITS: &str = "visits_pruned";
+
+// `versions` row bumped whenever the counter is written other than by counting
+// visits: by `/batch`, CSV imports and the `/counter` DELETE routes. None of
+// them is logged in `visits`, so a recompute would undo them, as after a prune.
+const UNLOGGED_COUNTS: &str = "counter_unlogged";
+
+// Why `/admin/recompute` can't rebuild the counter from the logged visits,
+// if it can't
+async fn recompute_refusal(
+    db: &Connection<CloudflareSender>,
+) -> anyhow::Result<Option<&'static str>> {
+    if table_version(db, PRUNED_VISITS).await?.version > 0 {
+        return Ok(Some(
+            "Logged visits were pruned, so they no longer account for every count",
+        ));
+    }
+    if table_version(db, UNLOGGED_COUNTS).await?.version > 0 {
+        return Ok(Some(
+            "The counter was changed by /batch, an import or a deletion, which aren't logged as visits",
+        ));
+    }
+    Ok(None)
+}
+
+// Delete the rows of PRUNED_TABLES older than `cutoff` (in milliseconds since
+// the Unix epoch), `batch` rows at a time and in at most `max_statements`
+// statements. Returns how many went per table, and whether some may be left.
+async fn prune(
+    db: &Db,
+    cutoff: i64,
+    batch: i64,
+    max_statements: usize,
+    now_ms: i64,
+) -> anyhow::Result<(serde_json::Map<String, serde_json::Value>, bool)> {
+    ensure_schema(db).await?;
+    let mut deleted = serde_json::Map::new();
+    let mut statements = 0;
+    let mut more = false;
+    for table in PRUNED_TABLES {
+        let mut total = 0;
+        loop {
+            if statements == max_statements {
+                more = true;
+                break;
+            }
+            statements += 1;
+            let rows = db
+                .primary
+                .execute(
+                    &format!(
+                        "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE ts < ? LIMIT ?)"
+                    ),
+                    params![cutoff, batch],
+                )
+                .await?;
+            total += rows;
+            if rows < batch as u64 {
+                break;
+            }
+        }
+        if *table == "visits" && total > 0 {
+            bump_table_version(&db.primary, PRUNED_VISITS, now_ms).await?;
+        }
+        tracing::info!("Pruned {total} rows of {table} older than {cutoff}");
+        deleted.insert(table.to_string(), total.into());
+    }
+    Ok((deleted, more))
+}
+
+// Remove every city of a country from the counter, returning how many there were
+async fn delete_country(db: &Db, country: &str, now_ms: i64) -> anyhow::Result<u64> {
+    let deleted = db
+        .primary
+        .execute("DELETE FROM counter WHERE country = ?", params![country])
+        .await?;
+    invalidate_caches();
+    if deleted > 0 {
+        bump_table_version(&db.primary, UNLOGGED_COUNTS, now_ms).await?;
+    }
+    Ok(deleted)
+}
+
+// Remove a single city's row from the counter, returning the number of deleted rows
+async fn delete_city(db: &Db, country: &str, city: &str, now_ms: i64) -> anyhow::Result<u64> {
+    let deleted = db
+        .primary
+        .execute(
+            "DELETE FROM counter WHERE country = ? AND city = ?",
+            params![country, city],
+        )
+        .await?;
+    invalidate_caches();
+    if deleted > 0 {
+        bump_table_version(&db.primary, UNLOGGED_COUNTS, now_ms).await?;
+    }
+    Ok(deleted)
+}
+
+// Names of the secrets holding the database credentials
+#[derive(Debug, PartialEq)]
+struct SecretNames {
+    url: String,
+    token: String,
+    replica_url: String,
+}
+
+impl SecretNames {
+    // With a `LIBSQL_SECRET_PREFIX` the secrets are `{PREFIX}_URL`, `{PREFIX}_TOKEN`
+    // and `{PREFIX}_REPLICA_URL`, which lets several instances share an account.
+    // Without it the default names are used.
+    fn new(prefix: Option<&str>) -> Self {
+        match prefix.map(str::trim).filter(|p| !p.is_empty()) {
+            Some(prefix) => SecretNames {
+                url: format!("{prefix}_URL"),
+                token: format!("{prefix}_TOKEN"),
+                replica_url: format!("{prefix}_REPLICA_URL"),
+            },
+            None => SecretNames {
+                url: "LIBSQL_CLIENT_URL".to_string(),
+                token: "LIBSQL_CLIENT_TOKEN".to_string(),
+                replica_url: "LIBSQL_REPLICA_URL".to_string(),
+            },
+        }
+    }
+}
+
+thread_local! {
+    // Workers run each isolate on a single thread, so this is one connection per isolate
+    static CONNECTION: ConnectionCache<Db> = const { ConnectionCache::new() };
+}
+
+// Get the isolate's shared connection, opening it on first use or after the
+// credentials changed (see ConnectionCache for the tradeoffs)
+fn open_connection(env: &Env) -> anyhow::Result<Rc<Db>> {
+    let prefix = env.var("LIBSQL_SECRET_PREFIX").ok().map(|v| v.to_string());
+    let names = SecretNames::new(prefix.as_deref());
+    let url = env
+        .secret(&names.url)
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .to_string();
+    let token = env
+        .secret(&names.token)
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .to_string();
+    // The replica is optional and shares the primary's auth token
+    let replica_url = env
+        .secret(&names.replica_url)
+        .map(|url| url.to_string())
+        .ok()
+        .filter(|url| !url.is_empty());
+    let key = format!("{url}\n{token}\n{}", replica_url.as_deref().unwrap_or(""));
+    CONNECTION.with(|cache| {
+        cache.get_or_open(&key, || {
+            let db = Db::new(Connection::open_cloudflare_worker(url, token.clone()));
+            Ok(match replica_url {
+                Some(replica_url) => {
+                    db.with_replica(Connection::open_cloudflare_worker(replica_url, token))
+                }
+                None => db,
+            })
+        })
+    })
+}
+
+// Drop the shared connection when a query failed because its credentials
+// were rejected, so that the next request reconnects
+fn note_db_error(message: &str) {
+    if CONNECTION.with(|cache| cache.invalidate_on_auth_error(message)) {
+        tracing::warn!("Database rejected the credentials, reconnecting on next request");
+    }
+}
+
+// ID of a request, to tell its log entries apart. Cloudflare gives every
+// request a unique `CF-Ray` header, so it's only made up when running locally.
+fn request_id(req: &Request) -> String {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    match req.headers().get("CF-Ray").ok().flatten() {
+        Some(ray) if !ray.is_empty() => ray,
+        _ => format!(
+            "local-{:x}-{}",
+            Date::now().as_millis(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ),
+    }
+}
+
+// ERROR_TEMPLATE filled in with the request's ID
+fn error_page(config: &Config, request_id: &str) -> String {
+    config
+        .error_template
+        .replace("{request_id}", &html_escape(request_id))
+}
+
+// HTML response for a page that failed to render. The error itself may
+// mention queries or connection details, so it's only logged, and visitors
+// get the request ID to report instead.
+fn page_error(e: impl std::fmt::Display, request_id: &str, config: &Config) -> Result<Response> {
+    failed_page(e, request_id, config).into_response()
+}
+
+fn failed_page(e: impl std::fmt::Display, request_id: &str, config: &Config) -> PlainResponse {
+    tracing::error!(request_id, "Failed to serve the page: {e}");
+    PlainResponse {
+        status: 500,
+        headers: vec![("Content-Type", HTML_CONTENT_TYPE.to_string())],
+        body: error_page(config, request_id),
+    }
+}
+
+// JSON response for a failed database operation
+fn db_error(e: impl std::fmt::Display) -> Result<Response> {
+    let message = e.to_string();
+    note_db_error(&message);
+    json_error(message, 500)
+}
+
+// Every (method, path, description) registered with the router in `main`.
+// Keep the two in sync: this table backs both `/api` and the 405 responses.
+const ROUTES: &[(&str, &str, &str)] = &[
+    ("GET", "/", "Count the visit and render the scoreboard page"),
+    (
+        "HEAD",
+        "/",
+        "Headers of the page, without counting the visit",
+    ),
+    ("GET", "/api", "List all routes"),
+    (
+        "GET",
+        "/ping",
+        "Liveness probe, never touching the database",
+    ),
+    ("GET", "/favicon.ico", "Icon of the page"),
+    ("GET", "/robots.txt", "Crawling rules"),
+    ("GET", "/sitemap.xml", "Sitemap listing the page"),
+    ("GET", "/stats", "Totals of countries, cities and visits"),
+    ("GET", "/top", "Countries with the most visits, ?n= of them"),
+    (
+        "GET",
+        "/top-cities",
+        "Cities with the most visits, ?n= of them",
+    ),
+    ("GET", "/counter.md", "Scoreboard as a Markdown table"),
+    ("GET", "/continents", "Visits per continent"),
+    ("GET", "/regions", "Visits per region of each country"),
+    ("GET", "/count", "Count of a single ?country= and ?city="),
+    ("GET", "/search", "Cities or countries matching ?q="),
+    (
+        "GET",
+        "/coordinates",
+        "Visited airports with their locations",
+    ),
+    (
+        "GET",
+        "/data",
+        "Counter rows and visited airports, in one response",
+    ),
+    (
+        "GET",
+        "/airports.bin",
+        "Visited airport locations, packed in binary",
+    ),
+    ("GET", "/worker-version", "Version of workers-rs"),
+    (
+        "GET",
+        "/locate",
+        "Location of the caller as seen by Cloudflare",
+    ),
+    (
+        "GET",
+        "/whoami",
+        "Where a visit of the caller would be counted",
+    ),
+    ("GET", "/users", "Example users"),
+    ("GET", "/users.csv", "All example users as CSV"),
+    ("GET", "/add-user", "Add an example user with ?email="),
+    (
+        "GET",
+        "/counter/delta",
+        "Counter rows updated after ?since= (epoch ms)",
+    ),
+    (
+        "GET",
+        "/counter/stream",
+        "Top cities as a server-sent event, ?n= of them",
+    ),
+    (
+        "GET",
+        "/counter/compare",
+        "Totals of two countries, ?a= and ?b=, and which one leads",
+    ),
+    (
+        "GET",
+        "/counter/histogram",
+        "Number of cities per range of counts",
+    ),
+    (
+        "GET",
+        "/counter/:country",
+        "Cities of a country as an HTML table",
+    ),
+    (
+        "GET",
+        "/spark/:country.svg",
+        "Sparkline of a country's daily visits",
+    ),
+    (
+        "GET",
+        "/admin/schema",
+        "Differences between the tables and the expected schema (admin)",
+    ),
+    (
+        "GET",
+        "/admin/columns/:table",
+        "Columns and declared types of a table (admin)",
+    ),
+    (
+        "POST",
+        "/admin/repair-coordinates",
+        "Deduplicate visited airports (admin)",
+    ),
+    (
+        "POST",
+        "/admin/prune",
+        "Delete logged visits and requests older than RETENTION_DAYS (admin)",
+    ),
+    (
+        "POST",
+        "/admin/recompute",
+        "Rebuild the counter from the logged visits (admin)",
+    ),
+    (
+        "POST",
+        "/batch",
+        "Add to the counts of several cities at once (admin)",
+    ),
+    (
+        "POST",
+        "/counter/import.csv",
+        "Restore counter rows from CSV (admin)",
+    ),
+    (
+        "DELETE",
+        "/counter/country/:country",
+        "Remove all cities of a country (admin)",
+    ),
+    (
+        "DELETE",
+        "/counter/:country/:city",
+        "Remove a single city (admin)",
+    ),
+];
+
+fn route_manifest() -> Vec<RouteEntry<'static>> {
+    ROUTES
+        .iter()
+        .map(|&(method, path, description)| RouteEntry {
+            path,
+            method,
+            description,
+        })
+        .collect()
+}
+
+// Match a path against a route pattern, where `:name` segments match any non-empty segment
+fn route_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut path = path.split('/');
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(p), Some(s)) if p.starts_with(':') && !s.is_empty() => {}
+            (Some(p), Some(s)) if p == s => {}
+            _ => return false,
+        }
+    }
+}
+
+// For a known path requested with a method it doesn't support, the value of
+// the `Allow` header listing the methods it does support
+fn disallowed_method(method: &str, path: &str) -> Option<String> {
+    let allowed = allowed_methods(path);
+    if allowed.is_empty() || allowed.contains(&method) {
+        None
+    } else {
+        Some(allowed.join(", "))
+    }
+}
+
+// Methods of the routes matching `path`, none for an unknown path
+fn allowed_methods(path: &str) -> Vec<&'static str> {
+    ROUTES
+        .iter()
+        .filter(|(_, pattern, _)| route_matches(pattern, path))
+        .map(|(method, _, _)| *method)
+        .collect()
+}
+
+// Request headers the endpoints read that browsers don't send cross-origin
+// without asking: the admin token, the body type of POST requests and the
+// conditional headers of `/users`
+const CORS_ALLOW_HEADERS: &str = "Authorization, Content-Type, If-None-Match, If-Modified-Since";
+
+// Headers of the response to a CORS preflight for `path`, or None when CORS
+// is disabled or the path isn't a route. Browsers may cache it for
+// CORS_MAX_AGE seconds rather than asking before every request.
+fn preflight_headers(path: &str, config: &Config) -> Option<Vec<(&'static str, String)>> {
+    let origin = config.cors_origin.as_ref()?;
+    let mut methods = allowed_methods(path);
+    if methods.is_empty() {
+        return None;
+    }
+    methods.push("OPTIONS");
+    Some(vec![
+        ("Access-Control-Allow-Origin", origin.clone()),
+        ("Access-Control-Allow-Methods", methods.join(", ")),
+        (
+            "Access-Control-Allow-Headers",
+            CORS_ALLOW_HEADERS.to_string(),
+        ),
+        ("Access-Control-Max-Age", config.cors_max_age.to_string()),
+        ("Vary", "Origin".to_string()),
+    ])
+}
+
+// Path without its trailing slash, when that's one of the routes, e.g.
+// `/users` for `/users/`. The router treats them as different paths.
+fn trailing_slash_target(path: &str) -> Option<&str> {
+    let stripped = path.strip_suffix('/').filter(|p| !p.is_empty())?;
+    ROUTES
+        .iter()
+        .any(|(_, pattern, _)| route_matches(pattern, stripped))
+        .then_some(stripped)
+}
+
+// Methods a POST request can be handled as with `?_method=`, for HTML forms
+// that can only send GET and POST
+const METHOD_OVERRIDES: &[&str] = &["DELETE"];
+
+// The method a request is handled as with METHOD_OVERRIDE: the one named by
+// `?_method=` (in any case) for POST requests, when it's in METHOD_OVERRIDES
+fn method_override(method: &str, url: &Url) -> Option<&'static str> {
+    if method != "POST" {
+        return None;
+    }
+    let (_, requested) = url.query_pairs().find(|(name, _)| name == "_method")?;
+    METHOD_OVERRIDES
+        .iter()
+        .find(|allowed| allowed.eq_ignore_ascii_case(&requested))
+        .copied()
+}
+
+// The method a request is routed with, after METHOD_OVERRIDE, or the `Allow`
+// header of the 405 response when its path doesn't support that method
+fn routed_method(method: &str, url: &Url, config: &Config) -> std::result::Result<String, String> {
+    let method = config
+        .method_override
+        .then(|| method_override(method, url))
+        .flatten()
+        .unwrap_or(method);
+    match disallowed_method(method, url.path()) {
+        Some(allow) => Err(allow),
+        None => Ok(method.to_string()),
+    }
+}
+
+// The request as if it was sent with `method`. Only its URL and headers are
+// kept: the DELETE handlers read neither a body nor Cloudflare's properties.
+fn with_method(req: &Request, method: &str) -> Result<Request> {
+    let method = match method {
+        "DELETE" => Method::Delete,
+        other => return Err(Error::RustError(format!("unexpected method {other}"))),
+    };
+    let mut init = RequestInit::new();
+    init.with_method(method).with_headers(req.headers().clone());
+    Request::new_with_init(req.url()?.as_str(), &init)
+}
+
+#[event(fetch)]
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
+    utils::set_panic_hook();
+    utils::init_tracing(&env);
+
+    let method = req.method().to_string();
+    let path = req.path();
+    let location = request_location(&req);
+    let started_at = Date::now().as_millis();
+
+    // Errors become a 500 here rather than in the runtime, so that they
+    // carry the response time header too
+    let mut response = match handle(req, env, ctx).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("Error handling {method} {path}: {e}");
+            Response::error("Internal Server Error", 500)?
+        }
+    };
+
+    let duration_ms = elapsed_ms(started_at, Date::now().as_millis());
+    let header = response
+        .headers_mut()
+        .set(RESPONSE_TIME_HEADER, &duration_ms.to_string());
+    if let Err(e) = header {
+        tracing::warn!("Error setting {RESPONSE_TIME_HEADER}: {e}");
+    }
+    log_request(
+        &method,
+        &path,
+        response.status_code(),
+        duration_ms,
+        &location,
+    );
+    Ok(response)
+}
+
+// Set on every response, for debugging without digging into logs
+const RESPONSE_TIME_HEADER: &str = "X-Response-Time-Ms";
+
+// Wall-clock time between two `Date::now()` readings, as `Instant` isn't
+// available in Workers. The clock only moves forward after I/O (Cloudflare's
+// Spectre mitigation), so pure computation shows up as 0.
+fn elapsed_ms(started_at_ms: u64, now_ms: u64) -> u64 {
+    now_ms.saturating_sub(started_at_ms)
+}
+
+async fn handle(mut req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
+    // Before the request log, so that probes never open a connection
+    if let Some((status, body)) = ping(&req.method().to_string(), &req.path()) {
+        return Ok(Response::ok(body)?.with_status(status));
+    }
+
+    let config = Config::from_env(&env);
+
+    let entry = RequestLogEntry::new(&req, Date::now().as_millis() as i64);
+    if let Some(write) = request_log_write(&config, entry, || open_connection(&env)) {
+        // Written after the response is sent, so logging doesn't add latency
+        ctx.wait_until(write);
+    }
+
+    if config.trailing_slash_redirect {
+        if let Some(target) = trailing_slash_target(&req.path()) {
+            let mut url = req.url()?;
+            url.set_path(target);
+            // 308 rather than 301, so that clients repeat the method and body
+            return Response::redirect_with_status(url, 308);
+        }
+    }
+
+    if req.method() == Method::Options {
+        if let Some(headers) = preflight_headers(&req.path(), &config) {
+            let mut response = Response::empty()?.with_status(204);
+            for (name, value) in headers {
+                response.headers_mut().set(name, &value)?;
+            }
+            return Ok(response);
+        }
+    }
+
+    // After the request log, which reads Cloudflare's properties of the
+    // original request, and before routing
+    match routed_method(&req.method().to_string(), &req.url()?, &config) {
+        Ok(method) if method != req.method().to_string() => {
+            req = with_method(&req, &method)?;
+        }
+        Ok(_) => {}
+        Err(allow) => {
+            let mut response = json_error("Method Not Allowed", 405)?;
+            response.headers_mut().set("Allow", &allow)?;
+            return Ok(response);
+        }
+    }
+
+    let cors_origin = config.cors_origin.clone();
+    let router = Router::with_data(config);
+
+    let mut response = router
+        .get_async("/", |req, ctx| async move {
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return page_error(e, &request_id(&req), &ctx.data),
+            };
+            let mut visit = VisitContext::from_request(&req, &ctx.env);
+            // Like the visit cookie, clients asking not to be tracked aren't remembered
+            if !visit.do_not_track {
+                match dedup::is_duplicate(&ctx.env, &ctx.data, visit.client.as_deref()).await {
+                    Ok(duplicate) => visit.duplicate = duplicate,
+                    Err(e) => {
+                        tracing::warn!("Error checking the dedup store, counting the visit: {e}")
+                    }
+                }
+            }
+            let options = PageOptions {
+                language: page_language(
+                    req.headers().get("Accept-Language")?.as_deref(),
+                    &ctx.data,
+                ),
+                ..PageOptions::from_query(&query)
+            };
+            match serve(&visit, &db, &ctx.data, &options).await {
+                Ok(html) => {
+                    let mut response = page_response(html, &ctx.data)?;
+                    if let Some(cookie) = visit_cookie(&visit, &ctx.data) {
+                        response.headers_mut().set("Set-Cookie", &cookie)?;
+                    }
+                    Ok(response)
+                }
+                Err(e) => {
+                    note_db_error(&e.to_string());
+                    page_error(e, &request_id(&req), &ctx.data)
+                }
+            }
+        })
+        .head("/", |_, ctx| head_page(&ctx.data).into_response())
+        .get("/api", |_, _| Response::from_json(&route_manifest()))
+        .get_async("/stats", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            match AggCache::global()
+                .get_or_try_insert_with("stats", now_ms, ttl_ms, || {
+                    stats(db.read(), ctx.data.stats_decimals)
+                })
+                .await
+            {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/top", |req, ctx| async move {
+            if let Err(response) = parse_query(&req, &ctx.data) {
+                return response;
+            }
+            let list = match top_list_params(&req.url()?, &TOP_LIST) {
+                Ok(list) => list,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            let key = format!("top?{}", list.key());
+            match AggCache::global()
+                .get_or_try_insert_with(&key, now_ms, ttl_ms, || top_countries(db.read(), &list))
+                .await
+            {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/top-cities", |req, ctx| async move {
+            if let Err(response) = parse_query(&req, &ctx.data) {
+                return response;
+            }
+            let list = match top_list_params(&req.url()?, &TOP_CITIES_LIST) {
+                Ok(list) => list,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            let key = format!("top-cities?{}", list.key());
+            match AggCache::global()
+                .get_or_try_insert_with(&key, now_ms, ttl_ms, || async {
+                    Ok(serde_json::to_value(top_cities(db.read(), &list).await?)?)
+                })
+                .await
+            {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/counter.md", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let rows = match db.read().query(&scoreboard_query(&ctx.data), ()).await {
+                Ok(rows) => rows,
+                Err(e) => return db_error(e),
+            };
+            let result = match ResultSet::from_rows(rows) {
+                Ok(result) => result,
+                Err(e) => return db_error(e),
+            };
+            let mut response = Response::ok(result.to_markdown(&ctx.data))?;
+            response
+                .headers_mut()
+                .set("Content-Type", MARKDOWN_CONTENT_TYPE)?;
+            Ok(response)
+        })
+        .get_async("/continents", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            match AggCache::global()
+                .get_or_try_insert_with("continents", now_ms, ttl_ms, || {
+                    continent_totals(db.read())
+                })
+                .await
+            {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/regions", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            if let Err(e) = ensure_schema(&db).await {
+                return db_error(e);
+            }
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            match AggCache::global()
+                .get_or_try_insert_with("regions", now_ms, ttl_ms, || async {
+                    Ok(serde_json::to_value(region_totals(db.read()).await?)?)
+                })
+                .await
+            {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/count", |req, ctx| async move {
+            let mut query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let country = query.remove("country").filter(|c| !c.is_empty());
+            let city = query.remove("city").filter(|c| !c.is_empty());
+            let (country, city) = match (country, city) {
+                (Some(country), Some(city)) => (country, city),
+                _ => return json_error("Both country and city are required", 400),
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            match city_count(db.read(), &country, &city).await {
+                Ok(value) => Response::from_json(&CounterEntry {
+                    country,
+                    city: Some(city),
+                    value,
+                }),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/search", |req, ctx| async move {
+            let mut params = match parse_query(&req, &ctx.data) {
+                Ok(params) => params,
+                Err(response) => return response,
+            };
+            let query = params.remove("q").unwrap_or_default();
+            if query.chars().count() < 2 {
+                return json_error("Query must be at least 2 characters long", 400);
+            }
+            let list = match ListParams::from_query(&req.url()?, &SEARCH_LIST) {
+                Ok(list) => list,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            match search_cities(db.read(), &query, &list).await {
+                Ok(entries) => Response::from_json(&entries),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/data", |req, ctx| async move {
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let counter_fields = parse_fields(
+                "columns",
+                query.get("columns").map(String::as_str),
+                COUNTER_FIELDS,
+            );
+            let airport_fields = parse_fields(
+                "airport_columns",
+                query.get("airport_columns").map(String::as_str),
+                AIRPORT_FIELDS,
+            );
+            let (counter_fields, airport_fields) = match (counter_fields, airport_fields) {
+                (Ok(counter), Ok(airports)) => (counter, airports),
+                (Err(message), _) | (_, Err(message)) => return json_error(message, 400),
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            match all_data(db.read(), &counter_fields, &airport_fields).await {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/airports.bin", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let rows = match db.read().query(AIRPORTS_QUERY, ()).await {
+                Ok(rows) => rows,
+                Err(e) => return db_error(e),
+            };
+            let airports = match parse_airports(rows) {
+                Ok(airports) => airports,
+                Err(e) => return db_error(e),
+            };
+            let mut response = Response::from_bytes(encode_airports_bin(&airports))?;
+            response
+                .headers_mut()
+                .set("Content-Type", "application/octet-stream")?;
+            Ok(response)
+        })
+        .get_async("/coordinates", |req, ctx| async move {
+            if let Err(response) = parse_query(&req, &ctx.data) {
+                return response;
+            }
+            let list = match ListParams::from_query(&req.url()?, &COORDINATES_LIST) {
+                Ok(list) => list,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let order_by = list.order_by(&COORDINATES_LIST).unwrap_or("airport");
+            let stmt = format!("{AIRPORTS_QUERY} ORDER BY {order_by} LIMIT ? OFFSET ?");
+            let params = params![i64::from(list.limit), i64::from(list.offset)];
+            let rows = match db.read().query(&stmt, params).await {
+                Ok(rows) => rows,
+                Err(e) => return db_error(e),
+            };
+            match parse_airports(rows) {
+                Ok(airports) => Response::from_json(&airports),
+                Err(e) => db_error(e),
+            }
+        })
+        // Only "/" counts visits, so the browsers' icon requests are never counted
+        .get("/favicon.ico", |_, _| favicon().into_response())
+        // Only "/" counts visits, so crawlers never count by fetching these
+        .get("/robots.txt", |_, ctx| {
+            robots_txt(&ctx.data).into_response()
+        })
+        .get("/sitemap.xml", |req, _| {
+            let origin = req.url()?.origin().ascii_serialization();
+            let mut response = Response::ok(sitemap_xml(&origin))?;
+            response
+                .headers_mut()
+                .set("Content-Type", "application/xml; charset=utf-8")?;
+            Ok(response)
+        })
+        .get("/worker-version", |_, ctx| {
+            let version = ctx.var("WORKERS_RS_VERSION")?.to_string();
+            Response::ok(version)
+        })
+        .get("/locate", |req, ctx| {
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let sep = match locate_separator(query.get("sep").map(String::as_str)) {
+                Ok(sep) => sep,
+                Err(message) => return json_error(message, 400),
+            };
+            let cf = req.cf();
+            let coordinates = cf.coordinates().unwrap_or_default();
+            Response::ok(locate_line(
+                &[
+                    cf.colo(),
+                    cf.country().unwrap_or_default(),
+                    cf.city().unwrap_or_default(),
+                    coordinates.0.to_string(),
+                    coordinates.1.to_string(),
+                ],
+                sep,
+            ))
+        })
+        .get_async("/whoami", |req, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let visit = VisitContext::from_request(&req, &ctx.env);
+            match whoami(&visit, &db, &ctx.data).await {
+                Ok(whoami) => Response::from_json(&whoami),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/users", |req, ctx| async move {
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let accept = req.headers().get("Accept")?;
+            let format = match users_format(query.get("format"), accept.as_deref()) {
+                Ok(format) => format,
+                Err(message) => return json_error(message, 400),
+            };
+            let list = match ListParams::from_query(&req.url()?, &USERS_LIST) {
+                Ok(list) => list,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            if let Err(e) = ensure_schema(&db).await {
+                return db_error(e);
+            }
+            let version = match table_version(db.read(), "users").await {
+                Ok(version) => version,
+                Err(e) => return db_error(e),
+            };
+            let etag = version.etag(&users_variant(format, &list));
+            let last_modified = http_date(version.updated_at);
+            let mut headers = Headers::new();
+            headers.set("ETag", &etag)?;
+            // Without `?format=`, the representation depends on `Accept`
+            headers.set("Vary", "Accept")?;
+            headers.set("Last-Modified", &last_modified)?;
+            let if_none_match = req.headers().get("If-None-Match")?;
+            let if_modified_since = req.headers().get("If-Modified-Since")?;
+            if is_not_modified(
+                if_none_match.as_deref(),
+                if_modified_since.as_deref(),
+                &etag,
+                version.updated_at,
+            ) {
+                return Ok(Response::empty()?.with_status(304).with_headers(headers));
+            }
+            let stmt = match users_query(&ctx.data.users_table, &ctx.data.users_columns) {
+                Ok(stmt) => stmt,
+                Err(e) => return db_error(e),
+            };
+            let stmt = format!("{stmt} LIMIT ? OFFSET ?");
+            let params = params![i64::from(list.limit), i64::from(list.offset)];
+            let rows = match db.read().query(&stmt, params).await {
+                Ok(rows) => rows,
+                Err(e) => return db_error(e),
+            };
+            let result = match ResultSet::from_rows(rows) {
+                Ok(result) => result,
+                Err(e) => return db_error(e),
+            };
+            let response = match format {
+                "csv" => {
+                    headers.set("Content-Type", "text/csv; charset=utf-8")?;
+                    Response::ok(result.to_csv())?
+                }
+                "table" => {
+                    headers.set("Content-Type", HTML_CONTENT_TYPE)?;
+                    Response::ok(result.to_html(&ctx.data))?
+                }
+                _ => {
+                    headers.set("Content-Type", "application/json")?;
+                    Response::from_json(&result.to_json(&ctx.data))?
+                }
+            };
+            Ok(response.with_headers(headers))
+        })
+        // The whole table, streamed row by row rather than paged
+        .get_async("/users.csv", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let stmt = match users_query(&ctx.data.users_table, &ctx.data.users_columns) {
+                Ok(stmt) => stmt,
+                Err(e) => return db_error(e),
+            };
+            let rows = match db.read().query(&stmt, ()).await {
+                Ok(rows) => rows,
+                Err(e) => return db_error(e),
+            };
+            let lines = CsvLines::new(rows).map(|line| match line {
+                Ok(line) => Ok(line.into_bytes()),
+                Err(e) => {
+                    tracing::error!("Error streaming /users.csv: {e}");
+                    Err(Error::RustError(e.to_string()))
+                }
+            });
+            let mut response = Response::from_stream(futures_util::stream::iter(lines))?;
+            response
+                .headers_mut()
+                .set("Content-Type", "text/csv; charset=utf-8")?;
+            Ok(response)
+        })
+        .get_async("/add-user", |req, ctx| async move {
+            let hash_query = match parse_query_once(&req, &ctx.data, &["email"]) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let email = match hash_query.get("email") {
+                Some(string) => string,
+                None => return json_error("No email", 400),
+            };
+            let dry_run = hash_query.get("dry_run").is_some_and(|v| v == "true");
+
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            if let Err(e) = ensure_schema(&db).await {
+                return db_error(e);
+            }
+            match add_user(&db.primary, &ctx.data.users_table, email, dry_run).await {
+                Ok(AddUserOutcome::Inserted) => {}
+                Ok(AddUserOutcome::WouldInsert) => {
+                    return Response::from_json(&json!({ "would_insert": true }))
+                }
+                Ok(AddUserOutcome::Rejected { status, error }) => return json_error(error, status),
+                Err(e) => return db_error(e),
+            }
+            let now_ms = Date::now().as_millis() as i64;
+            match bump_table_version(&db.primary, "users", now_ms).await {
+                Ok(()) => Response::from_json(&serde_json::json!({
+                    "result": "Added"
+                })),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/counter/stream", |req, ctx| async move {
+            if let Err(response) = parse_query(&req, &ctx.data) {
+                return response;
+            }
+            let list = match top_list_params(&req.url()?, &TOP_CITIES_LIST) {
+                Ok(list) => list,
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            // Shared with /top-cities, which returns the same JSON
+            let key = format!("top-cities?{}", list.key());
+            let snapshot = AggCache::global()
+                .get_or_try_insert_with(&key, now_ms, ttl_ms, || async {
+                    Ok(serde_json::to_value(top_cities(db.read(), &list).await?)?)
+                })
+                .await;
+            match snapshot {
+                Ok(json) => {
+                    // Asking for snapshots more often would only get cached ones
+                    let retry_ms = ttl_ms.max(1000);
+                    let mut response = Response::ok(sse_snapshot(&json, retry_ms))?;
+                    let headers = response.headers_mut();
+                    headers.set("Content-Type", "text/event-stream")?;
+                    headers.set("Cache-Control", "no-store")?;
+                    Ok(response)
+                }
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/counter/compare", |req, ctx| async move {
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let (Some(a), Some(b)) = (query.get("a"), query.get("b")) else {
+                return json_error("Both ?a= and ?b= country codes are required", 400);
+            };
+            if let Some(error) = country_code_error(a).or_else(|| country_code_error(b)) {
+                return json_error(error, 400);
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let totals = async {
+                let a = country_total(db.read(), a).await?;
+                let b = country_total(db.read(), b).await?;
+                anyhow::Ok(compare_countries(a, b))
+            };
+            match totals.await {
+                Ok(comparison) => Response::from_json(&comparison),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/counter/histogram", |_, ctx| async move {
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            match AggCache::global()
+                .get_or_try_insert_with("histogram", now_ms, ttl_ms, || {
+                    count_histogram(db.read(), &ctx.data.histogram_bounds)
+                })
+                .await
+            {
+                Ok(json) => Response::from_json(&json),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/counter/:country", |_, ctx| async move {
+            let country = match ctx.param("country").and_then(|c| percent_decode(c)) {
+                Some(country) => country,
+                None => return json_error("Malformed country", 400),
+            };
+            if let Some(error) = country_code_error(&country) {
+                return json_error(error, 400);
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let ttl_ms = ctx.data.agg_cache_sec * 1000;
+            let now_ms = Date::now().as_millis();
+            let fragment = country_pages(&ctx.data)
+                .get_or_try_insert_with(&country, now_ms, ttl_ms, || {
+                    country_fragment(db.read(), &country, &ctx.data)
+                })
+                .await;
+            match fragment {
+                Ok(html) => html_response(html),
+                Err(e) => db_error(e),
+            }
+        })
+        // Route parameters span whole segments, so the extension is part of it
+        .get_async("/spark/:country", |_, ctx| async move {
+            let country = match ctx
+                .param("country")
+                .and_then(|c| c.strip_suffix(".svg"))
+                .and_then(percent_decode)
+            {
+                Some(country) => country,
+                None => return json_error("Not Found", 404),
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            if let Err(e) = ensure_schema(&db).await {
+                return db_error(e);
+            }
+            let today = Date::now().as_millis() as i64 / MS_PER_DAY;
+            match daily_visits(db.read(), &country, today, SPARK_DAYS).await {
+                Ok(buckets) => {
+                    let mut response = Response::ok(sparkline_svg(&buckets))?;
+                    response
+                        .headers_mut()
+                        .set("Content-Type", "image/svg+xml")?;
+                    Ok(response)
+                }
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/counter/delta", |req, ctx| async move {
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let since = match parse_since(query.get("since").map(String::as_str)) {
+                Ok(since) => since,
+                Err(message) => return json_error(message, 400),
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            if let Err(e) = ensure_schema(&db).await {
+                return db_error(e);
+            }
+            match counter_delta(db.read(), since).await {
+                Ok(delta) => Response::from_json(&delta),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/admin/schema", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            match schema_drift(&db.primary).await {
+                Ok(drift) => Response::from_json(&json!({
+                    "ok": drift.is_empty(),
+                    "drift": drift,
+                })),
+                Err(e) => db_error(e),
+            }
+        })
+        .get_async("/admin/columns/:table", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let name = ctx.param("table").map(String::as_str).unwrap_or_default();
+            let Some(table) = inspectable_table(name, &ctx.data) else {
+                return json_error("Table can't be inspected", 403);
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            match table_columns(db.read(), table).await {
+                Ok(columns) if columns.is_empty() => json_error("No such table", 404),
+                Ok(columns) => {
+                    let columns: Vec<_> = columns
+                        .into_iter()
+                        .map(|(name, ty)| json!({ "name": name, "type": ty }))
+                        .collect();
+                    Response::from_json(&json!({ "table": table, "columns": columns }))
+                }
+                Err(e) => db_error(e),
+            }
+        })
+        .post_async("/admin/repair-coordinates", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            match repair_coordinates(&db).await {
+                Ok((before, after)) => Response::from_json(&json!({
+                    "rows_before": before,
+                    "rows_after": after,
+                })),
+                Err(e) => db_error(e),
+            }
+        })
+        .post_async("/admin/prune", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let now_ms = Date::now().as_millis() as i64;
+            let cutoff = now_ms - ctx.data.retention_days.max(0) * MS_PER_DAY;
+            match prune(&db, cutoff, PRUNE_BATCH, PRUNE_MAX_STATEMENTS, now_ms).await {
+                Ok((deleted, more)) => Response::from_json(&json!({
+                    "cutoff": cutoff,
+                    "deleted": deleted,
+                    "more": more,
+                })),
+                Err(e) => db_error(e),
+            }
+        })
+        .post_async("/admin/recompute", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let country = query.get("country").map(String::as_str);
+            if let Some(error) = country.and_then(country_code_error) {
+                return json_error(error, 400);
+            }
+            // Without logged visits, the counter would be emptied
+            if !ctx.data.visit_logging {
+                return json_error("Visits are only logged with VISIT_LOGGING", 409);
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            // Nor would the counts of pruned visits, or those never logged
+            match recompute_refusal(&db.primary).await {
+                Ok(Some(error)) => return json_error(error, 409),
+                Ok(None) => {}
+                Err(e) => return db_error(e),
+            }
+            match recompute_counter(&db, country).await {
+                Ok((before, after)) => Response::from_json(&json!({
+                    "rows_before": before,
+                    "rows_after": after,
+                })),
+                Err(e) => db_error(e),
+            }
+        })
+        .post_async("/batch", |mut req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let atomic = query.get("atomic").map_or(true, |v| v != "false");
+            let body = match read_body_limited(&mut req, ctx.data.max_body_bytes).await {
+                Ok(body) => body,
+                Err(response) => return response,
+            };
+            let ops: Vec<BatchOp> = match serde_json::from_slice(&body) {
+                Ok(ops) => ops,
+                Err(e) => {
+                    return json_error(
+                        format!("Body must be a JSON array of {{country, city, by}}: {e}"),
+                        400,
+                    )
+                }
+            };
+            if ops.len() > MAX_BATCH_OPS {
+                return json_error(
+                    format!("At most {MAX_BATCH_OPS} operations are accepted"),
+                    400,
+                );
+            }
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let now_ms = Date::now().as_millis() as i64;
+            match apply_batch(&db, &ctx.data, &ops, atomic, now_ms).await {
+                Ok(batch) if atomic && batch.applied < ops.len() => {
+                    Ok(Response::from_json(&batch)?.with_status(400))
+                }
+                Ok(batch) => Response::from_json(&batch),
+                Err(e) => db_error(e),
+            }
+        })
+        .post_async("/counter/import.csv", |mut req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let query = match parse_query(&req, &ctx.data) {
+                Ok(query) => query,
+                Err(response) => return response,
+            };
+            let replace = match query.get("mode").map(String::as_str) {
+                None | Some("replace") => true,
+                Some("add") => false,
+                Some(_) => return json_error("mode must be replace or add", 400),
+            };
+            let csv = match read_body_limited(&mut req, ctx.data.max_body_bytes).await {
+                Ok(body) => match String::from_utf8(body) {
+                    Ok(csv) => csv,
+                    Err(_) => return json_error("Body must be UTF-8 text", 400),
+                },
+                Err(response) => return response,
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let now_ms = Date::now().as_millis() as i64;
+            match import_counter_csv(&db, &csv, replace, now_ms).await {
+                Ok(Ok(imported)) => Response::from_json(&json!({ "imported": imported })),
+                Ok(Err(error)) => json_error(error, 400),
+                Err(e) => db_error(e),
+            }
+        })
+        // Static segments take precedence, so this is matched before the
+        // per-city route below
+        .delete_async("/counter/country/:country", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let country = match ctx.param("country").and_then(|c| percent_decode(c)) {
+                Some(country) => country,
+                None => return json_error("Malformed country", 400),
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let now_ms = Date::now().as_millis() as i64;
+            match delete_country(&db, &country, now_ms).await {
+                Ok(0) => Ok(Response::from_json(&json!({ "deleted": 0 }))?.with_status(404)),
+                Ok(deleted) => Response::from_json(&json!({ "deleted": deleted })),
+                Err(e) => db_error(e),
+            }
+        })
+        .delete_async("/counter/:country/:city", |req, ctx| async move {
+            if !is_admin(&req, &ctx.env) {
+                return json_error("Unauthorized", 401);
+            }
+            let country = ctx.param("country").and_then(|c| percent_decode(c));
+            let city = ctx.param("city").and_then(|c| percent_decode(c));
+            let (country, city) = match (country, city) {
+                (Some(country), Some(city)) => (country, city),
+                _ => return json_error("Malformed country or city", 400),
+            };
+            let db = match open_connection(&ctx.env) {
+                Ok(client) => client,
+                Err(e) => return db_error(e),
+            };
+            let now_ms = Date::now().as_millis() as i64;
+            match delete_city(&db, &country, &city, now_ms).await {
+                Ok(0) => Ok(Response::from_json(&json!({ "deleted": 0 }))?.with_status(404)),
+                Ok(deleted) => Response::from_json(&json!({ "deleted": deleted })),
+                Err(e) => db_error(e),
+            }
+        })
+        .run(req, env)
+        .await?;
+    if let Some(origin) = cors_origin {
+        response
+            .headers_mut()
+            .set("Access-Control-Allow-Origin", &origin)?;
+    }
+    Ok(response)
+}
+
+// Version of a table's content, bumped on every change to it
+struct TableVersion {
+    version: i64,
+    // Time of the last change, in milliseconds since the Unix epoch
+    updated_at: i64,
+}
+
+impl TableVersion {
+    fn etag(&self, name: &str) -> String {
+        format!("\"{name}-{}\"", self.version)
+    }
+}
+
+async fn table_version(
+    db: &Connection<CloudflareSender>,
+    name: &str,
+) -> anyhow::Result<TableVersion> {
+    let mut result = db
+        .query(
+            "SELECT version, updated_at FROM versions WHERE name = ?",
+            params![name],
+        )
+        .await?;
+    Ok(match result.next()? {
+        Some(row) => TableVersion {
+            version: row.get(0)?,
+            updated_at: row.get(1)?,
+        },
+        None => TableVersion {
+            version: 0,
+            updated_at: 0,
+        },
+    })
+}
+
+async fn bump_table_version(
+    db: &Connection<CloudflareSender>,
+    name: &str,
+    now_ms: i64,
+) -> anyhow::Result<()> {
+    db.execute(
+        "INSERT INTO versions VALUES (?, 1, ?)
+        ON CONFLICT(name) DO UPDATE SET version = version + 1, updated_at = excluded.updated_at",
+        params![name, now_ms],
+    )
+    .await?;
+    Ok(())
+}
+
+// Whether a conditional request already holds the current representation.
+// If-None-Match takes precedence over If-Modified-Since, as in RFC 9110.
+fn is_not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    last_modified_ms: i64,
+) -> bool {
+    if let Some(tags) = if_none_match {
+        return tags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    match if_modified_since.and_then(parse_http_date) {
+        // HTTP dates only have a precision of seconds
+        Some(since) => last_modified_ms.div_euclid(1000) <= since.div_euclid(1000),
+        None => false,
+    }
+}
+
+// Whether `name` can be safely interpolated into SQL as an identifier,
+// i.e. it matches `^[a-zA-Z_][a-zA-Z0-9_]*$`
+fn is_valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Representation of `/users`: `?format=` when given, otherwise the first of
+// the `Accept` header's types that has one, and JSON when none has
+fn users_format(
+    format: Option<&String>,
+    accept: Option<&str>,
+) -> std::result::Result<&'static str, String> {
+    const FORMATS: [(&str, &str); 3] = [
+        ("json", "application/json"),
+        ("csv", "text/csv"),
+        ("table", "text/html"),
+    ];
+    if let Some(format) = format {
+        return FORMATS
+            .iter()
+            .find(|(name, _)| name == format)
+            .map(|(name, _)| *name)
+            .ok_or_else(|| "format must be one of json, csv or table".to_string());
+    }
+    let accepted = accept
+        .unwrap_or_default()
+        .split(',')
+        .map(|media| media.split(';').next().unwrap_or_default().trim());
+    for media in accepted {
+        if let Some((name, _)) = FORMATS
+            .iter()
+            .find(|(_, ty)| ty.eq_ignore_ascii_case(media))
+        {
+            return Ok(name);
+        }
+    }
+    Ok("json")
+}
+
+// Identifies a `/users` response among the ones of the same table version:
+// its representation and page. Caches and ETags must keep them apart.
+fn users_variant(format: &str, list: &ListParams) -> String {
+    format!("users.{format}?{}", list.key())
+}
+
+// Build the `/users` query from the USERS_TABLE name and a comma-separated
+// column allow-list (or `*`)
+fn users_query(table: &str, columns: &str) -> anyhow::Result<String> {
+    let table = users_table(table)?;
+    let columns = columns.trim();
+    if columns == "*" {
+        return Ok(format!("select * from {table}"));
+    }
+    let columns: Vec<&str> = columns.split(',').map(str::trim).collect();
+    if let Some(invalid) = columns.iter().find(|c| !is_valid_identifier(c)) {
+        anyhow::bail!("invalid column name in USERS_COLUMNS: {invalid:?}");
+    }
+    Ok(format!("select {} from {table}", columns.join(", ")))
+}
+
+// Build the `/add-user` statement for the USERS_TABLE
+fn add_user_query(table: &str) -> anyhow::Result<String> {
+    Ok(format!("insert into {} values (?)", users_table(table)?))
+}
+
+// Longest email address accepted by `/add-user` (RFC 5321's path limit)
+const MAX_EMAIL_LEN: usize = 254;
+
+// Why `/add-user` won't take `email`, if it won't. This only catches obvious
+// mistakes, the address may still not exist.
+fn email_rejection(email: &str) -> Option<String> {
+    if email.len() > MAX_EMAIL_LEN {
+        return Some(format!("email is longer than {MAX_EMAIL_LEN} bytes"));
+    }
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !domain.contains('@')
+                && !email.chars().any(char::is_whitespace) =>
+        {
+            None
+        }
+        _ => Some(format!("{email:?} is not a valid email address")),
+    }
+}
+
+// What `/add-user` did, or would have done with `?dry_run=true`
+#[derive(Debug, PartialEq)]
+enum AddUserOutcome {
+    Inserted,
+    WouldInsert,
+    Rejected { status: u16, error: String },
+}
+
+// Add a user to the USERS_TABLE, once the email looks valid and isn't a user
+// yet (when the table has an `email` column to look it up in). A dry run makes
+// the same checks and only skips the insert.
+async fn add_user(
+    db: &Connection<CloudflareSender>,
+    table: &str,
+    email: &str,
+    dry_run: bool,
+) -> anyhow::Result<AddUserOutcome> {
+    let table = users_table(table)?;
+    if let Some(error) = email_rejection(email) {
+        return Ok(AddUserOutcome::Rejected { status: 400, error });
+    }
+    let columns = table_columns(db, table).await?;
+    if columns
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("email"))
+    {
+        let mut existing = db
+            .query(
+                &format!("select 1 from {table} where email = ? limit 1"),
+                params![email.to_string()],
+            )
+            .await?;
+        if existing.next()?.is_some() {
+            return Ok(AddUserOutcome::Rejected {
+                status: 409,
+                error: format!("{email} is already a user"),
+            });
+        }
+    }
+    if dry_run {
+        return Ok(AddUserOutcome::WouldInsert);
+    }
+    db.execute(&add_user_query(table)?, params![email.to_string()])
+        .await?;
+    Ok(AddUserOutcome::Inserted)
+}
+
+fn users_table(table: &str) -> anyhow::Result<&str> {
+    let table = table.trim();
+    if !is_valid_identifier(table) {
+        anyhow::bail!("invalid table name in USERS_TABLE: {table:?}");
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Db, VisitContext};
+    use crate::config::Config;
+    use libsql::wasm::{CloudflareSender, Connection};
+
+    #[test]
+    fn test_empty_scoreboard_renders_placeholder() {
+        let columns = vec![
+            "country".to_string(),
+            "city".to_string(),
+            "value".to_string(),
+        ];
+        let scoreboard = super::ResultSet {
+            columns,
+            rows: Vec::new(),
+        };
+        let page = super::render_page_within(
+            &Config::default(),
+            &scoreboard,
+            None,
+            None,
+            &Default::default(),
+        );
+        assert!(page.contains(super::NO_VISITS_HTML));
+        assert!(!page.contains("<table"));
+
+        #[cfg(feature = "map")]
+        {
+            // the scoreboard's placeholder is enough, the map adds nothing
+            assert_eq!(super::render_map_canvas(&[], 5), "");
+            let page = super::render_page_within(
+                &Config::default(),
+                &scoreboard,
+                Some(Vec::new()),
+                None,
+                &Default::default(),
+            );
+            assert_eq!(page.matches(super::NO_VISITS_HTML).count(), 1);
+            assert!(!page.contains("p5.min.js"));
+            assert!(!page.contains("<footer>"));
+        }
+    }
+
+    #[cfg(not(feature = "map"))]
+    #[test]
+    fn test_page_without_map() {
+        let page =
+            super::PageContext::new(&Config::default(), super::NO_VISITS_HTML.to_string(), None);
+        let html = super::render_page(&page);
+        assert!(!html.contains("p5.min.js"));
+        assert!(!html.contains("tile.osm.org"));
+        assert!(html.contains(super::NO_VISITS_HTML));
+    }
+
+    #[tokio::test]
+    async fn test_offline_page_has_no_external_urls() {
+        let db = test_db();
+        let config = Config {
+            offline: true,
+            ..Config::default()
+        };
+        let html = super::serve(
+            &visit("t25", "XN", "Offline", (12.0, 12.0)),
+            &db,
+            &config,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert!(html.contains("Offline"));
+        for url in ["cdnjs", "unpkg", "tile.osm.org", "<script"] {
+            assert!(!html.contains(url), "{url} in {html}");
+        }
+        assert_eq!(super::page_csp(&config), Some(super::OFFLINE_CSP));
+        assert!(super::OFFLINE_CSP.starts_with("default-src 'none'"));
+        assert_eq!(super::page_csp(&Config::default()), None);
+    }
+
+    #[test]
+    fn test_page_declares_utf8() {
+        assert_eq!(super::HTML_CONTENT_TYPE, "text/html; charset=utf-8");
+        let page =
+            super::PageContext::new(&Config::default(), "<p>São Paulo</p>".to_string(), None);
+        let html = super::render_page(&page);
+        assert!(html.contains(r#"<meta charset="utf-8">"#));
+        assert!(html.contains("São Paulo"));
+    }
+
+    #[test]
+    fn test_page_context_fields_rendered() {
+        let page = super::PageContext {
+            title: "Title &amp; more".to_string(),
+            scoreboard_html: "<table id=\"scores\"></table>".to_string(),
+            map_html: Some("<div id=\"map\"></div>".to_string()),
+            branding_html: "<em>Brand</em>".to_string(),
+            theme: super::Theme::Light,
+            last_updated: Some(super::fmt_utc(1_709_210_096_789)),
+        };
+        let html = super::render_page(&page);
+        assert!(html.contains("<title>Title &amp; more</title>"));
+        assert!(html.contains("<p>Last updated: 2024-02-29 12:34 UTC</p>"));
+        assert!(html.contains(&page.scoreboard_html));
+        assert!(html.contains("<div id=\"map\"></div>"));
+        assert!(html.contains(&page.branding_html));
+        assert!(html.contains("tile.osm.org"));
+
+        let page = super::PageContext {
+            map_html: None,
+            ..page
+        };
+        assert!(!super::render_page(&page).contains("tile.osm.org"));
+        let page = super::PageContext {
+            last_updated: None,
+            ..page
+        };
+        assert!(!super::render_page(&page).contains("Last updated"));
+    }
+
+    #[test]
+    fn test_page_language() {
+        use super::{page_language, render_page, PageContext};
+        use crate::config::LocalizedPage;
+
+        let config = Config {
+            page_i18n: [(
+                "fr".to_string(),
+                LocalizedPage {
+                    title: Some("Compteur de pays".to_string()),
+                    branding_html: None,
+                },
+            )]
+            .into(),
+            ..Config::default()
+        };
+        assert_eq!(page_language(Some("fr"), &config).as_deref(), Some("fr"));
+        assert_eq!(
+            page_language(Some("de-CH, fr-FR;q=0.8, en;q=0.5"), &config).as_deref(),
+            Some("fr")
+        );
+        assert_eq!(page_language(Some("en, fr;q=0"), &config), None);
+        assert_eq!(page_language(Some("de"), &config), None);
+        assert_eq!(page_language(None, &config), None);
+
+        let scoreboard = "<td>Zürich</td>".to_string();
+        let html = render_page(
+            &PageContext::new(&config, scoreboard.clone(), None).localize(&config, Some("fr")),
+        );
+        assert!(html.contains("<title>Compteur de pays</title>"), "{html}");
+        // untranslated strings and data stay as they are
+        assert!(html.contains(&config.branding_html));
+        assert!(html.contains(&scoreboard));
+        let html =
+            render_page(&PageContext::new(&config, scoreboard, None).localize(&config, None));
+        assert!(html.contains("<title>Country counter</title>"));
+    }
+
+    #[test]
+    fn test_theme() {
+        use super::{render_page, PageContext, PageOptions, Theme};
+        use std::collections::HashMap;
+
+        let query = HashMap::from([("theme".to_string(), "dark".to_string())]);
+        let options = PageOptions::from_query(&query);
+        assert_eq!(options.theme, Some(Theme::Dark));
+        let query = HashMap::from([("theme".to_string(), "neon".to_string())]);
+        assert_eq!(PageOptions::from_query(&query).theme, None);
+
+        let page = PageContext {
+            theme: Theme::Dark,
+            ..PageContext::new(&Config::default(), String::new(), None)
+        };
+        let html = render_page(&page);
+        assert!(html.contains("--page-bg: #121212"), "{html}");
+        assert!(html.contains("--table-bg: #1e1e1e"));
+
+        // light unless configured otherwise
+        let html = render_page(&PageContext::new(&Config::default(), String::new(), None));
+        assert!(html.contains("--page-bg: #ffffff"));
+        let config = Config {
+            theme_default: Theme::Dark,
+            ..Config::default()
+        };
+        let html = render_page(&PageContext::new(&config, String::new(), None));
+        assert!(html.contains("--page-bg: #121212"));
+    }
+
+    #[test]
+    fn test_custom_page_title() {
+        let config = Config {
+            page_title: "Visits <live>".to_string(),
+            branding_html: "<em>Acme</em>".to_string(),
+            ..Config::default()
+        };
+        let html = super::render_page(&super::PageContext::new(&config, String::new(), None));
+        assert!(html.contains("<title>Visits &lt;live&gt;</title>"));
+        assert!(html.contains("<em>Acme</em>"));
+        assert!(!html.contains("Turso"));
+    }
+
+    #[test]
+    fn test_users_query_columns() {
+        assert_eq!(
+            super::users_query("example_users", "*").unwrap(),
+            "select * from example_users"
+        );
+        assert_eq!(
+            super::users_query("example_users", "email, name").unwrap(),
+            "select email, name from example_users"
+        );
+        assert!(super::users_query("example_users", "email, password; drop table x").is_err());
+        assert!(super::users_query("example_users", "1email").is_err());
+        assert!(super::users_query("example_users", "email,").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_users_columns_json() {
+        let db = test_db();
+        let table = "test_users_columns";
+        for statement in [
+            format!("CREATE TABLE IF NOT EXISTS {table}(email TEXT, name TEXT, password TEXT)"),
+            format!("DELETE FROM {table}"),
+            format!("INSERT INTO {table} VALUES ('a@b.c', 'Ann', 'hunter2')"),
+        ] {
+            db.primary.execute(&statement, ()).await.unwrap();
+        }
+
+        let query = super::users_query(table, "email, name").unwrap();
+        let rows = db.read().query(&query, ()).await.unwrap();
+        let json = super::ResultSet::from_rows(rows)
+            .unwrap()
+            .to_json(&Config::default());
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "columns": ["email", "name"],
+                "rows": [["a@b.c", "Ann"]],
+            })
+        );
+        assert!(!json.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_users_table_configured() {
+        assert_eq!(
+            super::users_query("customers", "email").unwrap(),
+            "select email from customers"
+        );
+        assert_eq!(
+            super::add_user_query(" customers ").unwrap(),
+            "insert into customers values (?)"
+        );
+        assert!(super::users_query("users; drop table counter", "*").is_err());
+        assert!(super::add_user_query("main.users").is_err());
+    }
+
+    #[test]
+    fn test_large_table_is_well_formed() {
+        use libsql::Value;
+        let columns = vec![
+            "country".to_string(),
+            "city".to_string(),
+            "value".to_string(),
+        ];
+        let rows: Vec<Vec<Value>> = (0..5000)
+            .map(|i| {
+                vec![
+                    Value::Text("PL".into()),
+                    Value::Text(format!("City {i}")),
+                    Value::Integer(i),
+                ]
+            })
+            .collect();
+        let html = super::render_html_table(&columns, &rows, &Config::default());
+
+        assert!(html.starts_with("<table"));
+        assert!(html.ends_with("</table>"));
+        for tag in ["table", "th", "tr", "td"] {
+            let opened = html.matches(&format!("<{tag}>")).count()
+                + html.matches(&format!("<{tag} ")).count();
+            let closed = html.matches(&format!("</{tag}>")).count();
+            assert_eq!(opened, closed, "unbalanced <{tag}>");
+        }
+        assert_eq!(html.matches("<tr ").count(), 5000);
+        assert_eq!(html.matches("<td>").count(), 15000);
+    }
+
+    #[test]
+    fn test_truncate_cell() {
+        use super::truncate_cell;
+        assert_eq!(truncate_cell("Warsaw", 10), "Warsaw");
+        assert_eq!(truncate_cell("Helsinki", 8), "Helsinki");
+        assert_eq!(truncate_cell("Helsinki", 4), "Hels…");
+        // multibyte characters are cut on char boundaries
+        assert_eq!(truncate_cell("São Paulo", 2), "Sã…");
+        assert_eq!(truncate_cell("Zürich", 6), "Zürich");
+        assert_eq!(truncate_cell("東京都", 1), "東…");
+    }
+
+    #[test]
+    fn test_long_cell_has_full_title() {
+        use libsql::Value;
+        let config = Config {
+            html_cell_max_chars: 5,
+            ..Config::default()
+        };
+        let columns = vec!["city".to_string()];
+        let rows = vec![vec![Value::Text("Llanfair<pwll>".into())]];
+        let html = super::render_html_table(&columns, &rows, &config);
+        assert!(html.contains(r#"<td title="Llanfair&lt;pwll&gt;">Llanf…</td>"#));
+    }
+
+    #[test]
+    fn test_weight_value() {
+        use libsql::Value;
+        assert!(matches!(super::weight_value(1.0), Value::Integer(1)));
+        assert!(matches!(super::weight_value(3.0), Value::Integer(3)));
+        assert!(matches!(super::weight_value(0.5), Value::Real(w) if w == 0.5));
+    }
+
+    #[cfg(feature = "map")]
+    #[test]
+    fn test_thin_keeps_busiest_airport_per_cell() {
+        use crate::models::AirportEntry;
+        let airport = |name: &str, lat: f64, lon: f64, visits: i64| AirportEntry {
+            airport: name.to_string(),
+            lat,
+            lon,
+            timezone: None,
+            visits,
+            approximate: false,
+        };
+        let airports = vec![
+            airport("ams", 52.31, 4.76, 3),
+            airport("hel", 60.32, 24.95, 1),
+            airport("rtm", 51.96, 4.44, 8),
+        ];
+        let thinned = super::thin_airports(airports, 3.0);
+        let names: Vec<_> = thinned.iter().map(|a| a.airport.as_str()).collect();
+        assert_eq!(names, vec!["rtm", "hel"]);
+    }
+
+    #[test]
+    fn test_secret_names() {
+        use super::SecretNames;
+        let names = SecretNames::new(None);
+        assert_eq!(names.url, "LIBSQL_CLIENT_URL");
+        assert_eq!(names.token, "LIBSQL_CLIENT_TOKEN");
+        assert_eq!(names.replica_url, "LIBSQL_REPLICA_URL");
+        assert_eq!(SecretNames::new(Some("")), names);
+
+        let names = SecretNames::new(Some("STAGING_DB"));
+        assert_eq!(names.url, "STAGING_DB_URL");
+        assert_eq!(names.token, "STAGING_DB_TOKEN");
+        assert_eq!(names.replica_url, "STAGING_DB_REPLICA_URL");
+    }
+
+    #[cfg(feature = "map")]
+    #[test]
+    fn test_null_coordinates_are_skipped() {
+        use libsql::Value;
+        let rows = vec![
+            vec![
+                Value::Text("waw".into()),
+                Value::Real(52.1672),
+                Value::Real(20.9679),
+                Value::Null,
+                Value::Integer(3),
+            ],
+            vec![
+                Value::Text("hel".into()),
+                Value::Null,
+                Value::Real(24.9497),
+                Value::Null,
+                Value::Integer(1),
+            ],
+        ];
+        let airports = super::airports_from_values(rows);
+        assert_eq!(airports.len(), 1);
+        assert_eq!(airports[0].airport, "waw");
+        assert_eq!(airports[0].visits, 3);
+
+        let html = super::render_map_canvas(&airports, 5);
+        assert!(html.contains("waw"));
+        assert!(!html.contains("hel"));
+    }
+
+    #[test]
+    fn test_weighted_airport_visits() {
+        use libsql::Value;
+        let rows = vec![vec![
+            Value::Text("waw".into()),
+            Value::Real(52.1672),
+            Value::Real(20.9679),
+            Value::Null,
+            Value::Real(2.5),
+        ]];
+        let airports = super::airports_from_values(rows);
+        assert_eq!(airports[0].visits, 3);
+    }
+
+    #[test]
+    fn test_like_pattern_escapes_wildcards() {
+        use super::like_pattern;
+        assert_eq!(like_pattern("ars"), "%ars%");
+        assert_eq!(like_pattern("50%"), "%50\\%%");
+        assert_eq!(like_pattern("a_b"), "%a\\_b%");
+        assert_eq!(like_pattern("a\\b"), "%a\\\\b%");
+    }
+
+    #[test]
+    fn test_benign_schema_errors() {
+        use super::is_benign_schema_error;
+        assert!(is_benign_schema_error("table counter already exists"));
+        assert!(is_benign_schema_error("duplicate column name: timezone"));
+        assert!(!is_benign_schema_error("near \"TABLEE\": syntax error"));
+        assert!(!is_benign_schema_error("no such table: coordinates"));
+    }
+
+    #[cfg(feature = "map")]
+    #[test]
+    fn test_map_coordinates_are_rounded() {
+        use super::round_coord;
+        assert_eq!(round_coord(52.167212345, 5), "52.16721");
+        assert_eq!(round_coord(-20.96796, 2), "-20.97");
+        assert_eq!(round_coord(24.5, 5), "24.5");
+        assert_eq!(round_coord(60.0, 3), "60");
+        assert_eq!(round_coord(60.31834, 0), "60");
+
+        use crate::models::AirportEntry;
+        let airports = [AirportEntry {
+            airport: "waw".to_string(),
+            lat: 52.167212345,
+            lon: 20.967912345,
+            timezone: None,
+            visits: 1,
+            approximate: false,
+        }];
+        let html = super::render_map_canvas(&airports, 3);
+        assert!(html.contains("myMap.latLngToPixel(52.167, 20.968)"));
+    }
+
+    #[cfg(feature = "map")]
+    #[test]
+    fn test_approximate_airports_drawn_hollow() {
+        use crate::models::AirportEntry;
+        let airport = |approximate| AirportEntry {
+            airport: "waw".to_string(),
+            lat: 52.2297,
+            lon: 21.0122,
+            timezone: None,
+            visits: 1,
+            approximate,
+        };
+        assert!(!super::render_map_canvas(&[airport(false)], 4).contains("noFill()"));
+        assert!(super::render_map_canvas(&[airport(true)], 4).contains("noFill()"));
+    }
+
+    #[cfg(feature = "map")]
+    #[test]
+    fn test_leaflet_map() {
+        use crate::config::MapEngine;
+        use crate::models::AirportEntry;
+        let airport = |name: &str, lat, lon, approximate| AirportEntry {
+            airport: name.to_string(),
+            lat,
+            lon,
+            timezone: None,
+            visits: 1,
+            approximate,
+        };
+        let airports = [
+            airport("waw", 52.1672, 20.9679, false),
+            airport("hel", 60.3183, 24.9497, false),
+            airport("</script>", 1.0, 2.0, true),
+        ];
+        let config = Config {
+            map_engine: MapEngine::Leaflet,
+            ..Config::default()
+        };
+        let html = super::render_map(&airports, &config);
+        assert!(html.contains("leaflet.js"));
+        assert!(!html.contains("p5") && !html.contains("mappa"));
+        assert!(html.contains(
+            "L.circleMarker([52.1672, 20.9679], point).bindTooltip(\"waw\").addTo(map);"
+        ));
+        assert!(html.contains("L.circleMarker([60.3183, 24.9497], point)"));
+        assert!(html.contains("L.circleMarker([1, 2], approximate).bindTooltip(\"<\\/script>\")"));
+        assert_eq!(html.matches("</script>").count(), 2);
+
+        assert!(super::render_map(&airports, &Config::default()).contains("p5.min.js"));
+        assert_eq!(super::render_map(&[], &config), "");
+        assert_eq!("leaflet".parse(), Ok(MapEngine::Leaflet));
+        assert!("d3".parse::<MapEngine>().is_err());
+    }
+
+    #[test]
+    fn test_blob_cell_modes() {
+        use crate::config::HtmlBlobMode;
+        use libsql::Value;
+        let columns = vec!["data".to_string()];
+        let rows = vec![vec![Value::Blob(vec![1, 2, 3, 4])]];
+
+        let html = super::render_html_table(&columns, &rows, &Config::default());
+        assert!(html.contains("<td>&lt;blob 4 bytes&gt;</td>"));
+
+        let config = Config {
+            html_blob_mode: HtmlBlobMode::Base64,
+            ..Config::default()
+        };
+        let html = super::render_html_table(&columns, &rows, &config);
+        assert!(html.contains("<td>AQIDBA</td>"));
+    }
+
+    #[test]
+    fn test_result_set_renderings_agree() {
+        use super::ResultSet;
+        use libsql::Value;
+
+        let result = ResultSet {
+            columns: vec!["email".to_string(), "note".to_string()],
+            rows: vec![
+                vec![Value::Text("a@b.c".into()), Value::Null],
+                vec![Value::Text("d@e.f".into()), Value::Text(r#"x, "y""#.into())],
+            ],
+        };
+
+        let html = result.to_html(&Config::default());
+        assert_eq!(html.matches("<tr").count(), 2);
+        assert!(html.contains(r#"<th style="border: 1px solid">email</th>"#));
+        assert!(html.contains(r#"<th style="border: 1px solid">note</th>"#));
+
+        let json = result.to_json(&Config::default());
+        assert_eq!(json["columns"], serde_json::json!(["email", "note"]));
+        assert_eq!(json["rows"].as_array().unwrap().len(), 2);
+
+        // an empty /users table (or a page past its end) keeps its headers,
+        // without the scoreboard's placeholder
+        let empty = ResultSet {
+            columns: result.columns.clone(),
+            rows: Vec::new(),
+        };
+        let html = empty.to_html(&Config::default());
+        assert_eq!(
+            html,
+            r#"<table style="border: 1px solid"><th style="border: 1px solid">email</th><th style="border: 1px solid">note</th></table>"#
+        );
+        assert!(!html.contains(super::NO_VISITS_HTML));
+        assert_eq!(json["rows"][0][1], serde_json::Value::Null);
+
+        let csv = result.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "email,note");
+        assert_eq!(lines[1], "a@b.c,");
+        assert_eq!(lines[2], r#"d@e.f,"x, ""y""""#);
+    }
+
+    #[test]
+    fn test_page_size_budget() {
+        use super::{render_page_within, AirportEntry, ResultSet, TRUNCATED_HTML};
+        use libsql::Value;
+
+        let scoreboard = ResultSet {
+            columns: vec!["country".into(), "city".into(), "value".into()],
+            rows: (0..200)
+                .map(|i| {
+                    vec![
+                        Value::Text("XB".into()),
+                        Value::Text(format!("City number {i} with a rather long name")),
+                        Value::Integer(i),
+                    ]
+                })
+                .collect(),
+        };
+        let airports: Vec<AirportEntry> = (0..200)
+            .map(|i| AirportEntry {
+                airport: format!("b{i}"),
+                lat: i as f64 / 10.0,
+                lon: i as f64 / 10.0,
+                timezone: None,
+                visits: i,
+                approximate: false,
+            })
+            .collect();
+        let options = Default::default();
+
+        let page = render_page_within(
+            &Config::default(),
+            &scoreboard,
+            Some(airports.clone()),
+            None,
+            &options,
+        );
+        assert!(!page.contains(TRUNCATED_HTML));
+
+        let config = Config {
+            max_page_bytes: 4000,
+            ..Config::default()
+        };
+        let page = render_page_within(&config, &scoreboard, Some(airports.clone()), None, &options);
+        assert!(page.len() <= 4000, "{} bytes", page.len());
+        assert!(page.contains(TRUNCATED_HTML));
+        // the most visited rows are the ones kept
+        assert!(page.contains("City number 199 "));
+        assert!(!page.contains("City number 0 "));
+
+        // a budget no page fits in still keeps one row, not the placeholder
+        let config = Config {
+            max_page_bytes: 1,
+            ..Config::default()
+        };
+        let page = render_page_within(&config, &scoreboard, Some(airports), None, &options);
+        assert!(page.contains(TRUNCATED_HTML));
+        assert!(!page.contains(super::NO_VISITS_HTML));
+        assert!(page.contains("City number 199 "));
+        assert!(!page.contains("City number 198 "));
+    }
+
+    #[test]
+    fn test_json_bigint() {
+        use super::{json_value, MAX_SAFE_INTEGER};
+        use crate::config::JsonBigInt;
+        use libsql::Value;
+        use serde_json::json;
+
+        let big = MAX_SAFE_INTEGER + 2;
+        assert_eq!(
+            json_value(&Value::Integer(big), JsonBigInt::Number),
+            json!(big)
+        );
+        assert_eq!(
+            json_value(&Value::Integer(big), JsonBigInt::String),
+            json!("9007199254740993")
+        );
+        assert_eq!(
+            json_value(&Value::Integer(-big), JsonBigInt::String),
+            json!("-9007199254740993")
+        );
+        assert_eq!(
+            json_value(&Value::Integer(i64::MIN), JsonBigInt::String),
+            json!(i64::MIN.to_string())
+        );
+        // safe integers stay numbers
+        let safe = Value::Integer(MAX_SAFE_INTEGER);
+        assert_eq!(
+            json_value(&safe, JsonBigInt::String),
+            json!(MAX_SAFE_INTEGER)
+        );
+        assert_eq!(
+            json_value(&Value::Integer(42), JsonBigInt::String),
+            json!(42)
+        );
+    }
+
+    #[test]
+    fn test_response_time() {
+        assert_eq!(super::elapsed_ms(1_000, 1_042), 42);
+        // a clock going backwards never yields a bogus huge duration
+        assert_eq!(super::elapsed_ms(1_042, 1_000), 0);
+        let header = super::elapsed_ms(1_000, 1_250).to_string();
+        assert_eq!(header.parse::<u64>().unwrap(), 250);
+    }
+
+    #[test]
+    fn test_malformed_query_does_not_panic() {
+        use super::collect_query;
+        use worker::Url;
+
+        // Invalid percent-encoding and UTF-8 are decoded lossily, never rejected
+        let url = Url::parse("https://example.com/add-user?email=%ZZ%ff&%=1").unwrap();
+        let query = collect_query(&url, 2048, 32, &[]).unwrap();
+        assert_eq!(query["email"], "%ZZ\u{FFFD}");
+        assert_eq!(query["%"], "1");
+    }
+
+    #[test]
+    fn test_counter_value_coerced() {
+        use super::parse_counter_value;
+        use libsql::Value;
+
+        assert_eq!(parse_counter_value(Value::Integer(7)), 7);
+        assert_eq!(parse_counter_value(Value::Text("3".into())), 3);
+        assert_eq!(parse_counter_value(Value::Text(" 12 ".into())), 12);
+        assert_eq!(parse_counter_value(Value::Text("2.5e1".into())), 25);
+        assert_eq!(parse_counter_value(Value::Real(2.0)), 2);
+        assert_eq!(parse_counter_value(Value::Text("many".into())), 0);
+        assert_eq!(parse_counter_value(Value::Null), 0);
+        assert_eq!(parse_counter_value(Value::Blob(vec![1])), 0);
+    }
+
+    #[test]
+    fn test_stats_averages() {
+        // 3 countries with 7 cities and 20 visits between them
+        let stats = super::stats_response(3, 7, 20, 2);
+        assert_eq!(stats.avg_visits_per_city, 2.86);
+        assert_eq!(stats.avg_visits_per_country, 6.67);
+        let stats = super::stats_response(3, 7, 20, 0);
+        assert_eq!(stats.avg_visits_per_city, 3.0);
+        assert_eq!(stats.avg_visits_per_country, 7.0);
+        assert_eq!(
+            super::stats_response(3, 7, 20, 4).avg_visits_per_city,
+            2.8571
+        );
+        assert_eq!(
+            serde_json::to_value(super::stats_response(1, 2, 3, 1)).unwrap(),
+            serde_json::json!({
+                "countries": 1,
+                "cities": 2,
+                "visits": 3,
+                "avg_visits_per_city": 1.5,
+                "avg_visits_per_country": 3.0
+            })
+        );
+
+        let empty = super::stats_response(0, 0, 0, 2);
+        assert_eq!(empty.avg_visits_per_city, 0.0);
+        assert_eq!(empty.avg_visits_per_country, 0.0);
+    }
+
+    #[test]
+    fn test_sse_snapshot() {
+        let cities = serde_json::json!([{"country": "PL", "city": "Warsaw", "value": 3}]);
+        let frame = super::sse_snapshot(&cities, 10_000);
+        assert!(frame.starts_with("data: "));
+        assert!(frame.ends_with("\n\n"));
+        let mut lines = frame.lines();
+        let data = lines.next().unwrap().strip_prefix("data: ").unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(data).unwrap(),
+            cities
+        );
+        assert_eq!(lines.next(), Some("retry: 10000"));
+        assert_eq!(lines.next(), Some(""));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let counts = vec![
+            (0, 3),
+            (1, 4),
+            (2, 1),
+            (5, 2),
+            (7, 1),
+            (50, 1),
+            (51, 2),
+            (900, 1),
+        ];
+        assert_eq!(
+            super::histogram(counts, &[1, 5, 10, 50]),
+            serde_json::json!({"1": 4, "2-5": 3, "6-10": 1, "11-50": 1, "51+": 3})
+        );
+        assert_eq!(
+            super::histogram(Vec::new(), &[2]),
+            serde_json::json!({"1-2": 0, "3+": 0})
+        );
+        assert_eq!(
+            super::histogram(vec![(3, 2)], &[]),
+            serde_json::json!({"1+": 2})
+        );
+    }
+
+    #[test]
+    fn test_sum_by_continent() {
+        let countries = vec![
+            ("PL".to_string(), 3),
+            ("DE".to_string(), 2),
+            ("JP".to_string(), 4),
+            ("XX".to_string(), 1),
+        ];
+        let totals: Vec<(String, i64)> = super::sum_by_continent(countries)
+            .into_iter()
+            .map(|e| (e.continent, e.value))
+            .collect();
+        assert_eq!(
+            totals,
+            vec![
+                ("Europe".to_string(), 5),
+                ("Asia".to_string(), 4),
+                ("Unknown".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_head_page_allowed() {
+        use super::disallowed_method;
+        assert_eq!(disallowed_method("HEAD", "/"), None);
+        assert_eq!(
+            disallowed_method("POST", "/"),
+            Some("GET, HEAD".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_head_page() {
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'QP'",
+            "DELETE FROM coordinates WHERE airport = 't43'",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let config = Config {
+                offline: true,
+                ..Config::default()
+            };
+            let html = super::serve(
+                &visit("t43", "QP", "Headless", (5.5, 5.5)),
+                &db,
+                &config,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+
+            let head = super::head_page(&config);
+            assert_eq!(head.status, 200);
+            assert!(head.body.is_empty());
+            // the same headers as the page itself
+            assert_eq!(head.headers, super::page(html, &config).headers);
+            assert!(head.header("Content-Security-Policy").is_some());
+            assert_eq!(
+                super::city_count(db.read(), "QP", "Headless")
+                    .await
+                    .unwrap(),
+                1
+            );
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_robots_txt() {
+        let robots = Config::default().robots_txt;
+        assert!(robots.starts_with("User-agent: *\n"));
+        for path in ["/users", "/add-user", "/admin/", "/counter/"] {
+            assert!(robots.contains(&format!("Disallow: {path}\n")), "{path}");
+        }
+        assert!(!robots.contains("Disallow: /\n"));
+        assert_eq!(super::disallowed_method("GET", "/robots.txt"), None);
+
+        let config = Config {
+            robots_txt: "User-agent: *\nDisallow: /\n".to_string(),
+            ..Config::default()
+        };
+        let response = super::robots_txt(&config);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, config.robots_txt);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let sitemap = super::sitemap_xml("https://example.com");
+        assert!(sitemap.contains("<loc>https://example.com/</loc>"));
+    }
+
+    #[test]
+    fn test_airports_bin_round_trip() {
+        use crate::models::AirportEntry;
+
+        fn decode(bin: &[u8]) -> Vec<(String, f32, f32)> {
+            let count = u32::from_le_bytes(bin[..4].try_into().unwrap());
+            let mut pos = 4;
+            let mut airports = Vec::new();
+            for _ in 0..count {
+                let len = u16::from_le_bytes(bin[pos..pos + 2].try_into().unwrap()) as usize;
+                pos += 2;
+                let name = String::from_utf8(bin[pos..pos + len].to_vec()).unwrap();
+                pos += len;
+                let lat = f32::from_le_bytes(bin[pos..pos + 4].try_into().unwrap());
+                let lon = f32::from_le_bytes(bin[pos + 4..pos + 8].try_into().unwrap());
+                pos += 8;
+                airports.push((name, lat, lon));
+            }
+            assert_eq!(pos, bin.len());
+            airports
+        }
+
+        let airports = vec![
+            AirportEntry {
+                airport: "WAW".to_string(),
+                lat: 52.1657,
+                lon: 20.9671,
+                timezone: None,
+                visits: 3,
+                approximate: false,
+            },
+            AirportEntry {
+                airport: "GRU".to_string(),
+                lat: -23.4356,
+                lon: -46.4731,
+                timezone: Some("America/Sao_Paulo".to_string()),
+                visits: 1,
+                approximate: false,
+            },
+        ];
+        let bin = super::encode_airports_bin(&airports);
+        assert_eq!(bin.len(), 4 + 2 * (2 + 3 + 8));
+        let expected: Vec<(String, f32, f32)> = airports
+            .iter()
+            .map(|a| (a.airport.clone(), a.lat as f32, a.lon as f32))
+            .collect();
+        assert_eq!(decode(&bin), expected);
+        assert_eq!(super::encode_airports_bin(&[]), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_error_page() {
+        use super::error_page;
+
+        let html = error_page(&Config::default(), "8a1b2c3d4e5f6a7b-WAW");
+        assert!(html.contains("8a1b2c3d4e5f6a7b-WAW"), "{html}");
+        assert!(!html.contains("{request_id}"));
+
+        let config = Config {
+            error_template: "<p>Oops ({request_id})</p>".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(error_page(&config, "<id>"), "<p>Oops (&lt;id&gt;)</p>");
+
+        let error = "no such table: counter (libsql://db.example.com)";
+        let page = super::failed_page(error, "8a1b2c3d4e5f6a7b-WAW", &config);
+        assert_eq!(page.status, 500);
+        assert_eq!(page.body, "<p>Oops (8a1b2c3d4e5f6a7b-WAW)</p>");
+        assert!(!page.body.contains("no such table") && !page.body.contains("libsql://"));
+        assert_eq!(page.header("Content-Type"), Some(super::HTML_CONTENT_TYPE));
+    }
+
+    #[test]
+    fn test_locate_line() {
+        use super::{locate_line, locate_separator};
+
+        let fields = |city: &str| ["WAW", "PL", city, "52.1", "21"].map(String::from).to_vec();
+        assert_eq!(locate_line(&fields("Warsaw"), ';'), "WAW;PL;Warsaw;52.1;21");
+        assert_eq!(
+            locate_line(&fields("A;B \"C\""), ';'),
+            "WAW;PL;\"A;B \"\"C\"\"\";52.1;21"
+        );
+        assert_eq!(locate_line(&fields("A;B"), '|'), "WAW|PL|A;B|52.1|21");
+
+        assert_eq!(locate_separator(None), Ok(';'));
+        assert_eq!(locate_separator(Some("|")), Ok('|'));
+        for invalid in ["", "a", "7", ";;", " ", "\t", "\"", "."] {
+            assert!(locate_separator(Some(invalid)).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_data_fields_projected() {
+        use super::{parse_fields, project, AIRPORT_FIELDS, COUNTER_FIELDS};
+        use crate::models::{AirportEntry, CounterEntry};
+        use serde_json::json;
+
+        assert_eq!(
+            parse_fields("columns", None, COUNTER_FIELDS).unwrap(),
+            COUNTER_FIELDS
+        );
+        let fields = parse_fields("columns", Some("value, country,value"), COUNTER_FIELDS).unwrap();
+        assert_eq!(fields, ["value", "country"]);
+        assert_eq!(
+            parse_fields("columns", Some("country,lat"), COUNTER_FIELDS).unwrap_err(),
+            "columns must be a list of country, city, value"
+        );
+        assert!(parse_fields("columns", Some(""), COUNTER_FIELDS).is_err());
+
+        let counter = [CounterEntry {
+            country: "PL".to_string(),
+            city: Some("Warsaw".to_string()),
+            value: 3,
+        }];
+        assert_eq!(
+            project(&counter, &fields).unwrap(),
+            [json!({ "country": "PL", "value": 3 })]
+        );
+
+        let airports = [AirportEntry {
+            airport: "WAW".to_string(),
+            lat: 52.25,
+            lon: 21.0,
+            timezone: None,
+            visits: 3,
+            approximate: false,
+        }];
+        let fields =
+            parse_fields("airport_columns", Some("airport,timezone"), AIRPORT_FIELDS).unwrap();
+        assert_eq!(
+            project(&airports, &fields).unwrap(),
+            [json!({ "airport": "WAW", "timezone": null })]
+        );
+        assert_eq!(project(&airports, AIRPORT_FIELDS).unwrap()[0]["lat"], 52.25);
+    }
+
+    #[test]
+    fn test_markdown_table() {
+        use super::ResultSet;
+        use libsql::Value;
+
+        let result = ResultSet {
+            columns: vec!["country".into(), "city".into(), "value".into()],
+            rows: vec![
+                vec![
+                    Value::Text("PL".into()),
+                    Value::Text("Warsaw".into()),
+                    Value::Integer(3),
+                ],
+                vec![
+                    Value::Text("XX".into()),
+                    Value::Text("Pipe | Town\nNew line".into()),
+                    Value::Real(0.5),
+                ],
+            ],
+        };
+        let md = result.to_markdown(&Config::default());
+        let lines: Vec<&str> = md.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "| country | city | value |",
+                "| --- | --- | --- |",
+                "| PL | Warsaw | 3 |",
+                "| XX | Pipe \\| Town New line | 0.5 |",
+            ]
+        );
+        // every row has as many unescaped separators as the header
+        for line in lines {
+            assert_eq!(line.replace("\\|", "").matches('|').count(), 4, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_count_bars() {
+        use super::render_html_table_with;
+        use libsql::Value;
+
+        let columns = vec!["city".to_string(), "value".to_string()];
+        let row = |city: &str, value: Value| vec![Value::Text(city.into()), value];
+        let rows = vec![
+            row("Warsaw", Value::Integer(8)),
+            row("Helsinki", Value::Integer(2)),
+            row("Legacy", Value::Text("4".into())),
+        ];
+        let html = render_html_table_with(&columns, &rows, &Config::default(), true);
+        assert_eq!(html.matches("class=\"bar\"").count(), 3);
+        assert!(html.contains("<td>8<div class=\"bar\" style=\"background: steelblue; height: 4px; width: 100.0%\"></div></td>"), "{html}");
+        assert!(html.contains("width: 25.0%"), "{html}");
+        assert!(html.contains("width: 50.0%"), "{html}");
+        // bars are opt-in
+        let html = render_html_table_with(&columns, &rows, &Config::default(), false);
+        assert!(!html.contains("class=\"bar\""));
+
+        let zeros = vec![
+            row("Warsaw", Value::Integer(0)),
+            row("Helsinki", Value::Null),
+        ];
+        let html = render_html_table_with(&columns, &zeros, &Config::default(), true);
+        assert_eq!(html.matches("width: 0.0%").count(), 2, "{html}");
+        let html = render_html_table_with(&columns, &[], &Config::default(), true);
+        assert!(!html.contains("class=\"bar\""));
+    }
+
+    #[test]
+    fn test_counts_formatted() {
+        use libsql::Value;
+
+        let columns = vec![
+            "country".to_string(),
+            "city".to_string(),
+            "value".to_string(),
+        ];
+        let rows = vec![
+            vec![
+                Value::Text("PL".into()),
+                Value::Text("Warsaw".into()),
+                Value::Integer(3),
+            ],
+            vec![
+                Value::Text("FI".into()),
+                Value::Text("Helsinki".into()),
+                Value::Real(0.1 + 0.2),
+            ],
+            vec![
+                Value::Text("SE".into()),
+                Value::Text("2.5".into()),
+                Value::Real(4.0),
+            ],
+        ];
+        let html = super::render_html_table(&columns, &rows, &Config::default());
+        assert!(html.contains("<td>3</td>"), "{html}");
+        assert!(html.contains("<td>0.3</td>"), "{html}");
+        assert!(html.contains("<td>4.0</td>"), "{html}");
+        // only the count column is formatted
+        assert!(html.contains("<td>2.5</td>"), "{html}");
+
+        let config = Config {
+            count_decimals: 0,
+            ..Config::default()
+        };
+        let html = super::render_html_table(&columns, &rows, &config);
+        assert!(
+            html.contains("<td>0</td>") && html.contains("<td>4</td>"),
+            "{html}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_favicon_route() {
+        assert!(super::ROUTES
+            .iter()
+            .any(|(method, path, _)| *method == "GET" && *path == "/favicon.ico"));
+        assert_eq!(super::disallowed_method("GET", "/favicon.ico"), None);
+        assert!(super::FAVICON_SVG.starts_with("<svg") && super::FAVICON_SVG.ends_with("</svg>"));
+
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'QO'",
+            "DELETE FROM coordinates WHERE airport = 't42'",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let icon = visit("t42", "QO", "Icon", (5.0, 5.0));
+            super::serve(&icon, &db, &Config::default(), &Default::default())
+                .await
+                .unwrap();
+
+            let favicon = super::favicon();
+            assert_eq!(favicon.status, 200);
+            assert_eq!(favicon.body, super::FAVICON_SVG);
+            assert_eq!(favicon.header("Content-Type"), Some("image/svg+xml"));
+            assert_eq!(
+                favicon.header("Cache-Control"),
+                Some(super::FAVICON_CACHE_CONTROL)
+            );
+            // serving the icon leaves the counter alone
+            assert_eq!(super::city_count(db.read(), "QO", "Icon").await.unwrap(), 1);
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_repeated_email_rejected() {
+        use super::{collect_query, QueryError};
+        use worker::Url;
+
+        let url = Url::parse("https://example.com/add-user?email=a%40b.c&email=d%40e.f").unwrap();
+        assert_eq!(
+            collect_query(&url, 2048, 32, &["email"]).unwrap_err(),
+            QueryError::Repeated("email".to_string())
+        );
+        // other parameters may repeat
+        let url = Url::parse("https://example.com/add-user?email=a%40b.c&x=1&x=2").unwrap();
+        let query = collect_query(&url, 2048, 32, &["email"]).unwrap();
+        assert_eq!(query["x"], "1");
+    }
+
+    #[test]
+    fn test_too_many_query_params_rejected() {
+        use super::{collect_query, QueryError};
+        use worker::Url;
+
+        let query: Vec<String> = (0..33).map(|i| format!("p{i}=1")).collect();
+        let url = Url::parse(&format!("https://example.com/?{}", query.join("&"))).unwrap();
+        assert_eq!(
+            collect_query(&url, 2048, 32, &[]).unwrap_err(),
+            QueryError::TooMany(32)
+        );
+        // repeated names count as well
+        let url = Url::parse(&format!("https://example.com/?{}", "a=1&".repeat(33))).unwrap();
+        assert!(collect_query(&url, 2048, 32, &[]).is_err());
+
+        let url = Url::parse(&format!("https://example.com/?{}", query[..32].join("&"))).unwrap();
+        assert_eq!(collect_query(&url, 2048, 32, &[]).unwrap().len(), 32);
+        // empty parameters aren't counted
+        let url = Url::parse(&format!(
+            "https://example.com/?&&{}&",
+            query[..32].join("&&")
+        ))
+        .unwrap();
+        assert_eq!(collect_query(&url, 2048, 32, &[]).unwrap().len(), 32);
+        assert_eq!(Config::default().max_query_params, 32);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        use super::collect_body;
+        use futures_util::stream;
+
+        let chunks = || stream::iter(vec![Ok(vec![b'a'; 600]), Ok(vec![b'b'; 600])]);
+        // read until it goes over the limit, whatever the declared length
+        assert_eq!(collect_body(None, chunks(), 1000).await.unwrap(), None);
+        assert_eq!(
+            collect_body(Some("10"), chunks(), 1000).await.unwrap(),
+            None
+        );
+        let body = collect_body(Some("1200"), chunks(), 1200).await.unwrap();
+        assert_eq!(body.map(|b| b.len()), Some(1200));
+
+        // a larger Content-Length is refused before reading
+        assert_eq!(
+            collect_body(Some("2000"), stream::empty(), 1000)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            collect_body(Some("bogus"), stream::empty(), 1000)
+                .await
+                .unwrap(),
+            Some(Vec::new())
+        );
+        assert_eq!(Config::default().max_body_bytes, 1024 * 1024);
+    }
+
+    #[test]
+    fn test_oversized_query_rejected() {
+        use super::{collect_query, QueryError};
+        use worker::Url;
+
+        let url = Url::parse("https://example.com/add-user?email=a%40b.c&email=other").unwrap();
+        let query = collect_query(&url, 2048, 32, &[]).unwrap();
+        assert_eq!(query["email"], "a@b.c");
+
+        let long = "x".repeat(2049);
+        let url = Url::parse(&format!("https://example.com/add-user?email={long}")).unwrap();
+        assert_eq!(
+            collect_query(&url, 2048, 32, &[]).unwrap_err(),
+            QueryError::TooLong("email".to_string())
+        );
+        let url = Url::parse(&format!("https://example.com/add-user?{long}=1")).unwrap();
+        assert!(collect_query(&url, 2048, 32, &[]).is_err());
+    }
+
+    #[test]
+    fn test_conditional_users_request() {
+        use super::is_not_modified;
+        let etag = r#""users-3""#;
+        let ts = 1_709_210_096_000;
+        assert!(is_not_modified(Some(etag), None, etag, ts));
+        assert!(is_not_modified(
+            Some(r#"W/"users-3", "users-4""#),
+            None,
+            etag,
+            ts
+        ));
+        assert!(!is_not_modified(Some(r#""users-2""#), None, etag, ts));
+        assert!(is_not_modified(
+            None,
+            Some("Thu, 29 Feb 2024 12:34:56 GMT"),
+            etag,
+            ts + 500
+        ));
+        assert!(!is_not_modified(
+            None,
+            Some("Thu, 29 Feb 2024 12:34:55 GMT"),
+            etag,
+            ts
+        ));
+        assert!(!is_not_modified(None, None, etag, ts));
+        // an ETag mismatch wins over a matching date
+        assert!(!is_not_modified(
+            Some(r#""users-2""#),
+            Some("Thu, 29 Feb 2024 12:34:56 GMT"),
+            etag,
+            ts
+        ));
+    }
+
+    #[test]
+    fn test_admin_token_check() {
+        use super::check_admin_token;
+        assert!(check_admin_token(Some("Bearer s3cret"), Some("s3cret")));
+        assert!(!check_admin_token(Some("Bearer wrong"), Some("s3cret")));
+        assert!(!check_admin_token(Some("s3cret"), Some("s3cret")));
+        assert!(!check_admin_token(None, Some("s3cret")));
+        // without a configured token admin endpoints stay disabled
+        assert!(!check_admin_token(Some("Bearer "), Some("")));
+        assert!(!check_admin_token(Some("Bearer s3cret"), None));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        use super::percent_decode;
+        assert_eq!(percent_decode("Warsaw").unwrap(), "Warsaw");
+        assert_eq!(percent_decode("S%C3%A3o%20Paulo").unwrap(), "São Paulo");
+        assert_eq!(percent_decode("a%2Fb").unwrap(), "a/b");
+        assert!(percent_decode("bad%2").is_none());
+        assert!(percent_decode("bad%zz").is_none());
+    }
+
+    #[test]
+    fn test_route_matches() {
+        use super::route_matches;
+        assert!(route_matches("/", "/"));
+        assert!(route_matches("/users", "/users"));
+        assert!(!route_matches("/users", "/users/1"));
+        assert!(route_matches(
+            "/counter/:country/:city",
+            "/counter/PL/Warsaw"
+        ));
+        assert!(!route_matches("/counter/:country/:city", "/counter/PL/"));
+        assert!(!route_matches("/counter/:country/:city", "/counter/PL"));
+    }
+
+    #[test]
+    fn test_trailing_slash_target() {
+        use super::{disallowed_method, trailing_slash_target};
+        assert_eq!(trailing_slash_target("/users/"), Some("/users"));
+        assert_eq!(
+            trailing_slash_target("/counter/PL/Warsaw/"),
+            Some("/counter/PL/Warsaw")
+        );
+        assert_eq!(trailing_slash_target("/"), None);
+        assert_eq!(trailing_slash_target("/users"), None);
+        assert_eq!(trailing_slash_target("/nowhere/"), None);
+        assert_eq!(trailing_slash_target("/users//"), None);
+        // the redirect ends up at the same route, with the same methods allowed
+        let target = trailing_slash_target("/add-user/").unwrap();
+        assert_eq!(
+            disallowed_method("POST", target),
+            disallowed_method("POST", "/add-user")
+        );
+    }
+
+    #[test]
+    fn test_method_override() {
+        use super::{disallowed_method, method_override};
+        use worker::Url;
+
+        let url = Url::parse("https://example.com/counter/PL/Warsaw?_method=delete").unwrap();
+        let method = method_override("POST", &url);
+        assert_eq!(method, Some("DELETE"));
+        // which is the method of the city deletion handler
+        assert_eq!(disallowed_method(method.unwrap(), url.path()), None);
+        assert!(disallowed_method("POST", url.path()).is_some());
+
+        assert_eq!(method_override("GET", &url), None);
+        let url = Url::parse("https://example.com/counter/PL/Warsaw?_method=PATCH").unwrap();
+        assert_eq!(method_override("POST", &url), None);
+        let url = Url::parse("https://example.com/batch").unwrap();
+        assert_eq!(method_override("POST", &url), None);
+        assert!(!Config::default().method_override);
+    }
+
+    #[test]
+    fn test_method_override_routes_to_delete() {
+        use super::{routed_method, ROUTES};
+        use worker::Url;
+
+        let config = Config {
+            method_override: true,
+            ..Config::default()
+        };
+        for (path, pattern) in [
+            ("/counter/PL/Warsaw", "/counter/:country/:city"),
+            ("/counter/country/PL", "/counter/country/:country"),
+        ] {
+            // a form's POST reaches the admin handler registered for DELETE
+            let url = Url::parse(&format!("https://example.com{path}?_method=DELETE")).unwrap();
+            assert_eq!(
+                routed_method("POST", &url, &config).as_deref(),
+                Ok("DELETE")
+            );
+            assert!(ROUTES
+                .iter()
+                .any(|(method, route, _)| *method == "DELETE" && *route == pattern));
+            // and is refused like any POST without the override
+            assert_eq!(
+                routed_method("POST", &url, &Config::default()).as_deref(),
+                Err("DELETE")
+            );
+        }
+        let url = Url::parse("https://example.com/counter/PL/Warsaw?_method=PUT").unwrap();
+        assert_eq!(
+            routed_method("POST", &url, &config).as_deref(),
+            Err("DELETE")
+        );
+        let url = Url::parse("https://example.com/batch?_method=DELETE").unwrap();
+        assert_eq!(routed_method("POST", &url, &config).as_deref(), Ok("POST"));
+    }
+
+    #[test]
+    fn test_wrong_method_is_not_allowed() {
+        use super::disallowed_method;
+        assert_eq!(
+            disallowed_method("POST", "/add-user").as_deref(),
+            Some("GET")
+        );
+        assert_eq!(
+            disallowed_method("GET", "/counter/PL/Warsaw").as_deref(),
+            Some("DELETE")
+        );
+        assert_eq!(disallowed_method("GET", "/add-user"), None);
+        // unknown paths are left to the router's 404
+        assert_eq!(disallowed_method("POST", "/unknown"), None);
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        use super::preflight_headers;
+
+        assert_eq!(preflight_headers("/users", &Config::default()), None);
+        let config = Config {
+            cors_origin: Some("https://example.com".to_string()),
+            ..Config::default()
+        };
+        let headers = preflight_headers("/users", &config).unwrap();
+        let header = |name| {
+            headers
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("Access-Control-Max-Age"), Some("600"));
+        assert_eq!(header("Access-Control-Allow-Methods"), Some("GET, OPTIONS"));
+        assert_eq!(
+            header("Access-Control-Allow-Origin"),
+            Some("https://example.com")
+        );
+
+        let config = Config {
+            cors_max_age: 120,
+            ..config
+        };
+        let headers = preflight_headers("/counter/PL/Warsaw", &config).unwrap();
+        assert!(headers.contains(&("Access-Control-Max-Age", "120".to_string())));
+        assert!(headers.contains(&(
+            "Access-Control-Allow-Methods",
+            "DELETE, OPTIONS".to_string()
+        )));
+        assert_eq!(preflight_headers("/nowhere", &config), None);
+    }
+
+    #[test]
+    fn test_route_manifest() {
+        let manifest = serde_json::to_value(super::route_manifest()).unwrap();
+        let routes = manifest.as_array().unwrap();
+        for (method, path) in [("GET", "/"), ("GET", "/users"), ("GET", "/locate")] {
+            let route = routes
+                .iter()
+                .find(|r| r["path"] == path && r["method"] == method)
+                .unwrap_or_else(|| panic!("{method} {path} missing from the manifest"));
+            assert!(!route["description"].as_str().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_log_request_fields() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            super::log_request(
+                "POST",
+                "/add-user",
+                405,
+                12,
+                "(0.0, 0.0) within unknown region",
+            );
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains(r#"method="POST""#), "{output}");
+        assert!(output.contains(r#"path="/add-user""#), "{output}");
+        assert!(output.contains("status=405"), "{output}");
+        assert!(output.contains("duration_ms=12"), "{output}");
+    }
+
+    #[test]
+    fn test_list_orders_are_sql() {
+        use super::{
+            ListParams, COORDINATES_LIST, SEARCH_LIST, TOP_CITIES_LIST, TOP_LIST, USERS_LIST,
+        };
+        for spec in [&TOP_LIST, &TOP_CITIES_LIST, &SEARCH_LIST, &COORDINATES_LIST] {
+            assert!(ListParams::new(spec).order_by(spec).is_some());
+            for (name, _) in spec.orders {
+                assert!(super::is_valid_identifier(name));
+            }
+        }
+        assert_eq!(ListParams::new(&USERS_LIST).order_by(&USERS_LIST), None);
+    }
+
+    fn test_connection() -> Connection<CloudflareSender> {
+        let url = env!("LIBSQL_CLIENT_URL");
+        let auth_token = env!("LIBSQL_CLIENT_TOKEN");
+        Connection::open_cloudflare_worker(url, auth_token)
+    }
+
+    fn test_db() -> Db {
+        Db::new(test_connection())
+    }
+
+    fn visit(airport: &str, country: &str, city: &str, coordinates: (f32, f32)) -> VisitContext {
+        VisitContext {
+            airport: airport.to_string(),
+            country: country.to_string(),
+            city: city.to_string(),
+            coordinates: Some(coordinates),
+            timezone: None,
+            region: None,
+            asn: 64_512,
+            returning: false,
+            do_not_track: false,
+            client: Some(format!("visit:{airport}")),
+            duplicate: false,
+            ts: 1_700_000_000_000,
+        }
+    }
+
+    // Run `test` on a fresh schema, between `teardown` statements deleting
+    // the rows it writes: before, in case an earlier run left them behind,
+    // and after, even when the test panics. The panic is raised again then.
+    async fn with_teardown<F, Fut>(db: Db, teardown: &[&str], test: F)
+    where
+        F: FnOnce(std::rc::Rc<Db>) -> Fut,
+        Fut: std::future::Future<Output = ()> + 'static,
+    {
+        let db = std::rc::Rc::new(db);
+        super::ensure_schema(&db).await.unwrap();
+        for statement in teardown {
+            db.primary.execute(*statement, ()).await.unwrap();
+        }
+        let test = test(db.clone());
+        let outcome = tokio::task::LocalSet::new()
+            .run_until(async move { tokio::task::spawn_local(test).await })
+            .await;
+        for statement in teardown {
+            db.primary.execute(*statement, ()).await.unwrap();
+        }
+        if let Err(e) = outcome {
+            if e.is_panic() {
+                std::panic::resume_unwind(e.into_panic());
+            }
+        }
+    }
+
+    // Latencies observed by simulate_visits
+    struct VisitStats {
+        visits: usize,
+        elapsed: std::time::Duration,
+        mean: std::time::Duration,
+        max: std::time::Duration,
+    }
+
+    // Serve `visits` the way concurrent browsers would, with at most
+    // `concurrency` of them in flight at a time
+    async fn simulate_visits(
+        db: std::rc::Rc<Db>,
+        visits: &[VisitContext],
+        concurrency: usize,
+        config: Config,
+    ) -> VisitStats {
+        use std::cell::{Cell, RefCell};
+        use std::rc::Rc;
+        use std::time::{Duration, Instant};
+
+        let visits: Rc<[VisitContext]> = visits.into();
+        let config = Rc::new(config);
+        let next = Rc::new(Cell::new(0));
+        let latencies = Rc::new(RefCell::new(Vec::with_capacity(visits.len())));
+        let started = Instant::now();
+        let tasks = tokio::task::LocalSet::new();
+        for _ in 0..concurrency.max(1) {
+            let (db, visits, config) = (db.clone(), visits.clone(), config.clone());
+            let (next, latencies) = (next.clone(), latencies.clone());
+            tasks.spawn_local(async move {
+                while let Some(visit) = visits.get(next.get()) {
+                    next.set(next.get() + 1);
+                    let start = Instant::now();
+                    super::serve(visit, &db, &config, &Default::default())
+                        .await
+                        .unwrap();
+                    latencies.borrow_mut().push(start.elapsed());
+                }
+            });
+        }
+        tasks.await;
+
+        let latencies = latencies.take();
+        let total: Duration = latencies.iter().sum();
+        VisitStats {
+            visits: latencies.len(),
+            elapsed: started.elapsed(),
+            mean: total / latencies.len().max(1) as u32,
+            max: latencies.iter().max().copied().unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn test_reads_use_replica_when_configured() {
+        let db = test_db();
+        assert!(std::ptr::eq(db.read(), &db.primary));
+
+        let db = test_db().with_replica(test_connection());
+        assert!(std::ptr::eq(db.read(), db.replica.as_ref().unwrap()));
+        assert!(!std::ptr::eq(db.read(), &db.primary));
+    }
+
+    #[tokio::test]
+    async fn test_counter_updated() {
+        // Only rows of these airports and countries are written, so the test
+        // doesn't depend on what other tests or earlier runs left behind
+        let teardown = [
+            "DELETE FROM counter WHERE country IN ('XB', 'XH')",
+            "DELETE FROM coordinates WHERE airport IN ('t32', 't33')",
+            "DELETE FROM visits WHERE airport IN ('t32', 't33')",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let payloads = [
+                ("t32", "XB", "Warsaw", (-52.1672, -20.9679)),
+                ("t32", "XB", "Warsaw", (-52.1672, -20.9679)),
+                ("t32", "XB", "Warsaw", (-52.1672, -20.9679)),
+                ("t33", "XH", "Helsinki", (-60.3183, -24.9497)),
+                ("t33", "XH", "Helsinki", (-60.3183, -24.9497)),
+            ];
+
+            for p in payloads {
+                super::serve(
+                    &visit(p.0, p.1, p.2, p.3),
+                    &db,
+                    &Config::default(),
+                    &Default::default(),
+                )
+                .await
+                .unwrap();
+            }
+
+            let mut result = db
+                .read()
+                .query(
+                    "SELECT country, city, value FROM counter WHERE country IN ('XB', 'XH')",
+                    (),
+                )
+                .await
+                .unwrap();
+            let columns: Vec<_> = (0..result.column_count())
+                .map(|c| result.column_name(c).unwrap_or(""))
+                .collect();
+
+            assert_eq!(columns, vec!["country", "city", "value"]);
+            let mut cities = 0;
+            while let Some(row) = result.next().unwrap() {
+                let city: String = row.get(1).unwrap();
+                match city.as_str() {
+                    "Warsaw" => assert_eq!(row.get::<i64>(2).unwrap(), 3),
+                    "Helsinki" => assert_eq!(row.get::<i64>(2).unwrap(), 2),
+                    other => panic!("Unknown city: {:?}", other),
+                }
+                cities += 1;
+            }
+            assert_eq!(cities, 2);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_compare_countries() {
+        let teardown = ["DELETE FROM counter WHERE country IN ('XL', 'XV')"];
+        with_teardown(test_db(), &teardown, |db| async move {
+            db.primary
+                .execute(
+                    "INSERT INTO counter(country, city, value) VALUES ('XL', 'Ljubljana', 4), ('XL', 'Maribor', 3), ('XV', 'Vilnius', 5)",
+                    (),
+                )
+                .await
+                .unwrap();
+
+            let a = super::country_total(db.read(), "XL").await.unwrap();
+            let b = super::country_total(db.read(), "XV").await.unwrap();
+            assert_eq!((a.value, b.value), (7, 5));
+            let comparison = super::compare_countries(a, b);
+            assert_eq!(comparison.leader.as_deref(), Some("XL"));
+            assert_eq!(
+                serde_json::to_value(&comparison).unwrap(),
+                serde_json::json!({
+                    "a": {"country": "XL", "value": 7},
+                    "b": {"country": "XV", "value": 5},
+                    "leader": "XL"
+                })
+            );
+
+            // a country without visits is compared as 0, and equal totals tie
+            let unvisited = super::country_total(db.read(), "XZ").await.unwrap();
+            assert_eq!(unvisited.value, 0);
+            let b = super::country_total(db.read(), "XV").await.unwrap();
+            assert_eq!(super::compare_countries(b, unvisited).leader.as_deref(), Some("XV"));
+            let a = super::country_total(db.read(), "XV").await.unwrap();
+            let b = super::country_total(db.read(), "XV").await.unwrap();
+            assert_eq!(super::compare_countries(a, b).leader, None);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_fallback_coordinates() {
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'NZ' AND city = 'Unplaced'",
+            "DELETE FROM coordinates WHERE airport IN ('t34', 't35', 't39')",
+            "DELETE FROM visits WHERE airport IN ('t34', 't35', 't39')",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            async fn location(db: &Db, airport: &str) -> Option<(f64, f64, i64)> {
+                let mut rows = db
+                    .read()
+                    .query(
+                        "SELECT lat, long, approximate FROM coordinates WHERE airport = ?",
+                        libsql::params![airport],
+                    )
+                    .await
+                    .unwrap();
+                rows.next().unwrap().map(|row| {
+                    (
+                        row.get(0).unwrap(),
+                        row.get(1).unwrap(),
+                        row.get(2).unwrap(),
+                    )
+                })
+            }
+
+            let unplaced = |airport| VisitContext {
+                coordinates: None,
+                ..visit(airport, "NZ", "Unplaced", (0.0, 0.0))
+            };
+            super::serve(
+                &unplaced("t34"),
+                &db,
+                &Config::default(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(location(&db, "t34").await, None);
+
+            let config = Config {
+                fallback_coords: true,
+                ..Config::default()
+            };
+            super::serve(&unplaced("t35"), &db, &config, &Default::default())
+                .await
+                .unwrap();
+            let (lat, lon, approximate) = location(&db, "t35").await.expect("t35 airport");
+            assert!((lat - -41.2865).abs() < 1e-3 && (lon - 174.7762).abs() < 1e-3);
+            assert_eq!(approximate, 1);
+
+            let rows = db.read().query(super::AIRPORTS_QUERY, ()).await.unwrap();
+            let airports = super::parse_airports(rows).unwrap();
+            let t35 = airports.iter().find(|a| a.airport == "t35").unwrap();
+            assert!(t35.approximate);
+
+            // an airport already placed keeps its only row and its coordinates
+            let placed = visit("t39", "NZ", "Unplaced", (-8.5, -8.5));
+            super::serve(&placed, &db, &config, &Default::default())
+                .await
+                .unwrap();
+            super::serve(&unplaced("t39"), &db, &config, &Default::default())
+                .await
+                .unwrap();
+            let mut rows = db
+                .read()
+                .query("SELECT COUNT(*) FROM coordinates WHERE airport = 't39'", ())
+                .await
+                .unwrap();
+            assert_eq!(rows.next().unwrap().unwrap().get::<i64>(0).unwrap(), 1);
+            let (lat, lon, approximate) = location(&db, "t39").await.expect("t39 airport");
+            assert!((lat - -8.5).abs() < 1e-3 && (lon - -8.5).abs() < 1e-3);
+            assert_eq!(approximate, 0);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_first_and_repeat_visit_weights() {
+        let db = test_db();
+        let config = Config {
+            first_visit_weight: 5.0,
+            repeat_visit_weight: 1.0,
+            ..Config::default()
+        };
+        // a fresh airport and day, so the first visit is not yet seen
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let first = VisitContext {
+            ts: now,
+            ..visit("t12", "XX", "Weighted", (1.0, 1.0))
+        };
+        let repeat = VisitContext {
+            ts: now + 1000,
+            ..visit("t12", "XX", "Weighted", (1.0, 1.0))
+        };
+        db.primary
+            .execute("DELETE FROM counter WHERE country = 'XX'", ())
+            .await
+            .unwrap();
+        db.primary
+            .execute("DELETE FROM seen WHERE colo = 't12'", ())
+            .await
+            .unwrap();
+
+        super::serve(&first, &db, &config, &Default::default())
+            .await
+            .unwrap();
+        super::serve(&repeat, &db, &config, &Default::default())
+            .await
+            .unwrap();
+        super::serve(&repeat, &db, &config, &Default::default())
+            .await
+            .unwrap();
+
+        let mut result = db
+            .read()
+            .query(
+                "SELECT value FROM counter WHERE country = 'XX' AND city = 'Weighted'",
+                (),
+            )
+            .await
+            .unwrap();
+        let row = result.next().unwrap().unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_asn_not_counted() {
+        let db = test_db();
+        let config = Config {
+            blocked_asns: vec![16509],
+            ..Config::default()
+        };
+        let counted = visit("t13", "XX", "Unblocked", (2.0, 2.0));
+        super::serve(&counted, &db, &config, &Default::default())
+            .await
+            .unwrap();
+        let before = super::city_count(db.read(), "XX", "Unblocked")
+            .await
+            .unwrap();
+
+        let blocked = VisitContext {
+            asn: 16509,
+            ..visit("t13", "XX", "Unblocked", (2.0, 2.0))
+        };
+        let html = super::serve(&blocked, &db, &config, &Default::default())
+            .await
+            .unwrap();
+        assert!(html.contains("Unblocked"));
+        let after = super::city_count(db.read(), "XX", "Unblocked")
+            .await
+            .unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        use super::parse_since;
+        assert_eq!(parse_since(Some("1700000000000")), Ok(1_700_000_000_000));
+        assert_eq!(parse_since(Some("0")), Ok(0));
+        assert!(parse_since(None).is_err());
+        assert!(parse_since(Some("-1")).is_err());
+        assert!(parse_since(Some("yesterday")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_counter_delta() {
+        let db = test_db();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let first = VisitContext {
+            ts: now,
+            ..visit("t14", "XD", "Earlier", (3.0, 3.0))
+        };
+        let second = VisitContext {
+            ts: now + 1000,
+            ..visit("t14", "XD", "Later", (3.0, 3.0))
+        };
+        for visit in [&first, &second] {
+            super::serve(visit, &db, &Config::default(), &Default::default())
+                .await
+                .unwrap();
+        }
+
+        let delta = super::counter_delta(db.read(), now).await.unwrap();
+        let cities: Vec<&str> = delta
+            .rows
+            .iter()
+            .filter(|e| e.country == "XD")
+            .map(|e| e.city.as_str())
+            .collect();
+        assert_eq!(cities, vec!["Later"]);
+        assert!(delta.cursor >= now + 1000);
+    }
+
+    #[tokio::test]
+    async fn test_max_cities_folds_into_other() {
+        let db = test_db();
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+        let mut result = db
+            .primary
+            .query("SELECT COUNT(*) FROM counter", ())
+            .await
+            .unwrap();
+        let rows: i64 = result.next().unwrap().unwrap().get(0).unwrap();
+        // the counter is already full, so any new city is folded
+        let config = Config {
+            max_cities: Some(rows as u64),
+            ..Config::default()
+        };
+        let other_before = super::city_count(db.read(), "XO", super::OTHER_CITY)
+            .await
+            .unwrap();
+
+        let city = format!("Novel{rows}");
+        let novel = visit("t15", "XO", &city, (4.0, 4.0));
+        super::serve(&novel, &db, &config, &Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(super::city_count(db.read(), "XO", &city).await.unwrap(), 0);
+        let other_after = super::city_count(db.read(), "XO", super::OTHER_CITY)
+            .await
+            .unwrap();
+        assert_eq!(other_after, other_before + 1);
+    }
+
+    #[tokio::test]
+    async fn test_whoami_reflects_config() {
+        let db = test_db();
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+        let mut caller = visit("t20", "XW", "Whoville", (5.0, 6.0));
+        caller.region = Some("North".to_string());
+
+        let me = super::whoami(&caller, &db, &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(me.airport, "t20");
+        assert_eq!((me.country.as_str(), me.city.as_str()), ("XW", "Whoville"));
+        assert_eq!(me.region.as_deref(), Some("North"));
+        assert_eq!((me.lat, me.lon), (5.0, 6.0));
+        assert_eq!(me.local_time, "22:13 UTC");
+        assert!(me.counted);
+
+        caller.timezone = Some("America/New_York".to_string());
+        let me = super::whoami(&caller, &db, &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(me.local_time, "17:13");
+
+        let config = Config {
+            max_cities: Some(0),
+            blocked_asns: vec![caller.asn],
+            ..Config::default()
+        };
+        let me = super::whoami(&caller, &db, &config).await.unwrap();
+        assert_eq!(me.city, super::OTHER_CITY);
+        assert!(!me.counted);
+        // nothing was counted by asking
+        assert_eq!(
+            super::city_count(db.read(), "XW", "Whoville")
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_city_case_merged() {
+        let db = test_db();
+        let insensitive = Config {
+            city_case_insensitive: true,
+            ..Config::default()
+        };
+        let before = super::city_count(db.read(), "XQ", "Casetown")
+            .await
+            .unwrap();
+        for city in ["Casetown", "casetown", "CASETOWN"] {
+            super::serve(
+                &visit("t26", "XQ", city, (13.0, 13.0)),
+                &db,
+                &insensitive,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        }
+        let after = super::city_count(db.read(), "XQ", "Casetown")
+            .await
+            .unwrap();
+        assert_eq!(after, before + 3);
+        assert_eq!(
+            super::city_count(db.read(), "XQ", "CASETOWN")
+                .await
+                .unwrap(),
+            0
+        );
+
+        // off by default
+        let sensitive = Config::default();
+        assert!(!sensitive.city_case_insensitive);
+        let city = "CaseSensitiveTown";
+        let upper = city.to_uppercase();
+        for city in [city, upper.as_str()] {
+            super::serve(
+                &visit("t26", "XQ", city, (13.0, 13.0)),
+                &db,
+                &sensitive,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        }
+        assert!(super::city_count(db.read(), "XQ", &upper).await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_region_recorded_and_aggregated() {
+        let db = test_db();
+        let with_region = VisitContext {
+            region: Some("Masovia".to_string()),
+            ..visit("t16", "XR", "Warsaw", (5.0, 5.0))
+        };
+        let without_region = visit("t16", "XR", "Nowhere", (5.0, 5.0));
+        for visit in [&with_region, &without_region] {
+            super::serve(visit, &db, &Config::default(), &Default::default())
+                .await
+                .unwrap();
+        }
+
+        let regions = super::region_totals(db.read()).await.unwrap();
+        let masovia = regions
+            .iter()
+            .find(|e| e.country == "XR" && e.region.as_deref() == Some("Masovia"))
+            .unwrap();
+        assert!(masovia.value >= 1);
+        assert!(regions
+            .iter()
+            .any(|e| e.country == "XR" && e.region.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_visits_not_lost() {
+        const VISITS: i64 = 20;
+        let db = std::rc::Rc::new(test_db());
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let city = format!("Race{now}");
+
+        let visits = vec![visit("t17", "XC", &city, (6.0, 6.0)); VISITS as usize];
+        let stats = simulate_visits(db.clone(), &visits, 8, Config::default()).await;
+        assert_eq!(stats.visits, VISITS as usize);
+        assert!(stats.max <= stats.elapsed);
+        assert!(stats.mean <= stats.max);
+
+        let count = super::city_count(db.read(), "XC", &city).await.unwrap();
+        assert_eq!(count, VISITS);
+    }
+
+    #[test]
+    fn test_visit_cookie() {
+        use super::{has_cookie, visit_cookie, VISIT_COOKIE};
+        assert!(has_cookie(Some("visit_id=18bcfe56800"), VISIT_COOKIE));
+        assert!(has_cookie(Some("theme=dark; visit_id=1"), VISIT_COOKIE));
+        assert!(!has_cookie(Some("other_visit_id=1"), VISIT_COOKIE));
+        assert!(!has_cookie(None, VISIT_COOKIE));
+
+        let config = Config {
+            session_dedup: true,
+            ..Config::default()
+        };
+        let new = visit("t18", "XS", "Session", (7.0, 7.0));
+        let cookie = visit_cookie(&new, &config).unwrap();
+        assert!(cookie.starts_with("visit_id="));
+        assert!(cookie.contains("Max-Age=31536000"));
+        assert!(cookie.ends_with("; HttpOnly; Secure; SameSite=Lax"));
+        assert!(visit_cookie(&new, &Config::default()).is_none());
+
+        let returning = VisitContext {
+            returning: true,
+            ..visit("t18", "XS", "Session", (7.0, 7.0))
+        };
+        assert!(visit_cookie(&returning, &config).is_none());
+        let untracked = VisitContext {
+            do_not_track: true,
+            ..visit("t18", "XS", "Session", (7.0, 7.0))
+        };
+        assert!(visit_cookie(&untracked, &config).is_none());
+    }
+
+    #[test]
+    fn test_build_cookie() {
+        use super::build_cookie;
+        use crate::config::{CookieOptions, SameSite};
+
+        let cookie = build_cookie("theme", "dark", &CookieOptions::default());
+        assert_eq!(cookie, "theme=dark; Path=/; HttpOnly; Secure; SameSite=Lax");
+
+        let options = CookieOptions {
+            http_only: false,
+            secure: false,
+            same_site: SameSite::Strict,
+            max_age: Some(60),
+        };
+        assert_eq!(
+            build_cookie("theme", "dark", &options),
+            "theme=dark; Max-Age=60; Path=/; SameSite=Strict"
+        );
+        let options = CookieOptions {
+            same_site: SameSite::None,
+            ..options
+        };
+        assert!(build_cookie("theme", "dark", &options).ends_with("; Secure; SameSite=None"));
+    }
+
+    #[tokio::test]
+    async fn test_returning_session_not_counted() {
+        let db = std::rc::Rc::new(test_db());
+        let config = || Config {
+            session_dedup: true,
+            ..Config::default()
+        };
+        let new = visit("t18", "XS", "Session", (7.0, 7.0));
+        super::serve(&new, &db, &config(), &Default::default())
+            .await
+            .unwrap();
+        let before = super::city_count(db.read(), "XS", "Session").await.unwrap();
+
+        let returning = VisitContext {
+            returning: true,
+            ..visit("t18", "XS", "Session", (7.0, 7.0))
+        };
+        let stats = simulate_visits(db.clone(), &vec![returning; 10], 4, config()).await;
+        assert_eq!(stats.visits, 10);
+        let after = super::city_count(db.read(), "XS", "Session").await.unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[tokio::test]
+    async fn test_timezone_recorded() {
+        let db = test_db();
+        let visit = VisitContext {
+            timezone: Some("Europe/Warsaw".to_string()),
+            ..visit("waw", "PL", "Warsaw", (52.1672, 20.9679))
+        };
+        super::serve(&visit, &db, &Config::default(), &Default::default())
+            .await
+            .unwrap();
+
+        let rows = db.read().query(super::AIRPORTS_QUERY, ()).await.unwrap();
+        let airports = super::parse_airports(rows).unwrap();
+        let json = serde_json::to_value(&airports).unwrap();
+        let waw = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["airport"] == "waw")
+            .expect("waw airport");
+        assert_eq!(waw["timezone"], "Europe/Warsaw");
+    }
+
+    #[cfg(feature = "map")]
+    #[tokio::test]
+    async fn test_map_with_too_few_columns() {
+        let db = test_db();
+        super::serve(
+            &visit("t27", "XM", "Columns", (1.0, 1.0)),
+            &db,
+            &Config::default(),
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+
+        for query in [
+            "SELECT airport FROM coordinates",
+            "SELECT airport, lat FROM coordinates",
+        ] {
+            let rows = db.read().query(query, ()).await.unwrap();
+            let airports = super::map_airports(rows, &Default::default()).unwrap();
+            assert!(airports.is_empty());
+            assert_eq!(super::render_map_canvas(&airports, 5), "");
+        }
+        // the coordinates are enough, without the timezone and visits
+        let rows = db
+            .read()
+            .query("SELECT airport, lat, long FROM coordinates", ())
+            .await
+            .unwrap();
+        let airports = super::map_airports(rows, &Default::default()).unwrap();
+        assert!(airports.iter().any(|a| a.airport == "t27"));
+    }
+
+    #[cfg(feature = "map")]
+    #[tokio::test]
+    async fn test_map_points_by_recency() {
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'XY'",
+            "DELETE FROM coordinates WHERE airport IN ('t28', 't29')",
+            "DELETE FROM visits WHERE airport IN ('t28', 't29')",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64;
+            for (airport, ts) in [("t28", now), ("t29", now + 1)] {
+                let visit = VisitContext {
+                    ts,
+                    ..visit(airport, "XY", "Recent", (2.0, 2.0))
+                };
+                super::serve(&visit, &db, &Config::default(), &Default::default())
+                    .await
+                    .unwrap();
+            }
+
+            let capped = Config {
+                max_map_points: Some(2),
+                ..Config::default()
+            };
+            let query = super::map_query(&capped);
+            let rows = db.read().query(&query, ()).await.unwrap();
+            let airports = super::map_airports(rows, &Default::default()).unwrap();
+            assert_eq!(airports.len(), 2);
+            // No airport left out was seen after the kept ones. Checked in a
+            // single statement, as other tests write airports concurrently.
+            let mut rows = db
+                .read()
+                .query(
+                    &format!(
+                        "SELECT COUNT(*) FROM coordinates
+                        WHERE airport NOT IN (SELECT airport FROM ({query}))
+                        AND COALESCE(last_seen, 0) > (
+                            SELECT MIN(COALESCE(last_seen, 0)) FROM coordinates
+                            WHERE airport IN (SELECT airport FROM ({query}))
+                        )"
+                    ),
+                    (),
+                )
+                .await
+                .unwrap();
+            let newer_left_out: i64 = rows.next().unwrap().unwrap().get(0).unwrap();
+            assert_eq!(newer_left_out, 0);
+
+            // the later visit ranks first, whatever else the table holds
+            let uncapped = Config {
+                max_map_points: Some(1_000_000),
+                ..Config::default()
+            };
+            let rows = db
+                .read()
+                .query(&super::map_query(&uncapped), ())
+                .await
+                .unwrap();
+            let airports = super::map_airports(rows, &Default::default()).unwrap();
+            let rank = |code: &str| airports.iter().position(|a| a.airport == code).unwrap();
+            assert!(rank("t29") < rank("t28"));
+
+            let by_count = Config {
+                map_point_order: crate::config::MapPointOrder::Count,
+                ..capped
+            };
+            assert!(
+                super::map_query(&by_count).ends_with("ORDER BY COALESCE(visits, 0) DESC LIMIT 2")
+            );
+            assert_eq!(super::map_query(&Config::default()), super::AIRPORTS_QUERY);
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_email_rejection() {
+        use super::email_rejection;
+
+        assert_eq!(email_rejection("a@b.c"), None);
+        assert_eq!(email_rejection("first.last+tag@mail.example.com"), None);
+        for email in [
+            "", "a", "a@", "@b.c", "a@b", "a@.b", "a@b.", "a@b@c.d", "a b@c.d",
+        ] {
+            assert!(email_rejection(email).is_some(), "{email:?}");
+        }
+        let long = format!("{}@b.c", "a".repeat(super::MAX_EMAIL_LEN));
+        assert!(email_rejection(&long).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_add_user_dry_run() {
+        use super::{add_user, AddUserOutcome};
+
+        let db = test_db();
+        let table = "test_dry_run_users";
+        db.primary
+            .execute(
+                &format!("CREATE TABLE IF NOT EXISTS {table}(email TEXT)"),
+                (),
+            )
+            .await
+            .unwrap();
+        let email = "dry@run.example";
+        add_user(&db.primary, table, email, false).await.unwrap();
+        async fn count(db: &Connection<CloudflareSender>, table: &str) -> i64 {
+            let mut rows = db
+                .query(&format!("SELECT COUNT(*) FROM {table}"), ())
+                .await
+                .unwrap();
+            rows.next().unwrap().unwrap().get::<i64>(0).unwrap()
+        }
+        let before = count(&db.primary, table).await;
+
+        let outcome = add_user(&db.primary, table, email, true).await.unwrap();
+        assert!(
+            matches!(outcome, AddUserOutcome::Rejected { status: 409, .. }),
+            "{outcome:?}"
+        );
+        let outcome = add_user(&db.primary, table, "new@run.example", true)
+            .await
+            .unwrap();
+        assert_eq!(outcome, AddUserOutcome::WouldInsert);
+        let outcome = add_user(&db.primary, table, "not an email", true)
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            AddUserOutcome::Rejected { status: 400, .. }
+        ));
+        assert_eq!(count(&db.primary, table).await, before);
+
+        // a real insert is rejected the same way, and inserts nothing either
+        let outcome = add_user(&db.primary, table, email, false).await.unwrap();
+        assert!(
+            matches!(outcome, AddUserOutcome::Rejected { status: 409, .. }),
+            "{outcome:?}"
+        );
+        let outcome = add_user(&db.primary, table, "not an email", false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            AddUserOutcome::Rejected { status: 400, .. }
+        ));
+        assert_eq!(count(&db.primary, table).await, before);
+
+        // without an email column, there are no duplicates to look for
+        let table = "test_dry_run_ids";
+        db.primary
+            .execute(&format!("CREATE TABLE IF NOT EXISTS {table}(id TEXT)"), ())
+            .await
+            .unwrap();
+        add_user(&db.primary, table, email, false).await.unwrap();
+        let outcome = add_user(&db.primary, table, email, true).await.unwrap();
+        assert_eq!(outcome, AddUserOutcome::WouldInsert);
+    }
+
+    #[tokio::test]
+    async fn test_sparkline() {
+        use super::{daily_visits, sparkline_svg, MS_PER_DAY};
+
+        let db = test_db();
+        super::ensure_schema(&db).await.unwrap();
+        db.primary
+            .execute("DELETE FROM visits WHERE country = 'XG'", ())
+            .await
+            .unwrap();
+        // days 1000 to 1006: visits on the first, third and last one, and one
+        // before the range which isn't shown
+        for (day, visits) in [(999, 5), (1000, 1), (1002, 4), (1006, 2)] {
+            for _ in 0..visits {
+                db.primary
+                    .execute(
+                        "INSERT INTO visits VALUES (?, 't30', 'XG', 'Spark')",
+                        libsql::params![day * MS_PER_DAY + 1_000],
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let buckets = daily_visits(&db.primary, "XG", 1006, 7).await.unwrap();
+        assert_eq!(buckets, [1, 0, 4, 0, 0, 0, 2]);
+        let svg = sparkline_svg(&buckets);
+        let points = svg
+            .split("points=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("polyline points");
+        assert_eq!(points.split(' ').count(), 7);
+        // the busiest day reaches the top, days without visits the bottom
+        assert!(
+            points.starts_with("0.0,15.0 16.7,20.0 33.3,0.0 "),
+            "{points}"
+        );
+        assert!(svg.contains("<polyline"));
+
+        let empty = daily_visits(&db.primary, "XG", 2000, 7).await.unwrap();
+        let svg = sparkline_svg(&empty);
+        assert!(!svg.contains("<polyline"));
+        assert!(svg.contains("<line"));
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        use super::{apply_batch, city_count};
+        use crate::models::BatchOp;
+
+        let db = test_db();
+        super::ensure_schema(&db).await.unwrap();
+        let op = |city: &str, by| BatchOp {
+            country: "XA".to_string(),
+            city: city.to_string(),
+            by,
+        };
+        async fn counts(db: &Db) -> (i64, i64) {
+            (
+                city_count(db.read(), "XA", "Batch1").await.unwrap(),
+                city_count(db.read(), "XA", "Batch2").await.unwrap(),
+            )
+        }
+        let before = counts(&db).await;
+
+        // an invalid operation leaves every count as it was
+        let ops = [op("Batch1", 1), op("Batch2", 0)];
+        let batch = apply_batch(&db, &Config::default(), &ops, true, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(batch.applied, 0);
+        assert!(batch.results.iter().all(|r| !r.ok));
+        assert!(batch.results[1]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("by must be"));
+        assert_eq!(counts(&db).await, before);
+
+        let ops = [op("Batch1", 2), op("Batch2", 3), op("Batch1", 5)];
+        let batch = apply_batch(&db, &Config::default(), &ops, true, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(batch.applied, 3);
+        assert!(batch.results.iter().all(|r| r.ok && r.error.is_none()));
+        assert_eq!(counts(&db).await, (before.0 + 7, before.1 + 3));
+
+        // without atomic, the valid operations are applied anyway
+        let ops = [op("Batch1", 1), op("", 1)];
+        let batch = apply_batch(&db, &Config::default(), &ops, false, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(batch.applied, 1);
+        assert!(batch.results[0].ok && !batch.results[1].ok);
+        assert_eq!(counts(&db).await, (before.0 + 8, before.1 + 3));
+
+        // operations are counted like visits of their cities
+        let excluding = Config {
+            excluded_countries: vec!["XA".to_string()],
+            ..Config::default()
+        };
+        let batch = apply_batch(
+            &db,
+            &excluding,
+            &[op("Batch1", 1)],
+            false,
+            1_700_000_000_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(batch.applied, 0);
+        assert_eq!(counts(&db).await, (before.0 + 8, before.1 + 3));
+
+        let case_insensitive = Config {
+            city_case_insensitive: true,
+            ..Config::default()
+        };
+        let new_before = city_count(db.read(), "XA", "BatchNew").await.unwrap();
+        let ops = [op("batch1", 1), op("BatchNew", 2), op("BATCHNEW", 3)];
+        apply_batch(&db, &case_insensitive, &ops, true, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(counts(&db).await, (before.0 + 9, before.1 + 3));
+        assert_eq!(
+            city_count(db.read(), "XA", "BatchNew").await.unwrap(),
+            new_before + 5
+        );
+        assert_eq!(city_count(db.read(), "XA", "BATCHNEW").await.unwrap(), 0);
+
+        // the counter already holds more than a single row
+        let capped = Config {
+            max_cities: Some(1),
+            ..Config::default()
+        };
+        let other_before = city_count(db.read(), "XA", super::OTHER_CITY)
+            .await
+            .unwrap();
+        apply_batch(
+            &db,
+            &capped,
+            &[op("Batch1", 1), op("BatchCapped", 4)],
+            true,
+            1_700_000_000_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(counts(&db).await, (before.0 + 10, before.1 + 3));
+        assert_eq!(city_count(db.read(), "XA", "BatchCapped").await.unwrap(), 0);
+        assert_eq!(
+            city_count(db.read(), "XA", super::OTHER_CITY)
+                .await
+                .unwrap(),
+            other_before + 4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_updated_excludes_countries() {
+        assert!(!Config::default().show_last_updated);
+        let teardown = ["DELETE FROM counter WHERE country = 'QQ'"];
+        with_teardown(test_db(), &teardown, |db| async move {
+            // later than any visit counted by the other tests
+            let latest = 4_000_000_000_000_i64;
+            db.primary
+                .execute(
+                    "INSERT INTO counter(country, city, value, updated_at) VALUES ('QQ', 'Latest', 1, ?)",
+                    libsql::params![latest],
+                )
+                .await
+                .unwrap();
+
+            let shown = super::last_updated(&db, &Config::default()).await.unwrap();
+            assert_eq!(shown, Some(latest));
+            let config = Config {
+                excluded_countries: vec!["QQ".to_string()],
+                ..Config::default()
+            };
+            let shown = super::last_updated(&db, &config).await.unwrap();
+            assert!(shown.map_or(true, |ts| ts < latest), "{shown:?}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_excluded_country() {
+        let db = test_db();
+        let config = || Config {
+            excluded_countries: vec!["XJ".to_string()],
+            ..Config::default()
+        };
+        // counted once while not excluded, so that it has a row to hide
+        super::serve(
+            &visit("t31", "XJ", "Excluded", (3.0, 3.0)),
+            &db,
+            &Config::default(),
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        let before = super::city_count(db.read(), "XJ", "Excluded")
+            .await
+            .unwrap();
+
+        let visit = visit("t31", "XJ", "Excluded", (3.0, 3.0));
+        assert!(!super::should_count(&visit, &config()));
+        let html = super::serve(&visit, &db, &config(), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            super::city_count(db.read(), "XJ", "Excluded")
+                .await
+                .unwrap(),
+            before
+        );
+        assert!(!html.contains("Excluded"), "{html}");
+        assert_eq!(
+            super::scoreboard_query(&config()),
+            "SELECT country, city, value FROM counter WHERE country NOT IN ('XJ')"
+        );
+    }
+
+    #[test]
+    fn test_parse_counter_csv() {
+        use super::{parse_counter_csv, parse_csv};
+        use libsql::Value;
+
+        let records = parse_csv("a,\"b,\"\"c\"\"\"\r\n\"multi\nline\",d\nlast,").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (1, vec!["a".to_string(), "b,\"c\"".to_string()]),
+                (2, vec!["multi\nline".to_string(), "d".to_string()]),
+                (4, vec!["last".to_string(), String::new()]),
+            ]
+        );
+        assert!(parse_csv("\"open").is_err());
+        for csv in ["\"a\"b,c\n", "a,\"b\" \n", "\"a\"\"\"b\"", "\"a\"\r"] {
+            let e = parse_csv(csv).unwrap_err();
+            assert!(e.ends_with("text after a closing quote"), "{csv:?}: {e}");
+        }
+        assert_eq!(
+            parse_csv("\"a\",\"b\"\r\n").unwrap(),
+            vec![(1, vec!["a".to_string(), "b".to_string()])]
+        );
+
+        let rows =
+            parse_counter_csv("country,city,value\nPL,Warsaw,3\nPL,\"Zamość, old town\",1.5\n")
+                .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].1, "Zamość, old town");
+        assert_eq!(rows[1].2, Value::Real(1.5));
+
+        for (csv, error) in [
+            (
+                "PL,Warsaw,3\nPL,Kraków\n",
+                "line 2: expected 3 fields, got 2",
+            ),
+            ("PL,Warsaw,3\npl,Kraków,1\n", "line 2: country must be"),
+            ("PL,Warsaw,-3\n", "line 1: value must be"),
+            ("PL, ,3\n", "line 1: city must not be empty"),
+        ] {
+            let e = parse_counter_csv(csv).unwrap_err();
+            assert!(e.starts_with(error), "{e}");
+        }
+    }
 
     #[tokio::test]
-    async fn test_counter_updated() {
+    async fn test_import_counter_csv() {
+        use super::{city_count, import_counter_csv};
+
         let db = test_db();
+        let csv = "country,city,value\r\nXI,Imported,4\r\nXI,\"Imported, too\",2\r\n";
+        let imported = import_counter_csv(&db, csv, true, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(imported, Ok(2));
+        assert_eq!(city_count(db.read(), "XI", "Imported").await.unwrap(), 4);
+        assert_eq!(
+            city_count(db.read(), "XI", "Imported, too").await.unwrap(),
+            2
+        );
 
-        let payloads = [
-            ("waw", "PL", "Warsaw", (52.1672, 20.9679)),
-            ("waw", "PL", "Warsaw", (52.1672, 20.9679)),
-            ("waw", "PL", "Warsaw", (52.1672, 20.9679)),
-            ("hel", "FI", "Helsinki", (60.3183, 24.9497)),
-            ("hel", "FI", "Helsinki", (60.3183, 24.9497)),
-        ];
+        let imported = import_counter_csv(&db, "XI,Imported,3\n", false, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(imported, Ok(1));
+        assert_eq!(city_count(db.read(), "XI", "Imported").await.unwrap(), 7);
 
-        for p in payloads {
-            super::serve(p.0, p.1, p.2, p.3, &db).await.unwrap();
-        }
+        // a malformed row fails the whole import
+        let csv = "XI,Imported,1\nXI,Imported,many\n";
+        let imported = import_counter_csv(&db, csv, true, 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert!(imported.unwrap_err().starts_with("line 2:"));
+        assert_eq!(city_count(db.read(), "XI", "Imported").await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_country_grouped() {
+        use crate::continents;
+        use crate::list::ListParams;
+
+        let teardown = ["DELETE FROM counter WHERE country = '' AND city = 'Nowhere'"];
+        with_teardown(test_db(), &teardown, |db| async move {
+            // as left by older versions. The counter's key can't be NULL, being
+            // WITHOUT ROWID, so empty names are the ones to expect.
+            db.primary
+                .execute(
+                    "INSERT INTO counter(country, city, value) VALUES ('', 'Nowhere', 4)",
+                    (),
+                )
+                .await
+                .unwrap();
+
+            let list = ListParams {
+                limit: 100,
+                ..ListParams::new(&super::TOP_LIST)
+            };
+            let top = super::top_countries(db.read(), &list).await.unwrap();
+            let top = top.as_array().unwrap();
+            assert!(!top.iter().any(|entry| entry["country"] == ""));
+            let unknown = top
+                .iter()
+                .find(|entry| entry["country"] == super::UNKNOWN_LABEL)
+                .expect("(unknown) country");
+            assert!(unknown["value"].as_i64().unwrap() >= 4);
+
+            let regions = super::region_totals(db.read()).await.unwrap();
+            let unknown: i64 = regions
+                .iter()
+                .filter(|r| r.country == super::UNKNOWN_LABEL)
+                .map(|r| r.value)
+                .sum();
+            assert!(unknown >= 4);
+            assert!(!regions.iter().any(|r| r.country.is_empty()));
+
+            // and its visits go to the unknown continent
+            let totals = super::continent_totals(db.read()).await.unwrap();
+            let unknown = totals
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|entry| entry["continent"] == continents::UNKNOWN)
+                .expect("unknown continent");
+            assert!(unknown["value"].as_i64().unwrap() >= 4);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_request_log_persisted() {
+        use std::rc::Rc;
+
+        let entry = || super::RequestLogEntry {
+            ts: 1_700_000_000_000,
+            path: "/test-request-log".to_string(),
+            colo: "waw".to_string(),
+            country: "PL".to_string(),
+            city: "Warsaw".to_string(),
+        };
+        // off by default, without even opening a connection
+        assert!(!Config::default().request_logging);
+        let write = super::request_log_write(&Config::default(), entry(), || {
+            panic!("opened a connection without REQUEST_LOGGING")
+        });
+        assert!(write.is_none());
+
+        let db = Rc::new(test_db());
+        let config = Config {
+            request_logging: true,
+            ..Config::default()
+        };
+        let write = super::request_log_write(&config, entry(), || Ok(db.clone()));
+        write.expect("request log write").await;
 
         let mut result = db
-            .query("SELECT country, city, value FROM counter", ())
+            .read()
+            .query(
+                "SELECT colo, country, city FROM request_log WHERE path = ? AND ts = ?",
+                libsql::params!["/test-request-log", 1_700_000_000_000i64],
+            )
+            .await
+            .unwrap();
+        let row = result.next().unwrap().expect("request log row");
+        assert_eq!(row.get::<String>(0).unwrap(), "waw");
+        assert_eq!(row.get::<String>(1).unwrap(), "PL");
+        assert_eq!(row.get::<String>(2).unwrap(), "Warsaw");
+    }
+
+    #[tokio::test]
+    async fn test_city_count() {
+        let db = test_db();
+        super::serve(
+            &visit("waw", "PL", "Warsaw", (52.1672, 20.9679)),
+            &db,
+            &Config::default(),
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+
+        let count = super::city_count(db.read(), "PL", "Warsaw").await.unwrap();
+        assert!(count > 0);
+        let count = super::city_count(db.read(), "PL", "Atlantis")
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_coordinates_updated() {
+        let db = test_db();
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+        db.primary
+            .execute("DELETE FROM coordinates WHERE airport = 't24'", ())
+            .await
+            .unwrap();
+        async fn coordinates(db: &super::Db) -> (f64, f64) {
+            let mut result = db
+                .primary
+                .query(
+                    "SELECT lat, long FROM coordinates WHERE airport = 't24'",
+                    (),
+                )
+                .await
+                .unwrap();
+            let row = result.next().unwrap().unwrap();
+            let coordinates = (row.get::<f64>(0).unwrap(), row.get::<f64>(1).unwrap());
+            assert!(result.next().unwrap().is_none());
+            coordinates
+        }
+        let ignore = Config {
+            coord_update: false,
+            ..Config::default()
+        };
+
+        super::record_coordinates(&visit("t24", "XU", "Moved", (10.0, 11.0)), &db, &ignore)
+            .await
+            .unwrap();
+        super::record_coordinates(&visit("t24", "XU", "Moved", (10.5, 11.5)), &db, &ignore)
+            .await
+            .unwrap();
+        assert_eq!(coordinates(&db).await, (10.0, 11.0));
+
+        let update = Config::default();
+        super::record_coordinates(&visit("t24", "XU", "Moved", (10.5, 11.5)), &db, &update)
+            .await
+            .unwrap();
+        assert_eq!(coordinates(&db).await, (10.5, 11.5));
+    }
+
+    #[tokio::test]
+    async fn test_top_cities() {
+        use libsql::params;
+        use worker::Url;
+
+        let top = |query: &str| {
+            let url = Url::parse(&format!("https://example.com/top-cities?{query}")).unwrap();
+            match super::top_list_params(&url, &super::TOP_CITIES_LIST) {
+                Ok(list) => list,
+                Err(_) => panic!("rejected {query}"),
+            }
+        };
+        assert_eq!(top("n=2").limit, 2);
+        assert_eq!(top("n=1000").limit, 100);
+        // an invalid `n` falls back to the default, `?limit=` wins over it
+        assert_eq!(top("n=many").limit, 10);
+        assert_eq!(top("n=2&limit=3").limit, 3);
+
+        let teardown = ["DELETE FROM counter WHERE country = 'XK'"];
+        with_teardown(test_db(), &teardown, |db| async move {
+            // far more visits than any other city of the shared database, so
+            // that these are the top ones whatever else it holds
+            for (city, value) in [("Busy", 3), ("Quiet", 1), ("Middle", 2)] {
+                db.primary
+                    .execute(
+                        "INSERT INTO counter(country, city, value) VALUES ('XK', ?, ?)",
+                        params![city, 1_000_000_000_i64 + value],
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let cities = super::top_cities(db.read(), &top("n=2")).await.unwrap();
+            let names: Vec<_> = cities.iter().map(|e| e.city.as_deref()).collect();
+            assert_eq!(names, [Some("Busy"), Some("Middle")]);
+
+            let cities = super::top_cities(db.read(), &top("limit=100"))
+                .await
+                .unwrap();
+            assert!(cities.windows(2).all(|w| w[0].value >= w[1].value));
+            let names: Vec<_> = cities[..3].iter().map(|e| e.city.as_deref()).collect();
+            assert_eq!(names, [Some("Busy"), Some("Middle"), Some("Quiet")]);
+            assert!(cities[..3].iter().all(|e| e.country == "XK"));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_search_cities() {
+        let db = test_db();
+        for v in [
+            visit("waw", "PL", "Warsaw", (52.1672, 20.9679)),
+            visit("hel", "FI", "Helsinki", (60.3183, 24.9497)),
+        ] {
+            super::serve(&v, &db, &Config::default(), &Default::default())
+                .await
+                .unwrap();
+        }
+
+        let list = super::ListParams::new(&super::SEARCH_LIST);
+        let found = super::search_cities(db.read(), "ars", &list).await.unwrap();
+        assert!(found.iter().any(|e| e.city.as_deref() == Some("Warsaw")));
+        assert!(!found.iter().any(|e| e.city.as_deref() == Some("Helsinki")));
+
+        // wildcards in the query are matched literally
+        let found = super::search_cities(db.read(), "%%", &list).await.unwrap();
+        assert!(found.is_empty());
+        let found = super::search_cities(db.read(), "W_rsaw", &list)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_schema_errors_propagate() {
+        let db = test_db();
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+        // applying the schema again only hits benign errors
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+
+        let result = super::apply_schema(&db.primary, &["CREATE TABLEE broken(x)"]).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_indexes_reapplied_and_used() {
+        let db = test_db();
+        for _ in 0..2 {
+            super::apply_schema(&db.primary, super::SCHEMA)
+                .await
+                .unwrap();
+        }
+
+        let plan = query_plan(
+            &db.primary,
+            "SELECT country, SUM(value) AS value FROM counter GROUP BY country ORDER BY value DESC LIMIT 10",
+        )
+        .await;
+        assert!(plan.contains("counter_country_idx"), "{plan}");
+        let plan = query_plan(&db.primary, "SELECT * FROM visits WHERE ts > 0").await;
+        assert!(plan.contains("visits_ts_idx"), "{plan}");
+    }
+
+    async fn query_plan(db: &Connection<CloudflareSender>, sql: &str) -> String {
+        let mut rows = db
+            .query(&format!("EXPLAIN QUERY PLAN {sql}"), ())
+            .await
+            .unwrap();
+        let mut plan = String::new();
+        while let Some(row) = rows.next().unwrap() {
+            plan.push_str(&row.get::<String>(3).unwrap());
+            plan.push('\n');
+        }
+        plan
+    }
+
+    #[tokio::test]
+    async fn test_users_version_bumped() {
+        let db = test_db();
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+
+        let before = super::table_version(db.read(), "users").await.unwrap();
+        super::bump_table_version(&db.primary, "users", 1_700_000_000_000)
             .await
             .unwrap();
-        let columns: Vec<_> = (0..result.column_count())
-            .map(|c| result.column_name(c).unwrap_or(""))
+        let after = super::table_version(db.read(), "users").await.unwrap();
+
+        let etag = after.etag("users");
+        assert_ne!(before.etag("users"), etag);
+        assert!(super::is_not_modified(
+            Some(&etag),
+            None,
+            &etag,
+            after.updated_at
+        ));
+    }
+
+    #[test]
+    fn test_table_drift() {
+        use super::table_drift;
+
+        let expected = [("country", "TEXT"), ("city", "TEXT"), ("value", "")];
+        let actual = |columns: &[(&str, &str)]| -> Vec<(String, String)> {
+            columns
+                .iter()
+                .map(|(n, t)| (n.to_string(), t.to_string()))
+                .collect()
+        };
+        assert!(table_drift("counter", &expected, &actual(&expected)).is_empty());
+
+        let drift = table_drift(
+            "counter",
+            &expected,
+            &actual(&[("country", "text"), ("value", "INTEGER"), ("extra", "BLOB")]),
+        );
+        let problems: Vec<(Option<&str>, &str)> = drift
+            .iter()
+            .map(|d| (d.column.as_deref(), d.problem.as_str()))
             .collect();
+        assert_eq!(
+            problems,
+            [
+                (Some("city"), "missing column"),
+                (Some("value"), "type is \"INTEGER\", expected \"\""),
+                (Some("extra"), "unexpected column"),
+            ]
+        );
+
+        let drift = table_drift("counter", &expected, &[]);
+        assert_eq!(drift.len(), 1);
+        assert_eq!(
+            (drift[0].column.as_ref(), drift[0].problem.as_str()),
+            (None, "missing table")
+        );
+    }
+
+    #[test]
+    fn test_ping() {
+        use super::ping;
+
+        assert_eq!(ping("GET", "/ping"), Some((200, "pong")));
+        assert_eq!(ping("GET", "/"), None);
+        assert_eq!(ping("POST", "/ping"), None);
+    }
+
+    #[test]
+    fn test_users_variants() {
+        use super::{users_format, users_variant, ListParams, USERS_LIST};
+
+        let html = users_format(
+            None,
+            Some("text/html,application/xhtml+xml;q=0.9,*/*;q=0.8"),
+        );
+        let json = users_format(None, Some("application/json"));
+        assert_eq!((html.clone(), json.clone()), (Ok("table"), Ok("json")));
+        assert_eq!(
+            users_format(None, Some("text/csv; charset=utf-8")),
+            Ok("csv")
+        );
+        assert_eq!(users_format(None, Some("*/*")), Ok("json"));
+        assert_eq!(users_format(None, None), Ok("json"));
+        // an explicit format wins over Accept
+        let csv = "csv".to_string();
+        assert_eq!(users_format(Some(&csv), Some("text/html")), Ok("csv"));
+        assert!(users_format(Some(&"xml".to_string()), None).is_err());
+
+        let first = ListParams::new(&USERS_LIST);
+        let html = users_variant(html.unwrap(), &first);
+        let json = users_variant(json.unwrap(), &first);
+        assert_ne!(html, json);
+        let second = ListParams {
+            offset: 100,
+            ..first.clone()
+        };
+        assert_ne!(json, users_variant("json", &second));
+    }
+
+    #[test]
+    fn test_inspectable_table() {
+        use super::inspectable_table;
+
+        let config = Config::default();
+        assert_eq!(inspectable_table("counter", &config), Some("counter"));
+        assert_eq!(
+            inspectable_table("example_users", &config),
+            Some("example_users")
+        );
+        for name in [
+            "seen",
+            "sqlite_master",
+            "counter)",
+            "counter; DROP TABLE counter",
+            "",
+        ] {
+            assert_eq!(inspectable_table(name, &config), None, "{name}");
+        }
+
+        let config = Config {
+            users_table: "members".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(inspectable_table("members", &config), Some("members"));
+        assert_eq!(inspectable_table("example_users", &config), None);
+    }
+
+    #[tokio::test]
+    async fn test_schema_matches_expected_columns() {
+        let db = test_db();
+        super::apply_schema(&db.primary, super::SCHEMA)
+            .await
+            .unwrap();
+        let drift = super::schema_drift(&db.primary).await.unwrap();
+        assert!(drift.is_empty(), "{drift:?}");
+    }
+
+    #[tokio::test]
+    async fn test_repair_coordinates() {
+        use libsql::{params, Value};
+
+        let db = test_db();
+        // An unrepaired table of its own, rather than dropping the index of the
+        // shared one. Without declared types, text coordinates stay text.
+        let table = "test_repair_coordinates";
+        for statement in [
+            format!("DROP TABLE IF EXISTS {table}"),
+            format!("CREATE TABLE {table}(lat, long, airport TEXT, timezone TEXT, visits INTEGER DEFAULT 0)"),
+        ] {
+            db.primary.execute(&statement, ()).await.unwrap();
+        }
+        let insert = format!("INSERT INTO {table}(lat, long, airport, visits) VALUES (?, ?, ?, ?)");
+        for (lat, long, airport, visits) in [
+            (Value::Integer(52), Value::Integer(21), "t21", 3),
+            (Value::Real(52.25), Value::Real(21.5), "t21", 3),
+            (Value::Text("53".into()), Value::Integer(22), "t21", 1),
+            (
+                Value::Text("-7.5".into()),
+                Value::Text("8".into()),
+                "t22",
+                0,
+            ),
+        ] {
+            db.primary
+                .execute(&insert, params![lat, long, airport, visits])
+                .await
+                .unwrap();
+        }
+
+        let (before, after) = super::repair_coordinates_in(&db.primary, table)
+            .await
+            .unwrap();
+        assert_eq!((before, after), (4, 2));
+        let mut result = db
+            .primary
+            .query(
+                &format!("SELECT airport, lat, long, visits FROM {table} ORDER BY airport"),
+                (),
+            )
+            .await
+            .unwrap();
+        let row = result.next().unwrap().unwrap();
+        assert_eq!(row.get::<String>(0).unwrap(), "t21");
+        assert_eq!(row.get::<f64>(1).unwrap(), 52.25);
+        assert_eq!(row.get::<f64>(2).unwrap(), 21.5);
+        assert_eq!(row.get::<i64>(3).unwrap(), 3);
+        let row = result.next().unwrap().unwrap();
+        assert_eq!(row.get::<String>(0).unwrap(), "t22");
+        assert_eq!(row.get_value(1).unwrap(), Value::Real(-7.5));
+        assert_eq!(row.get_value(2).unwrap(), Value::Real(8.0));
+        assert!(result.next().unwrap().is_none());
+
+        // rerunning has nothing left to repair, and airports can't get another row
+        let again = db
+            .primary
+            .execute(&insert, params![1.0, 2.0, "t21", 1])
+            .await;
+        assert!(again.is_err());
+        let (before, after) = super::repair_coordinates_in(&db.primary, table)
+            .await
+            .unwrap();
+        assert_eq!((before, after), (2, 2));
+
+        db.primary
+            .execute(&format!("DROP TABLE {table}"), ())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_city_policies() {
+        use crate::config::EmptyCity;
+
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'QM'",
+            "DELETE FROM coordinates WHERE airport = 't38'",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let nowhere = visit("t38", "QM", "", (-7.5, -7.5));
+            for empty_city in [EmptyCity::Skip, EmptyCity::Bucket, EmptyCity::Keep] {
+                let config = Config {
+                    empty_city,
+                    ..Config::default()
+                };
+                super::serve(&nowhere, &db, &config, &Default::default())
+                    .await
+                    .unwrap();
+            }
+
+            let mut rows = db
+                .read()
+                .query(
+                    "SELECT city, value FROM counter WHERE country = 'QM' ORDER BY city",
+                    (),
+                )
+                .await
+                .unwrap();
+            let mut counted = Vec::new();
+            while let Some(row) = rows.next().unwrap() {
+                counted.push((row.get::<String>(0).unwrap(), row.get::<i64>(1).unwrap()));
+            }
+            // skipped once, then counted once in each row
+            assert_eq!(
+                counted,
+                [(String::new(), 1), (super::UNKNOWN_CITY.to_string(), 1)]
+            );
+
+            let skip = Config {
+                empty_city: EmptyCity::Skip,
+                ..Config::default()
+            };
+            assert!(super::normalize_key(&nowhere, &skip).is_none());
+            let whoami = super::whoami(&nowhere, &db, &skip).await.unwrap();
+            assert!(!whoami.counted);
+            let whoami = super::whoami(&nowhere, &db, &Config::default())
+                .await
+                .unwrap();
+            assert!(whoami.counted);
+            assert_eq!(whoami.city, super::UNKNOWN_CITY);
+
+            let warsaw = visit("t38", "QM", "Warsaw", (-7.5, -7.5));
+            let key = super::normalize_key(&warsaw, &skip).unwrap();
+            assert_eq!(key.city, "Warsaw");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let teardown = [
+            "DELETE FROM visits WHERE airport = 't37'",
+            "DELETE FROM request_log WHERE path = '/t37'",
+            "DELETE FROM versions WHERE name = 'visits_pruned'",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            // Far older than the rows of other tests, which are left alone
+            for ts in [1, 2, 3, 4, 5, 2_000, 3_000] {
+                db.primary
+                    .execute(
+                        "INSERT INTO visits VALUES (?, 't37', 'XE', 'Pruned')",
+                        libsql::params![ts],
+                    )
+                    .await
+                    .unwrap();
+                db.primary
+                    .execute(
+                        "INSERT INTO request_log VALUES (?, '/t37', 't37', 'XE', 'Pruned')",
+                        libsql::params![ts],
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            // batches of 2 take a few statements per table, more than one allows
+            let (deleted, more) = super::prune(&db, 1_000, 2, 1, 1_700_000_000_000)
+                .await
+                .unwrap();
+            assert!(more);
+            assert_eq!(deleted["visits"].as_u64(), Some(2));
+            assert_eq!(deleted["request_log"].as_u64(), Some(0));
+            let pruned = super::table_version(&db.primary, super::PRUNED_VISITS)
+                .await
+                .unwrap();
+            assert!(pruned.version > 0);
+
+            let (deleted, more) = super::prune(&db, 1_000, 2, 100, 1_700_000_000_000)
+                .await
+                .unwrap();
+            assert!(!more);
+            assert!(deleted["visits"].as_u64().unwrap() >= 3);
+            assert!(deleted["request_log"].as_u64().unwrap() >= 5);
+
+            async fn left(db: &Db, query: &str) -> Vec<i64> {
+                let mut rows = db.read().query(query, ()).await.unwrap();
+                let mut ts = Vec::new();
+                while let Some(row) = rows.next().unwrap() {
+                    ts.push(row.get(0).unwrap());
+                }
+                ts
+            }
+            assert_eq!(
+                left(
+                    &db,
+                    "SELECT ts FROM visits WHERE airport = 't37' ORDER BY ts"
+                )
+                .await,
+                [2_000, 3_000]
+            );
+            assert_eq!(
+                left(
+                    &db,
+                    "SELECT ts FROM request_log WHERE path = '/t37' ORDER BY ts"
+                )
+                .await,
+                [2_000, 3_000]
+            );
+        })
+        .await;
+    }
 
-        assert_eq!(columns, vec!["country", "city", "value"]);
-        while let Some(row) = result.next().unwrap() {
-            let city: String = row.get(1).unwrap();
-            match city.as_str() {
-                "Warsaw" => assert_eq!(row.get::<i64>(2).unwrap(), 3),
-                "Helsinki" => assert_eq!(row.get::<i64>(2).unwrap(), 2),
-                other => panic!("Unknown city: {:?}", other),
+    #[tokio::test]
+    async fn test_recompute_counter() {
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'XP'",
+            "DELETE FROM visits WHERE country = 'XP'",
+            "DELETE FROM coordinates WHERE airport = 't36'",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let config = Config {
+                visit_logging: true,
+                ..Config::default()
+            };
+            for city in ["Logged", "Logged", "Logged", "Other"] {
+                super::count_visit(&visit("t36", "XP", city, (-3.5, -3.5)), &db, &config)
+                    .await
+                    .unwrap();
             }
+            // drift away from the log: a wrong count and a city never visited
+            db.primary
+                .execute(
+                    "UPDATE counter SET value = 40 WHERE country = 'XP' AND city = 'Logged'",
+                    (),
+                )
+                .await
+                .unwrap();
+            db.primary
+                .execute(
+                    "INSERT INTO counter(country, city, value) VALUES ('XP', 'Unlogged', 5)",
+                    (),
+                )
+                .await
+                .unwrap();
+
+            let (before, after) = super::recompute_counter(&db, Some("XP")).await.unwrap();
+            assert_eq!((before, after), (3, 2));
+            assert_eq!(
+                super::city_count(db.read(), "XP", "Logged").await.unwrap(),
+                3
+            );
+            assert_eq!(
+                super::city_count(db.read(), "XP", "Other").await.unwrap(),
+                1
+            );
+            assert_eq!(
+                super::city_count(db.read(), "XP", "Unlogged")
+                    .await
+                    .unwrap(),
+                0
+            );
+
+            // nothing drifted since
+            let (before, after) = super::recompute_counter(&db, Some("XP")).await.unwrap();
+            assert_eq!(before, after);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_recompute_refused_after_unlogged_counts() {
+        use super::{table_version, UNLOGGED_COUNTS};
+        use crate::models::BatchOp;
+
+        let teardown = ["DELETE FROM counter WHERE country = 'QR'"];
+        with_teardown(test_db(), &teardown, |db| async move {
+            let now_ms = 1_700_000_000_000;
+            // other tests may bump it too, so it's only compared to before
+            let version = || async {
+                table_version(&db.primary, UNLOGGED_COUNTS)
+                    .await
+                    .unwrap()
+                    .version
+            };
+
+            let before = version().await;
+            super::import_counter_csv(&db, "QR,Restored,5\n", false, now_ms)
+                .await
+                .unwrap()
+                .unwrap();
+            let after_import = version().await;
+            assert!(after_import > before);
+
+            let op = BatchOp {
+                country: "QR".to_string(),
+                city: "Batched".to_string(),
+                by: 3,
+            };
+            let batch = super::apply_batch(&db, &Config::default(), &[op], true, now_ms)
+                .await
+                .unwrap();
+            assert_eq!(batch.applied, 1);
+            let after_batch = version().await;
+            assert!(after_batch > after_import);
+
+            assert_eq!(
+                super::delete_city(&db, "QR", "Restored", now_ms)
+                    .await
+                    .unwrap(),
+                1
+            );
+            assert!(version().await > after_batch);
+
+            // the visits log doesn't hold those counts, so they can't be rebuilt
+            let refusal = super::recompute_refusal(&db.primary).await.unwrap();
+            assert!(refusal.is_some());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_csv_lines_streamed() {
+        const ROWS: i64 = 2000;
+        let db = test_db();
+        for statement in [
+            "DROP TABLE IF EXISTS csv_export_test",
+            "CREATE TABLE csv_export_test(id INTEGER, name TEXT, note TEXT)",
+        ] {
+            db.primary.execute(statement, ()).await.unwrap();
         }
+        db.primary
+            .execute(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+                INSERT INTO csv_export_test SELECT i, 'user ' || i, 'says \"hi\", twice' FROM n",
+                libsql::params![ROWS],
+            )
+            .await
+            .unwrap();
+
+        let rows = db
+            .primary
+            .query("SELECT * FROM csv_export_test ORDER BY id", ())
+            .await
+            .unwrap();
+        let lines: Vec<String> = super::CsvLines::new(rows)
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(lines.len() as i64, ROWS + 1);
+        assert_eq!(lines[0], "id,name,note\r\n");
+        assert_eq!(lines[1], "1,user 1,\"says \"\"hi\"\", twice\"\r\n");
+        assert!(lines[ROWS as usize].starts_with(&format!("{ROWS},user {ROWS},")));
+
+        // the same as the buffered CSV
+        let rows = db
+            .primary
+            .query("SELECT * FROM csv_export_test ORDER BY id", ())
+            .await
+            .unwrap();
+        let csv = super::ResultSet::from_rows(rows).unwrap().to_csv();
+        assert_eq!(csv, lines.concat());
+    }
+
+    #[tokio::test]
+    async fn test_delete_country() {
+        let db = test_db();
+        for (country, city) in [("XE", "Gone"), ("XE", "Also gone"), ("XF", "Kept")] {
+            super::serve(
+                &visit("t19", country, city, (8.0, 8.0)),
+                &db,
+                &Config::default(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        let deleted = super::delete_country(&db, "XE", 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        assert_eq!(
+            super::delete_country(&db, "XE", 1_700_000_000_000)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(super::city_count(db.read(), "XE", "Gone").await.unwrap(), 0);
+        assert!(super::city_count(db.read(), "XF", "Kept").await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_city() {
+        use super::{city_count, delete_city};
+
+        let teardown = [
+            "DELETE FROM counter WHERE country = 'QN'",
+            "DELETE FROM coordinates WHERE airport IN ('t40', 't41')",
+        ];
+        with_teardown(test_db(), &teardown, |db| async move {
+            for v in [
+                visit("t40", "QN", "Deletable", (4.0, 4.0)),
+                visit("t41", "QN", "Kept", (4.5, 4.5)),
+            ] {
+                super::serve(&v, &db, &Config::default(), &Default::default())
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(city_count(db.read(), "QN", "Deletable").await.unwrap(), 1);
+
+            let now_ms = 1_700_000_000_000;
+            assert_eq!(
+                delete_city(&db, "QN", "Deletable", now_ms).await.unwrap(),
+                1
+            );
+            assert_eq!(
+                delete_city(&db, "QN", "Deletable", now_ms).await.unwrap(),
+                0
+            );
+
+            let html = super::serve(
+                &visit("t41", "QN", "Kept", (4.5, 4.5)),
+                &db,
+                &Config::default(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            assert!(!html.contains("Deletable"));
+            assert_eq!(city_count(db.read(), "QN", "Deletable").await.unwrap(), 0);
+            assert_eq!(city_count(db.read(), "QN", "Kept").await.unwrap(), 2);
+        })
+        .await;
     }
 }
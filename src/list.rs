@@ -0,0 +1,175 @@
+use worker::{Response, Result, Url};
+
+// Limit of the endpoints returning every row unless asked for fewer
+pub const UNLIMITED: u32 = u32::MAX;
+
+// How a list endpoint may be paged and sorted
+pub struct ListSpec {
+    // UNLIMITED for endpoints that return every row by default
+    pub default_limit: u32,
+    pub max_limit: u32,
+    // Accepted `?order=` values and the ORDER BY clause each one stands for.
+    // The first one is the default. Empty when the order can't be chosen.
+    pub orders: &'static [(&'static str, &'static str)],
+}
+
+// Paging and sorting of a list endpoint, from the `?limit=`, `?offset=` and
+// `?order=` query parameters. Limits out of range are clamped, while values
+// that aren't numbers or unknown orders are rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListParams {
+    pub limit: u32,
+    pub offset: u32,
+    pub order: Option<String>,
+}
+
+impl ListParams {
+    // The endpoint's defaults, as used when no parameter is given
+    pub fn new(spec: &ListSpec) -> Self {
+        ListParams {
+            limit: spec.default_limit,
+            offset: 0,
+            order: None,
+        }
+    }
+
+    // The parameters of `url`. Invalid ones are rejected with a 400 response,
+    // which is returned as is.
+    pub fn from_query(url: &Url, spec: &ListSpec) -> std::result::Result<Self, Result<Response>> {
+        Self::parse(url, spec).map_err(|message| crate::json_error(message, 400))
+    }
+
+    fn parse(url: &Url, spec: &ListSpec) -> std::result::Result<Self, String> {
+        let mut params = ListParams::new(spec);
+        if let Some(limit) = query_pair(url, "limit") {
+            params.limit = parse_number("limit", &limit)?.clamp(1, spec.max_limit);
+        }
+        if let Some(offset) = query_pair(url, "offset") {
+            params.offset = parse_number("offset", &offset)?;
+        }
+        if let Some(order) = query_pair(url, "order") {
+            if !spec.orders.iter().any(|(name, _)| *name == order) {
+                return Err(if spec.orders.is_empty() {
+                    "order is not supported here".to_string()
+                } else {
+                    let names: Vec<&str> = spec.orders.iter().map(|(name, _)| *name).collect();
+                    format!("order must be one of {}", names.join(", "))
+                });
+            }
+            params.order = Some(order);
+        }
+        Ok(params)
+    }
+
+    // ORDER BY clause of the requested order, or of the endpoint's default one
+    pub fn order_by(&self, spec: &ListSpec) -> Option<&'static str> {
+        let order = match &self.order {
+            Some(order) => spec.orders.iter().find(|(name, _)| name == order),
+            None => spec.orders.first(),
+        };
+        order.map(|(_, clause)| *clause)
+    }
+
+    // Identifies the page, e.g. for cache keys
+    pub fn key(&self) -> String {
+        format!(
+            "limit={}&offset={}&order={}",
+            self.limit,
+            self.offset,
+            self.order.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+// First value of the `name` query parameter, like `parse_query` keeps
+pub fn query_pair(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn parse_number(name: &str, value: &str) -> std::result::Result<u32, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{name} must be a non-negative integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ListParams, ListSpec, UNLIMITED};
+    use worker::Url;
+
+    const SPEC: ListSpec = ListSpec {
+        default_limit: 10,
+        max_limit: 100,
+        orders: &[("value", "value DESC"), ("name", "name")],
+    };
+
+    fn parse(query: &str, spec: &ListSpec) -> Result<ListParams, String> {
+        let url = Url::parse(&format!("https://example.com/top?{query}")).unwrap();
+        ListParams::parse(&url, spec)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let params = parse("", &SPEC).unwrap();
+        assert_eq!(params, ListParams::new(&SPEC));
+        assert_eq!(params.limit, 10);
+        assert_eq!(params.offset, 0);
+        assert_eq!(params.order_by(&SPEC), Some("value DESC"));
+    }
+
+    #[test]
+    fn test_limit_clamped() {
+        assert_eq!(parse("limit=5", &SPEC).unwrap().limit, 5);
+        assert_eq!(parse("limit=0", &SPEC).unwrap().limit, 1);
+        assert_eq!(parse("limit=1000", &SPEC).unwrap().limit, 100);
+        assert_eq!(parse("limit=%207%20", &SPEC).unwrap().limit, 7);
+        // the first of repeated parameters is used
+        assert_eq!(parse("limit=5&limit=6", &SPEC).unwrap().limit, 5);
+    }
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let all = ListSpec {
+            default_limit: UNLIMITED,
+            max_limit: UNLIMITED,
+            orders: &[],
+        };
+        assert_eq!(parse("", &all).unwrap().limit, UNLIMITED);
+        assert_eq!(parse("limit=5000", &all).unwrap().limit, 5000);
+    }
+
+    #[test]
+    fn test_invalid_numbers_rejected() {
+        assert!(parse("limit=abc", &SPEC).is_err());
+        assert!(parse("limit=-1", &SPEC).is_err());
+        assert!(parse("limit=", &SPEC).is_err());
+        assert!(parse("offset=1.5", &SPEC).is_err());
+        assert!(parse("offset=99999999999", &SPEC).is_err());
+        assert_eq!(parse("offset=40", &SPEC).unwrap().offset, 40);
+    }
+
+    #[test]
+    fn test_order_validated() {
+        let params = parse("order=name", &SPEC).unwrap();
+        assert_eq!(params.order_by(&SPEC), Some("name"));
+        let err = parse("order=name;%20drop%20table%20x", &SPEC).unwrap_err();
+        assert_eq!(err, "order must be one of value, name");
+
+        let unordered = ListSpec {
+            orders: &[],
+            ..SPEC
+        };
+        assert_eq!(ListParams::new(&unordered).order_by(&unordered), None);
+        assert!(parse("order=value", &unordered).is_err());
+    }
+
+    #[test]
+    fn test_key_distinguishes_pages() {
+        let first = parse("", &SPEC).unwrap();
+        let second = parse("offset=10", &SPEC).unwrap();
+        assert_ne!(first.key(), second.key());
+    }
+}
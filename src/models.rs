@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+// Shapes of the JSON returned by the API endpoints, and of the one `/batch`
+// accepts
+
+// A row of the counter table. `city` is omitted for per-country aggregates.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CounterEntry {
+    pub country: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    pub value: i64,
+}
+
+// Response of `/counter/compare`: the totals of both countries, and the
+// one with more visits, which is null on a tie
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompareResponse {
+    pub a: CounterEntry,
+    pub b: CounterEntry,
+    pub leader: Option<String>,
+}
+
+// A counter row returned by `/counter/delta`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CounterDeltaEntry {
+    pub country: String,
+    pub city: String,
+    pub value: i64,
+    // Time of the last visit, in milliseconds since the Unix epoch
+    pub updated_at: i64,
+}
+
+// A `/batch` operation: add `by` to the count of a city
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BatchOp {
+    pub country: String,
+    pub city: String,
+    pub by: i64,
+}
+
+// Outcome of a `/batch` operation, with why it wasn't applied if it wasn't
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchOpResult {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Response of `/batch`: the number of operations applied and the outcome of
+// each one, in the order they were given
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchResponse {
+    pub applied: usize,
+    pub results: Vec<BatchOpResult>,
+}
+
+// Rows changed since the requested time, and the `since` to pass next time
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CounterDelta {
+    pub rows: Vec<CounterDeltaEntry>,
+    pub cursor: i64,
+}
+
+// Airport location, as stored in the coordinates table
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AirportEntry {
+    pub airport: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub timezone: Option<String>,
+    pub visits: i64,
+    // Placed at the capital of the visitor's country (see FALLBACK_COORDS)
+    pub approximate: bool,
+}
+
+// Visits of all countries of a continent, as returned by `/continents`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContinentEntry {
+    pub continent: String,
+    pub value: i64,
+}
+
+// Visits of all cities of a country's region, as returned by `/regions`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegionEntry {
+    pub country: String,
+    // `None` for cities whose region is unknown
+    pub region: Option<String>,
+    pub value: i64,
+}
+
+// Where `/whoami` says a visit of the caller would be counted
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WhoAmIResponse {
+    pub airport: String,
+    pub country: String,
+    // City of the counter row, which is `(other)` once MAX_CITIES is reached
+    pub city: String,
+    pub region: Option<String>,
+    pub lat: f32,
+    pub lon: f32,
+    pub timezone: Option<String>,
+    // Time of the request in the caller's timezone, e.g. `14:30`, or `12:30 UTC`
+    // when the timezone is unknown
+    pub local_time: String,
+    // Whether a visit would be counted at all
+    pub counted: bool,
+}
+
+// A difference between a live table and what SCHEMA creates, reported by
+// `/admin/schema`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SchemaDrift {
+    pub table: String,
+    // `None` when the whole table is missing
+    pub column: Option<String>,
+    pub problem: String,
+}
+
+// Totals returned by `/stats`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsResponse {
+    pub countries: i64,
+    pub cities: i64,
+    pub visits: i64,
+    pub avg_visits_per_city: f64,
+    pub avg_visits_per_country: f64,
+}
+
+// A registered route, as listed by `/api`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteEntry<'a> {
+    pub path: &'a str,
+    pub method: &'a str,
+    pub description: &'a str,
+}
+
+// Body of every JSON error response
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_stats_response_fields() {
+        let stats = StatsResponse {
+            countries: 2,
+            cities: 3,
+            visits: 10,
+            avg_visits_per_city: 3.33,
+            avg_visits_per_country: 5.0,
+        };
+        assert_eq!(
+            serde_json::to_value(stats).unwrap(),
+            json!({
+                "countries": 2,
+                "cities": 3,
+                "visits": 10,
+                "avg_visits_per_city": 3.33,
+                "avg_visits_per_country": 5.0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_counter_entry_omits_missing_city() {
+        let entry = CounterEntry {
+            country: "PL".to_string(),
+            city: None,
+            value: 3,
+        };
+        assert_eq!(
+            serde_json::to_value(entry).unwrap(),
+            json!({ "country": "PL", "value": 3 })
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Calendar helpers for timestamps in milliseconds since the Unix epoch (UTC),
+// so that the worker doesn't need a date/time dependency. Timezones are left
+// to the JavaScript runtime, which has a timezone database already.
+
+use worker::js_sys::{global, Array, Date, Function, Object, Reflect};
+use worker::wasm_bindgen::{JsCast, JsValue};
+
+pub const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// (year, month 1..=12, day 1..=31) of the given day since the epoch
+// (Howard Hinnant's `civil_from_days`)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Day since the epoch of the given date (Howard Hinnant's `days_from_civil`)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Format as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(ts_ms: i64) -> String {
+    let days = ts_ms.div_euclid(MS_PER_DAY);
+    let secs = ts_ms.rem_euclid(MS_PER_DAY) / 1000;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Date and time in UTC, e.g. `2024-02-29 12:34 UTC`
+pub fn fmt_utc(ts_ms: i64) -> String {
+    let (year, month, day) = civil_from_days(ts_ms.div_euclid(MS_PER_DAY));
+    let mins = ts_ms.rem_euclid(MS_PER_DAY) / (60 * 1000);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        mins / 60,
+        mins % 60
+    )
+}
+
+// Time of day, e.g. `14:30`, in the timezone `offset_min` minutes ahead of
+// UTC. Without an offset, it's the UTC time, e.g. `12:30 UTC`.
+pub fn fmt_local_time(ts_ms: i64, offset_min: Option<i32>) -> String {
+    let local_ms = ts_ms + i64::from(offset_min.unwrap_or_default()) * 60 * 1000;
+    let mins = local_ms.rem_euclid(MS_PER_DAY) / (60 * 1000);
+    let time = format!("{:02}:{:02}", mins / 60, mins % 60);
+    match offset_min {
+        Some(_) => time,
+        None => format!("{time} UTC"),
+    }
+}
+
+// Local date and time at `ts_ms` in the IANA `timezone`, e.g. `Europe/Warsaw`,
+// as [year, month, day, hour, minute, second]. They come from the runtime's
+// `Intl.DateTimeFormat`, so this only works in Workers. None for a timezone
+// it doesn't know.
+pub fn local_fields(timezone: &str, ts_ms: i64) -> Option<[i64; 6]> {
+    const FIELDS: [&str; 6] = ["year", "month", "day", "hour", "minute", "second"];
+
+    let options = Object::new();
+    let numeric = FIELDS.map(|field| (field, "numeric"));
+    for (name, value) in [("timeZone", timezone), ("hourCycle", "h23")]
+        .iter()
+        .chain(&numeric)
+    {
+        Reflect::set(
+            &options,
+            &JsValue::from_str(name),
+            &JsValue::from_str(value),
+        )
+        .ok()?;
+    }
+    let intl = Reflect::get(&global(), &JsValue::from_str("Intl")).ok()?;
+    let constructor: Function = Reflect::get(&intl, &JsValue::from_str("DateTimeFormat"))
+        .ok()?
+        .dyn_into()
+        .ok()?;
+    // Constructed through Reflect, which returns the RangeError thrown for an
+    // unknown timezone rather than aborting
+    let locales = Array::of1(&JsValue::from_str("en-US"));
+    let format = Reflect::construct(&constructor, &Array::of2(&locales, &options)).ok()?;
+    let format_to_parts: Function = Reflect::get(&format, &JsValue::from_str("formatToParts"))
+        .ok()?
+        .dyn_into()
+        .ok()?;
+    let date = Date::new(&JsValue::from_f64(ts_ms as f64));
+    let parts: Array = format_to_parts
+        .call1(&format, &date)
+        .ok()?
+        .dyn_into()
+        .ok()?;
+
+    let mut fields: [Option<i64>; 6] = [None; 6];
+    for part in parts.iter() {
+        let kind = Reflect::get(&part, &JsValue::from_str("type"))
+            .ok()?
+            .as_string()?;
+        let Some(i) = FIELDS.iter().position(|field| *field == kind) else {
+            continue;
+        };
+        let value = Reflect::get(&part, &JsValue::from_str("value"))
+            .ok()?
+            .as_string()?;
+        fields[i] = value.parse().ok();
+    }
+    let [year, month, day, hour, minute, second] = fields;
+    Some([year?, month?, day?, hour?, minute?, second?])
+}
+
+// Minutes a timezone is ahead of UTC, from its local date and time at `ts_ms`
+// (see `local_fields`)
+pub fn utc_offset_min(ts_ms: i64, local: [i64; 6]) -> Option<i32> {
+    let [year, month, day, hour, minute, second] = local;
+    let month = u32::try_from(month).ok().filter(|m| (1..=12).contains(m))?;
+    let day = u32::try_from(day).ok().filter(|d| (1..=31).contains(d))?;
+    let local_ms = days_from_civil(year, month, day) * MS_PER_DAY
+        + ((hour * 60 + minute) * 60 + second) * 1000;
+    let offset_ms = local_ms - ts_ms.div_euclid(1000) * 1000;
+    i32::try_from(offset_ms.div_euclid(60 * 1000)).ok()
+}
+
+// Parse an HTTP date in the format produced by `http_date`
+pub fn parse_http_date(date: &str) -> Option<i64> {
+    let (_, rest) = date.split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+    let day: u32 = parts[0].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[1])? as u32 + 1;
+    let year: i64 = parts[2].parse().ok()?;
+    let time: Vec<i64> = parts[3]
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    if time.len() != 3 || !(1..=31).contains(&day) {
+        return None;
+    }
+    let secs = time[0] * 3600 + time[1] * 60 + time[2];
+    Some(days_from_civil(year, month, day) * MS_PER_DAY + secs * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fmt_local_time, fmt_utc, http_date, parse_http_date, utc_offset_min};
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(784_111_777_000), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            http_date(1_709_210_096_789),
+            "Thu, 29 Feb 2024 12:34:56 GMT"
+        );
+    }
+
+    #[test]
+    fn test_fmt_utc() {
+        assert_eq!(fmt_utc(0), "1970-01-01 00:00 UTC");
+        assert_eq!(fmt_utc(1_709_210_096_789), "2024-02-29 12:34 UTC");
+    }
+
+    #[test]
+    fn test_fmt_local_time() {
+        // Thu, 29 Feb 2024 12:34:56 GMT
+        let ts = 1_709_210_096_789;
+        assert_eq!(fmt_local_time(ts, None), "12:34 UTC");
+        assert_eq!(fmt_local_time(ts, Some(0)), "12:34");
+        assert_eq!(fmt_local_time(ts, Some(120)), "14:34");
+        assert_eq!(fmt_local_time(ts, Some(330)), "18:04");
+        assert_eq!(fmt_local_time(ts, Some(-300)), "07:34");
+        // offsets wrap around midnight either way
+        assert_eq!(fmt_local_time(ts, Some(-780)), "23:34");
+        assert_eq!(fmt_local_time(ts, Some(720)), "00:34");
+        assert_eq!(fmt_local_time(0, Some(-60)), "23:00");
+    }
+
+    #[test]
+    fn test_utc_offset_min() {
+        // Tue, 14 Nov 2023 22:13:20 GMT
+        let ts = 1_700_000_000_000;
+        assert_eq!(utc_offset_min(ts, [2023, 11, 14, 22, 13, 20]), Some(0));
+        assert_eq!(utc_offset_min(ts, [2023, 11, 14, 23, 13, 20]), Some(60));
+        assert_eq!(utc_offset_min(ts, [2023, 11, 14, 17, 13, 20]), Some(-300));
+        // local dates on either side of UTC's
+        assert_eq!(utc_offset_min(ts, [2023, 11, 15, 3, 58, 20]), Some(345));
+        assert_eq!(utc_offset_min(ts, [2023, 11, 14, 11, 13, 20]), Some(-660));
+        assert_eq!(utc_offset_min(0, [1969, 12, 31, 21, 0, 0]), Some(-180));
+        assert_eq!(utc_offset_min(ts, [2023, 13, 14, 22, 13, 20]), None);
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777_000)
+        );
+        let ts = 1_709_210_096_000;
+        assert_eq!(parse_http_date(&http_date(ts)), Some(ts));
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+    }
+}
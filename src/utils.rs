@@ -1,4 +1,5 @@
 use cfg_if::cfg_if;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 cfg_if! {
     // https://github.com/rustwasm/console_error_panic_hook#readme
@@ -10,3 +11,140 @@ cfg_if! {
         pub fn set_panic_hook() {}
     }
 }
+
+static TRACING_INIT_ATTEMPTED: AtomicBool = AtomicBool::new(false);
+
+// Set up tracing for the isolate. `tracing_worker::init` installs a global
+// subscriber, which can only be done once: calling it again on the next request
+// served by the same isolate would fail. Panics can't be caught on wasm (there's
+// no unwinding), so instead of catching them it's simply never called twice.
+// If it doesn't install a subscriber, events are written to the console by
+// `console_subscriber` instead.
+pub fn init_tracing(env: &worker::Env) {
+    if TRACING_INIT_ATTEMPTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let installed = init_tracing_with(
+        tracing::dispatcher::has_been_set,
+        || tracing_worker::init(env),
+        || {
+            worker::console_error!(
+                "tracing_worker::init did not install a subscriber, logging to the console"
+            );
+            let subscriber = console_subscriber(|line| worker::console_log!("{line}"));
+            let _ = tracing::subscriber::set_global_default(subscriber);
+        },
+    );
+    if !installed {
+        worker::console_error!("No tracing subscriber could be installed, logs are dropped");
+    }
+}
+
+// Run `init` unless a subscriber is already set, and `fallback` if `init`
+// didn't set one. Returns whether a subscriber is set in the end.
+fn init_tracing_with(
+    is_set: impl Fn() -> bool,
+    init: impl FnOnce(),
+    fallback: impl FnOnce(),
+) -> bool {
+    if is_set() {
+        return true;
+    }
+    init();
+    if !is_set() {
+        fallback();
+    }
+    is_set()
+}
+
+// Formats events as plain lines passed to `emit`, as wasm has no stdout
+fn console_subscriber(emit: fn(&str)) -> impl tracing::Subscriber + Send + Sync {
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        // SystemTime isn't available on wasm, and the console adds its own
+        .without_time()
+        .with_writer(move || ConsoleWriter {
+            line: Vec::new(),
+            emit,
+        })
+        .finish()
+}
+
+// Collects one formatted event and emits it when dropped
+struct ConsoleWriter {
+    line: Vec<u8>,
+    emit: fn(&str),
+}
+
+impl std::io::Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleWriter {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            (self.emit)(String::from_utf8_lossy(&self.line).trim_end());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{console_subscriber, init_tracing_with};
+    use std::cell::{Cell, RefCell};
+
+    // No global subscriber is installed here, as it would leak into every
+    // other test of the process
+    #[test]
+    fn test_tracing_initialized_once() {
+        let set = Cell::new(false);
+        let installed = init_tracing_with(|| set.get(), || set.set(true), || panic!("fallback"));
+        assert!(installed);
+        // later requests of the isolate don't initialize again
+        let installed = init_tracing_with(
+            || set.get(),
+            || panic!("initialized twice"),
+            || panic!("fallback"),
+        );
+        assert!(installed);
+
+        let set = Cell::new(false);
+        let fallback = Cell::new(false);
+        let installed = init_tracing_with(
+            || set.get(),
+            || {},
+            || {
+                fallback.set(true);
+                set.set(true);
+            },
+        );
+        assert!(installed && fallback.get());
+    }
+
+    thread_local! {
+        static LINES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    #[test]
+    fn test_console_subscriber() {
+        let subscriber = console_subscriber(|line| {
+            LINES.with(|lines| lines.borrow_mut().push(line.to_string()))
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("first");
+            tracing::error!(code = 7, "second");
+        });
+
+        let lines = LINES.with(|lines| lines.take());
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("WARN") && lines[0].ends_with("first"));
+        assert!(lines[1].contains("second code=7"));
+    }
+}